@@ -0,0 +1,261 @@
+//! Runs crab-rave planets against a seeded stream of sunrays, asteroids and
+//! explorer requests, then prints how long each of them survived and its metrics.
+//! The same seed always gives the same run.
+//!
+//! usage: crab-sim [--seed N] [--planets N] [--ticks N] [--explorers N] [--planet-type A|B|C|D]
+//!
+//! The planets are of type A by default, which can build a rocket; a type D
+//! planet has no defense and dies at the first asteroid.
+//!
+//! With the `prometheus` feature, `--metrics-port N` also serves the metrics
+//! on `127.0.0.1:N/metrics` while the simulation runs.
+
+use common_game::components::planet::PlanetType;
+use common_game::components::resource::BasicResourceType;
+use common_game::components::resource::BasicResourceType::*;
+use common_game::components::resource::ComplexResourceType::*;
+use common_game::utils::ID;
+use one_million_crabs::harness::{MockExplorer, PlanetHarness};
+use one_million_crabs::planet::PlanetBuilder;
+use one_million_crabs::planet::metrics::{self, PlanetMetrics};
+use std::process::ExitCode;
+
+const SUNRAY_CHANCE: f64 = 0.5;
+const ASTEROID_CHANCE: f64 = 0.05;
+const EXPLORER_CHANCE: f64 = 0.3;
+const BASIC_RESOURCES: [BasicResourceType; 4] = [Carbon, Hydrogen, Oxygen, Silicon];
+const USAGE: &str =
+    "usage: crab-sim [--seed N] [--planets N] [--ticks N] [--explorers N] [--planet-type A|B|C|D]";
+
+struct Config {
+    seed: u64,
+    planets: u32,
+    ticks: u64,
+    explorers: u32,
+    planet_type: PlanetType,
+    #[cfg(feature = "prometheus")]
+    metrics_port: Option<u16>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            seed: 42,
+            planets: 1,
+            ticks: 1000,
+            explorers: 2,
+            planet_type: PlanetType::A,
+            #[cfg(feature = "prometheus")]
+            metrics_port: None,
+        }
+    }
+}
+
+fn parse_args() -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        let invalid = |e: std::num::ParseIntError| format!("invalid value for {}: {}", arg, e);
+        match arg.as_str() {
+            "--seed" => config.seed = value.parse().map_err(invalid)?,
+            "--planets" => config.planets = value.parse().map_err(invalid)?,
+            "--ticks" => config.ticks = value.parse().map_err(invalid)?,
+            "--explorers" => config.explorers = value.parse().map_err(invalid)?,
+            "--planet-type" => config.planet_type = parse_planet_type(&value)?,
+            #[cfg(feature = "prometheus")]
+            "--metrics-port" => config.metrics_port = Some(value.parse().map_err(invalid)?),
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    Ok(config)
+}
+
+fn parse_planet_type(value: &str) -> Result<PlanetType, String> {
+    match value {
+        "A" => Ok(PlanetType::A),
+        "B" => Ok(PlanetType::B),
+        "C" => Ok(PlanetType::C),
+        "D" => Ok(PlanetType::D),
+        _ => Err(format!("invalid value for --planet-type: {}", value)),
+    }
+}
+
+/// Builder of a planet of the given type and the resources it can generate.
+fn planet_builder(
+    planet_id: ID,
+    planet_type: PlanetType,
+) -> (PlanetBuilder, &'static [BasicResourceType]) {
+    match planet_type {
+        PlanetType::A => (
+            PlanetBuilder::new(planet_id)
+                .planet_type(PlanetType::A)
+                .gen_rules(vec![Carbon]),
+            &[Carbon],
+        ),
+        PlanetType::B => (
+            PlanetBuilder::crafting(planet_id, vec![Water]),
+            &BASIC_RESOURCES,
+        ),
+        PlanetType::C => (
+            PlanetBuilder::crafting(planet_id, vec![Diamond, Life]),
+            &[Carbon],
+        ),
+        PlanetType::D => (PlanetBuilder::new(planet_id), &BASIC_RESOURCES),
+    }
+}
+
+/// SplitMix64, small and good enough to drive the simulation.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// true with probability `p`
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[(self.next_u64() % items.len() as u64) as usize]
+    }
+}
+
+/// What happened to a planet during the run.
+#[derive(Debug, PartialEq)]
+struct Report {
+    /// tick of the asteroid that destroyed the planet
+    destroyed_at: Option<u64>,
+    metrics: PlanetMetrics,
+}
+
+fn simulate(planet_id: ID, config: &Config) -> Result<Report, String> {
+    // every planet has its own stream, so adding planets doesn't change the others
+    let mut rng = Rng(config.seed ^ u64::from(planet_id).wrapping_mul(0xD1B5_4A32_D192_ED03));
+    let mut destroyed_at = None;
+
+    let (builder, resources) = planet_builder(planet_id, config.planet_type);
+    let planet = PlanetHarness::spawn(builder)?;
+    planet.start()?;
+    let explorers = (1..=config.explorers)
+        .map(|id| planet.land_explorer(id))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for tick in 0..config.ticks {
+        if rng.chance(SUNRAY_CHANCE) {
+            planet.sunray()?;
+        }
+        if rng.chance(ASTEROID_CHANCE) && planet.asteroid()?.is_none() {
+            destroyed_at = Some(tick);
+            break;
+        }
+        for explorer in &explorers {
+            if rng.chance(EXPLORER_CHANCE) {
+                explorer_request(explorer, resources, &mut rng)?;
+            }
+        }
+    }
+
+    // the metrics are dropped with the planet
+    let metrics = metrics::snapshot(planet_id).ok_or("the planet has no metrics")?;
+    planet.kill()?;
+    Ok(Report {
+        destroyed_at,
+        metrics,
+    })
+}
+
+fn explorer_request(
+    explorer: &MockExplorer,
+    resources: &[BasicResourceType],
+    rng: &mut Rng,
+) -> Result<(), String> {
+    match rng.next_u64() % 4 {
+        0 => {
+            explorer.available_cells()?;
+        }
+        1 => {
+            explorer.supported_resources()?;
+        }
+        _ => {
+            explorer.generate(rng.pick(resources))?;
+        }
+    }
+    Ok(())
+}
+
+fn print_report(planet_id: ID, report: &Report, ticks: u64) {
+    println!("planet {}", planet_id);
+    match report.destroyed_at {
+        Some(tick) => println!("  destroyed by an asteroid at tick {}", tick),
+        None => println!("  survived all {} ticks", ticks),
+    }
+    for line in report.metrics.to_string().lines() {
+        println!("  {}", line);
+    }
+}
+
+fn main() -> ExitCode {
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!(
+        "crab-sim seed={} planets={} ticks={} explorers={} planet-type={:?}",
+        config.seed, config.planets, config.ticks, config.explorers, config.planet_type
+    );
+    #[cfg(feature = "prometheus")]
+    let _server = match config
+        .metrics_port
+        .map(one_million_crabs::prometheus::serve)
+    {
+        Some(Ok(server)) => {
+            println!("serving metrics on http://{}/metrics", server.local_addr());
+            Some(server)
+        }
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+    for planet_id in 0..config.planets {
+        match simulate(planet_id, &config) {
+            Ok(report) => print_report(planet_id, &report, config.ticks),
+            Err(e) => {
+                eprintln!("planet {}: {}", planet_id, e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t01_same_seed_same_report() {
+        println!("+++++ Test same seed same report +++++");
+        let config = Config {
+            ticks: 300,
+            ..Config::default()
+        };
+        let first = simulate(0, &config).unwrap();
+        let second = simulate(0, &config).unwrap();
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,285 @@
+//! Mock orchestrator used to drive a planet outside of the real game.
+//!
+//! [`PlanetHarness`] runs a planet on its own thread, owns the orchestrator
+//! side of its channels and waits for every reply with a timeout.
+//! [`MockExplorer`] does the same for an explorer landed on the planet.
+
+use crate::planet::{PlanetBuilder, create_planet};
+use common_game::components::asteroid::Asteroid;
+use common_game::components::planet::{DummyPlanetState, Planet};
+use common_game::components::resource::{
+    BasicResource, BasicResourceType, ComplexResource, ComplexResourceRequest, ComplexResourceType,
+    GenericResource,
+};
+use common_game::components::rocket::Rocket;
+use common_game::components::sunray::Sunray;
+use common_game::protocols::orchestrator_planet::{OrchestratorToPlanet, PlanetToOrchestrator};
+use common_game::protocols::planet_explorer::{ExplorerToPlanet, PlanetToExplorer};
+use common_game::utils::ID;
+use crossbeam_channel::{Receiver, Sender, unbounded};
+use std::collections::HashSet;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long the harness waits for a reply if not told otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Result of a combine request, as sent by the planet.
+pub type CombineResult = Result<ComplexResource, (String, GenericResource, GenericResource)>;
+
+/// The orchestrator side of a planet running on its own thread.
+pub struct PlanetHarness {
+    planet_id: ID,
+    handle: JoinHandle<Result<(), String>>,
+    to_planet: Sender<OrchestratorToPlanet>,
+    from_planet: Receiver<PlanetToOrchestrator>,
+    to_planet_explorer: Sender<ExplorerToPlanet>,
+    timeout: Duration,
+}
+
+impl PlanetHarness {
+    /// Creates the planet with `create`, which receives the planet side
+    /// of the channels, and runs it on a new thread.
+    /// The planet AI is not started, see [`PlanetHarness::start`].
+    pub fn spawn_with<F>(create: F) -> Result<Self, String>
+    where
+        F: FnOnce(
+            Receiver<OrchestratorToPlanet>,
+            Sender<PlanetToOrchestrator>,
+            Receiver<ExplorerToPlanet>,
+        ) -> Result<Planet, String>,
+    {
+        let (to_planet, planet_receiver) = unbounded::<OrchestratorToPlanet>();
+        let (planet_sender, from_planet) = unbounded::<PlanetToOrchestrator>();
+        let (to_planet_explorer, explorer_receiver) = unbounded::<ExplorerToPlanet>();
+        let mut planet = create(planet_receiver, planet_sender, explorer_receiver)?;
+        let planet_id = planet.id();
+        let handle = thread::spawn(move || planet.run());
+        Ok(Self {
+            planet_id,
+            handle,
+            to_planet,
+            from_planet,
+            to_planet_explorer,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Runs the planet configured by `builder`.
+    pub fn spawn(builder: PlanetBuilder) -> Result<Self, String> {
+        Self::spawn_with(|rx_orch, tx_orch, rx_explorer| {
+            builder.build(rx_orch, tx_orch, rx_explorer)
+        })
+    }
+
+    /// Runs the default planet returned by [`create_planet`].
+    pub fn create(planet_id: ID) -> Result<Self, String> {
+        Self::spawn_with(|rx_orch, tx_orch, rx_explorer| {
+            create_planet(rx_orch, tx_orch, rx_explorer, planet_id)
+        })
+    }
+
+    /// Changes how long every helper waits for the reply of the planet.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn planet_id(&self) -> ID {
+        self.planet_id
+    }
+
+    pub fn send(&self, msg: OrchestratorToPlanet) -> Result<(), String> {
+        self.to_planet
+            .send(msg)
+            .map_err(|_| format!("planet {} is not running", self.planet_id))
+    }
+
+    /// Waits for the next message of the planet.
+    pub fn recv(&self) -> Result<PlanetToOrchestrator, String> {
+        self.from_planet.recv_timeout(self.timeout).map_err(|e| {
+            format!(
+                "no response from planet {} within {:?}: {}",
+                self.planet_id, self.timeout, e
+            )
+        })
+    }
+
+    /// Sends `msg` and waits for the reply, whatever it is.
+    pub fn request(&self, msg: OrchestratorToPlanet) -> Result<PlanetToOrchestrator, String> {
+        self.send(msg)?;
+        self.recv()
+    }
+
+    pub fn start(&self) -> Result<(), String> {
+        match self.request(OrchestratorToPlanet::StartPlanetAI)? {
+            PlanetToOrchestrator::StartPlanetAIResult { .. } => Ok(()),
+            other => Err(unexpected("start", &other)),
+        }
+    }
+
+    pub fn stop(&self) -> Result<(), String> {
+        match self.request(OrchestratorToPlanet::StopPlanetAI)? {
+            PlanetToOrchestrator::StopPlanetAIResult { .. } => Ok(()),
+            other => Err(unexpected("stop", &other)),
+        }
+    }
+
+    pub fn sunray(&self) -> Result<(), String> {
+        match self.request(OrchestratorToPlanet::Sunray(Sunray::default()))? {
+            PlanetToOrchestrator::SunrayAck { .. } => Ok(()),
+            other => Err(unexpected("sunray", &other)),
+        }
+    }
+
+    pub fn sunrays(&self, n: usize) -> Result<(), String> {
+        (0..n).try_for_each(|_| self.sunray())
+    }
+
+    /// Sends an asteroid and returns the rocket used by the planet, if any.
+    pub fn asteroid(&self) -> Result<Option<Rocket>, String> {
+        match self.request(OrchestratorToPlanet::Asteroid(Asteroid::default()))? {
+            PlanetToOrchestrator::AsteroidAck { rocket, .. } => Ok(rocket),
+            other => Err(unexpected("asteroid", &other)),
+        }
+    }
+
+    pub fn internal_state(&self) -> Result<DummyPlanetState, String> {
+        match self.request(OrchestratorToPlanet::InternalStateRequest)? {
+            PlanetToOrchestrator::InternalStateResponse { planet_state, .. } => Ok(planet_state),
+            other => Err(unexpected("internal state", &other)),
+        }
+    }
+
+    /// Lands an explorer on the planet.
+    pub fn land_explorer(&self, explorer_id: ID) -> Result<MockExplorer, String> {
+        let (to_explorer, from_planet) = unbounded::<PlanetToExplorer>();
+        match self.request(OrchestratorToPlanet::IncomingExplorerRequest {
+            explorer_id,
+            new_sender: to_explorer,
+        })? {
+            PlanetToOrchestrator::IncomingExplorerResponse { res: Ok(()), .. } => {
+                Ok(MockExplorer {
+                    explorer_id,
+                    to_planet: self.to_planet_explorer.clone(),
+                    from_planet,
+                    timeout: self.timeout,
+                })
+            }
+            other => Err(unexpected("incoming explorer", &other)),
+        }
+    }
+
+    /// Makes the explorer leave the planet.
+    pub fn remove_explorer(&self, explorer_id: ID) -> Result<(), String> {
+        match self.request(OrchestratorToPlanet::OutgoingExplorerRequest { explorer_id })? {
+            PlanetToOrchestrator::OutgoingExplorerResponse { res: Ok(()), .. } => Ok(()),
+            other => Err(unexpected("outgoing explorer", &other)),
+        }
+    }
+
+    /// Kills the planet and waits for its thread to end,
+    /// returning the result of `Planet::run`.
+    pub fn kill(self) -> Result<(), String> {
+        match self.request(OrchestratorToPlanet::KillPlanet)? {
+            PlanetToOrchestrator::KillPlanetResult { .. } => {}
+            other => return Err(unexpected("kill", &other)),
+        }
+        self.handle
+            .join()
+            .map_err(|_| format!("planet {} thread panicked", self.planet_id))?
+    }
+}
+
+/// An explorer landed on a [`PlanetHarness`] planet.
+pub struct MockExplorer {
+    explorer_id: ID,
+    to_planet: Sender<ExplorerToPlanet>,
+    from_planet: Receiver<PlanetToExplorer>,
+    timeout: Duration,
+}
+
+impl MockExplorer {
+    pub fn id(&self) -> ID {
+        self.explorer_id
+    }
+
+    pub fn send(&self, msg: ExplorerToPlanet) -> Result<(), String> {
+        self.to_planet
+            .send(msg)
+            .map_err(|_| "the planet is not running".to_string())
+    }
+
+    /// Waits for the next message of the planet to this explorer.
+    pub fn recv(&self) -> Result<PlanetToExplorer, String> {
+        self.from_planet.recv_timeout(self.timeout).map_err(|e| {
+            format!(
+                "no response to explorer {} within {:?}: {}",
+                self.explorer_id, self.timeout, e
+            )
+        })
+    }
+
+    /// Sends `msg` and waits for the reply, whatever it is.
+    pub fn request(&self, msg: ExplorerToPlanet) -> Result<PlanetToExplorer, String> {
+        self.send(msg)?;
+        self.recv()
+    }
+
+    pub fn supported_resources(&self) -> Result<HashSet<BasicResourceType>, String> {
+        match self.request(ExplorerToPlanet::SupportedResourceRequest {
+            explorer_id: self.explorer_id,
+        })? {
+            PlanetToExplorer::SupportedResourceResponse { resource_list } => Ok(resource_list),
+            other => Err(unexpected("supported resources", &other)),
+        }
+    }
+
+    pub fn supported_combinations(&self) -> Result<HashSet<ComplexResourceType>, String> {
+        match self.request(ExplorerToPlanet::SupportedCombinationRequest {
+            explorer_id: self.explorer_id,
+        })? {
+            PlanetToExplorer::SupportedCombinationResponse { combination_list } => {
+                Ok(combination_list)
+            }
+            other => Err(unexpected("supported combinations", &other)),
+        }
+    }
+
+    pub fn available_cells(&self) -> Result<u32, String> {
+        match self.request(ExplorerToPlanet::AvailableEnergyCellRequest {
+            explorer_id: self.explorer_id,
+        })? {
+            PlanetToExplorer::AvailableEnergyCellResponse { available_cells } => {
+                Ok(available_cells)
+            }
+            other => Err(unexpected("available cells", &other)),
+        }
+    }
+
+    /// Asks the planet to generate `resource`, None if it refused.
+    pub fn generate(&self, resource: BasicResourceType) -> Result<Option<BasicResource>, String> {
+        match self.request(ExplorerToPlanet::GenerateResourceRequest {
+            explorer_id: self.explorer_id,
+            resource,
+        })? {
+            PlanetToExplorer::GenerateResourceResponse { resource } => Ok(resource),
+            other => Err(unexpected("generate resource", &other)),
+        }
+    }
+
+    /// Asks the planet to combine the ingredients of `msg`.
+    pub fn combine(&self, msg: ComplexResourceRequest) -> Result<CombineResult, String> {
+        match self.request(ExplorerToPlanet::CombineResourceRequest {
+            explorer_id: self.explorer_id,
+            msg,
+        })? {
+            PlanetToExplorer::CombineResourceResponse { complex_response } => Ok(complex_response),
+            other => Err(unexpected("combine resource", &other)),
+        }
+    }
+}
+
+fn unexpected(request: &str, reply: &impl std::fmt::Debug) -> String {
+    format!("unexpected {} response: {:?}", request, reply)
+}
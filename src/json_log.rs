@@ -0,0 +1,187 @@
+//! Machine readable log output.
+//!
+//! Every planet can choose a [`LogSink`] at creation: when it writes JSON,
+//! the [`JsonWriter`] kept in the `PlanetLog` of its AI writes each `LogEvent`
+//! passing through `log_msg!` as one JSON line.
+//! [`read_events`] parses those files back into `LogEvent`s.
+
+use common_game::logging::{ActorType, Channel, EventType, LogEvent, Participant, Payload};
+use common_game::utils::ID;
+use serde_json::{Value, json};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Where the log events of a planet go.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum LogSink {
+    /// `log` crate only, formatted with `Display`.
+    #[default]
+    Log,
+    /// JSON lines appended to the given file, nothing goes to the `log` crate.
+    Json(PathBuf),
+    /// Both of the above.
+    LogAndJson(PathBuf),
+}
+
+/// JSON lines file of a planet, see [`LogSink`].
+#[derive(Debug)]
+pub struct JsonWriter {
+    file: File,
+    also_log: bool,
+}
+
+impl JsonWriter {
+    /// Opens the file of the sink in append mode, None if the sink doesn't write JSON.
+    pub fn open(sink: &LogSink) -> Result<Option<Self>, String> {
+        let (path, also_log) = match sink {
+            LogSink::Log => return Ok(None),
+            LogSink::Json(path) => (path, false),
+            LogSink::LogAndJson(path) => (path, true),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("can't open {}: {}", path.display(), e))?;
+        Ok(Some(Self { file, also_log }))
+    }
+
+    /// Writes the event as one JSON line.
+    /// Returns true if the event must also go to the `log` crate.
+    pub fn write(&self, event: &LogEvent) -> bool {
+        let line = format!("{}\n", to_json(event));
+        // a single write per line, so the lines of concurrent writers don't mix;
+        // a failed write can't be logged without recursing, fall back to the log crate
+        let written = (&self.file).write_all(line.as_bytes()).is_ok();
+        self.also_log || !written
+    }
+}
+
+/// Serializes the event as a single line JSON object.
+pub fn to_json(event: &LogEvent) -> Value {
+    let participant = |p: &Option<Participant>| match p {
+        Some(p) => json!({ "actor_type": format!("{:?}", p.actor_type), "id": p.id }),
+        None => Value::Null,
+    };
+    json!({
+        "timestamp_unix": event.timestamp_unix,
+        "sender": participant(&event.sender),
+        "receiver": participant(&event.receiver),
+        "event_type": format!("{:?}", event.event_type),
+        "channel": format!("{:?}", event.channel),
+        "payload": event.payload,
+    })
+}
+
+/// Parses an event written by [`to_json`].
+pub fn from_json(value: &Value) -> Result<LogEvent, String> {
+    let field = |name: &str| {
+        value
+            .get(name)
+            .ok_or_else(|| format!("missing field {}", name))
+    };
+    let string = |name: &str| {
+        field(name)?
+            .as_str()
+            .ok_or_else(|| format!("field {} is not a string", name))
+    };
+
+    let participant = |name: &str| -> Result<Option<Participant>, String> {
+        let p = field(name)?;
+        if p.is_null() {
+            return Ok(None);
+        }
+        let actor_type = p
+            .get("actor_type")
+            .and_then(Value::as_str)
+            .ok_or_else(|| format!("invalid {}", name))
+            .and_then(parse_actor_type)?;
+        let id = p
+            .get("id")
+            .and_then(Value::as_u64)
+            .and_then(|id| ID::try_from(id).ok())
+            .ok_or_else(|| format!("invalid {} id", name))?;
+        Ok(Some(Participant::new(actor_type, id)))
+    };
+
+    let mut payload = Payload::new();
+    for (key, val) in field("payload")?
+        .as_object()
+        .ok_or("field payload is not an object")?
+    {
+        let val = val
+            .as_str()
+            .ok_or_else(|| format!("payload value {} is not a string", key))?;
+        payload.insert(key.clone(), val.to_string());
+    }
+
+    Ok(LogEvent {
+        timestamp_unix: field("timestamp_unix")?
+            .as_u64()
+            .ok_or("field timestamp_unix is not a number")?,
+        sender: participant("sender")?,
+        receiver: participant("receiver")?,
+        event_type: parse_event_type(string("event_type")?)?,
+        channel: parse_channel(string("channel")?)?,
+        payload,
+    })
+}
+
+/// Reads back a file written by a JSON sink, one event per line.
+pub fn read_events(path: impl AsRef<Path>) -> Result<Vec<LogEvent>, String> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| format!("can't open {}: {}", path.display(), e))?;
+    let mut events = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value =
+            serde_json::from_str(&line).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        events.push(from_json(&value).map_err(|e| format!("line {}: {}", n + 1, e))?);
+    }
+    Ok(events)
+}
+
+fn parse_actor_type(s: &str) -> Result<ActorType, String> {
+    match s {
+        "Planet" => Ok(ActorType::Planet),
+        "Explorer" => Ok(ActorType::Explorer),
+        "Orchestrator" => Ok(ActorType::Orchestrator),
+        "User" => Ok(ActorType::User),
+        "Broadcast" => Ok(ActorType::Broadcast),
+        "SelfActor" => Ok(ActorType::SelfActor),
+        _ => Err(format!("unknown actor type {}", s)),
+    }
+}
+
+fn parse_channel(s: &str) -> Result<Channel, String> {
+    match s {
+        "Error" => Ok(Channel::Error),
+        "Warning" => Ok(Channel::Warning),
+        "Info" => Ok(Channel::Info),
+        "Debug" => Ok(Channel::Debug),
+        "Trace" => Ok(Channel::Trace),
+        _ => Err(format!("unknown channel {}", s)),
+    }
+}
+
+fn parse_event_type(s: &str) -> Result<EventType, String> {
+    match s {
+        "MessagePlanetToOrchestrator" => Ok(EventType::MessagePlanetToOrchestrator),
+        "MessageOrchestratorToPlanet" => Ok(EventType::MessageOrchestratorToPlanet),
+        "MessagePlanetToExplorer" => Ok(EventType::MessagePlanetToExplorer),
+        "MessageOrchestratorToExplorer" => Ok(EventType::MessageOrchestratorToExplorer),
+        "MessageExplorerToPlanet" => Ok(EventType::MessageExplorerToPlanet),
+        "MessageExplorerToOrchestrator" => Ok(EventType::MessageExplorerToOrchestrator),
+        "InternalPlanetAction" => Ok(EventType::InternalPlanetAction),
+        "InternalExplorerAction" => Ok(EventType::InternalExplorerAction),
+        "InternalOrchestratorAction" => Ok(EventType::InternalOrchestratorAction),
+        "UserToPlanet" => Ok(EventType::UserToPlanet),
+        "UserToExplorer" => Ok(EventType::UserToExplorer),
+        "UserToOrchestrator" => Ok(EventType::UserToOrchestrator),
+        _ => Err(format!("unknown event type {}", s)),
+    }
+}
@@ -1,12 +1,51 @@
+pub mod harness;
+pub mod json_log;
+pub mod log_config;
 pub mod planet;
-use planet::*;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod replay;
 
 #[cfg(test)]
 mod tests {
-    use crate::create_planet;
+    use crate::harness::{self, PlanetHarness};
+    use crate::json_log::{self, LogSink};
+    use crate::log_config::{LogCategory, LogConfig, PlanetLog};
+    use crate::planet::defense::{ChargeThreshold, NeverDefend, ReserveCells, RocketPolicy};
+    use crate::planet::demand::{DEFAULT_SMOOTHING, DemandPlanner, Plan};
+    use crate::planet::invariants::{self, CellDrift, InvariantPolicy};
+    use crate::planet::inventory::Inventory;
+    use crate::planet::metrics;
+    use crate::planet::quotas::{ExplorerQuota, FairnessPolicy, Throttle};
+    use crate::planet::snapshot::PlanetSnapshot;
+    use crate::planet::stacks::CellAllocator;
+    use crate::planet::visitors::VisitorRegistry;
+    use crate::planet::{
+        BatchReport, OneMillionCrabs, PlanetBuilder, create_planet, create_planet_from_snapshot,
+    };
+    use crate::replay;
+    use common_game::components::planet::{DummyPlanetState, PlanetAI, PlanetState, PlanetType};
+    use common_game::components::resource::{
+        BasicResource, BasicResourceType, ComplexResource, ComplexResourceRequest,
+        ComplexResourceType,
+    };
+    use common_game::components::resource::{Combinator, Generator};
+    use common_game::components::rocket::Rocket;
+    use common_game::components::sunray::Sunray;
+    use common_game::logging::{ActorType, Channel, EventType, LogEvent, Participant, Payload};
     use common_game::protocols::orchestrator_planet::{OrchestratorToPlanet, PlanetToOrchestrator};
     use common_game::protocols::planet_explorer::{ExplorerToPlanet, PlanetToExplorer};
+    use common_game::utils::ID;
     use crossbeam_channel::{Receiver, Sender, unbounded};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn rocket_planet(builder: PlanetBuilder) -> PlanetBuilder {
+        builder
+            .planet_type(PlanetType::A)
+            .gen_rules(vec![BasicResourceType::Carbon])
+    }
 
     #[test]
 
@@ -50,4 +89,1970 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn t02_cell_allocators_are_independent() {
+        println!("+++++ Test cell allocators are independent +++++");
+        let mut first = CellAllocator::with_cells(1, CELLS);
+        let mut second = CellAllocator::with_cells(2, CELLS);
+
+        // charging every cell of the first planet must not touch the second one
+        while let Some(idx) = first.get_free_cell_index() {
+            first.push_charged_cell(idx);
+        }
+        assert_eq!(first.get_free_cell_index(), None);
+        assert_eq!(second.get_charged_cell_index(), None);
+        assert_eq!(second.get_free_cell_index(), Some(0));
+
+        // discharging follows the LIFO order of the charged stack
+        assert_eq!(first.get_charged_cell_index(), Some(4));
+        first.push_free_cell(4);
+        assert_eq!(first.get_free_cell_index(), Some(4));
+    }
+
+    #[test]
+    fn t03_parallel_planets_sunrays() -> Result<(), String> {
+        println!("+++++ Test parallel planets sunrays +++++");
+        const N_PLANETS: u32 = 4;
+        const N_SUNRAYS: usize = 7;
+
+        // all the planets are created before any of them starts running
+        let planets = (0..N_PLANETS)
+            .map(PlanetHarness::create)
+            .collect::<Result<Vec<_>, _>>()?;
+        let orchestrators = planets
+            .into_iter()
+            .map(|planet| {
+                thread::spawn(move || -> Result<(ID, Vec<bool>), String> {
+                    planet.start()?;
+                    planet.sunrays(N_SUNRAYS)?;
+                    let cells = planet.internal_state()?.energy_cells;
+                    let planet_id = planet.planet_id();
+                    planet.kill()?;
+                    Ok((planet_id, cells))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for orchestrator in orchestrators {
+            let (planet_id, cells) = orchestrator
+                .join()
+                .map_err(|_| "orchestrator thread panicked".to_string())??;
+            assert!(
+                cells.iter().all(|charged| *charged),
+                "planet {} has uncharged cells: {:?}",
+                planet_id,
+                cells
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t04_builder_rejects_invalid_configurations() {
+        println!("+++++ Test builder rejects invalid configurations +++++");
+        let invalid = [
+            PlanetBuilder::new(0).gen_rules(vec![]),
+            PlanetBuilder::new(0)
+                .planet_type(PlanetType::A)
+                .gen_rules(vec![BasicResourceType::Carbon, BasicResourceType::Oxygen]),
+            PlanetBuilder::new(0).comb_rules(vec![ComplexResourceType::Water]),
+            PlanetBuilder::new(0)
+                .planet_type(PlanetType::B)
+                .comb_rules(vec![ComplexResourceType::Water, ComplexResourceType::Life]),
+            PlanetBuilder::new(0)
+                .gen_rules(vec![BasicResourceType::Carbon, BasicResourceType::Carbon]),
+            PlanetBuilder::crafting(0, vec![]),
+        ];
+        for builder in invalid {
+            assert!(builder.validate().is_err());
+            let (_orch_sender, planet_receiver) = unbounded::<OrchestratorToPlanet>();
+            let (planet_sender, _orch_receiver) = unbounded::<PlanetToOrchestrator>();
+            let (_explorer_sender, explorer_receiver) = unbounded::<ExplorerToPlanet>();
+            assert!(
+                builder
+                    .build(planet_receiver, planet_sender, explorer_receiver)
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn t05_builder_custom_planet() -> Result<(), String> {
+        println!("+++++ Test builder custom planet +++++");
+        let (_orch_sender, planet_receiver) = unbounded::<OrchestratorToPlanet>();
+        let (planet_sender, _orch_receiver) = unbounded::<PlanetToOrchestrator>();
+        let (_explorer_sender, explorer_receiver) = unbounded::<ExplorerToPlanet>();
+
+        let planet = PlanetBuilder::new(7)
+            .planet_type(PlanetType::C)
+            .gen_rules(vec![BasicResourceType::Hydrogen])
+            .comb_rules(vec![
+                ComplexResourceType::Water,
+                ComplexResourceType::Diamond,
+            ])
+            .build(planet_receiver, planet_sender, explorer_receiver)?;
+
+        assert_eq!(planet.id(), 7);
+        assert!(matches!(planet.planet_type(), PlanetType::C));
+        assert!(planet.state().can_have_rocket());
+        assert!(planet.generator().contains(BasicResourceType::Hydrogen));
+        assert!(!planet.generator().contains(BasicResourceType::Carbon));
+        assert!(planet.combinator().contains(ComplexResourceType::Water));
+        assert!(planet.combinator().contains(ComplexResourceType::Diamond));
+        assert!(!planet.combinator().contains(ComplexResourceType::Life));
+        Ok(())
+    }
+
+    #[test]
+    fn t06_visitor_registry() {
+        println!("+++++ Test visitor registry +++++");
+        let mut visitors = VisitorRegistry::new();
+        assert!(visitors.arrive(3));
+        assert!(visitors.arrive(1));
+        assert!(!visitors.arrive(3));
+        assert_eq!(visitors.present(), vec![1, 3]);
+
+        visitors.record_generated(3, BasicResourceType::Carbon);
+        visitors.record_generated(3, BasicResourceType::Carbon);
+        visitors.record_combined(3, ComplexResourceType::Diamond);
+        // explorers that are not landed are not tracked
+        visitors.record_generated(9, BasicResourceType::Oxygen);
+        assert!(visitors.get(9).is_none());
+
+        let visitor = visitors.depart(3).expect("explorer 3 is landed");
+        assert_eq!(visitor.consumed_cells, 3);
+        assert_eq!(visitor.generated.get(&BasicResourceType::Carbon), Some(&2));
+        assert_eq!(
+            visitor.combined.get(&ComplexResourceType::Diamond),
+            Some(&1)
+        );
+        assert!(!visitors.is_present(3));
+        assert!(visitors.depart(3).is_none());
+        assert_eq!(visitors.len(), 1);
+    }
+
+    #[test]
+    fn t07_explorer_arrival_and_departure() -> Result<(), String> {
+        println!("+++++ Test explorer arrival and departure +++++");
+        let planet = PlanetHarness::create(0)?;
+        planet.start()?;
+        let explorer = planet.land_explorer(1)?;
+        planet.sunray()?;
+
+        let resource = explorer
+            .generate(BasicResourceType::Oxygen)?
+            .ok_or("no oxygen")?;
+        assert_eq!(resource.get_type(), BasicResourceType::Oxygen);
+
+        planet.remove_explorer(1)?;
+        planet.kill()
+    }
+
+    #[test]
+    fn t08_always_defend() -> Result<(), String> {
+        println!("+++++ Test always defend +++++");
+        let planet = PlanetHarness::spawn(rocket_planet(PlanetBuilder::new(0)))?;
+        planet.start()?;
+
+        // no energy, no rocket
+        assert!(planet.asteroid()?.is_none());
+
+        planet.sunrays(2)?;
+        assert!(planet.asteroid()?.is_some());
+        let state = planet.internal_state()?;
+        assert_eq!(state.charged_cells_count, 1);
+        assert!(!state.has_rocket);
+
+        planet.kill()
+    }
+
+    #[test]
+    fn t09_never_defend() -> Result<(), String> {
+        println!("+++++ Test never defend +++++");
+        let planet = PlanetHarness::spawn(
+            rocket_planet(PlanetBuilder::new(0)).defense_strategy(Box::new(NeverDefend)),
+        )?;
+        planet.start()?;
+
+        planet.sunrays(3)?;
+        assert!(planet.asteroid()?.is_none());
+        assert_eq!(planet.internal_state()?.charged_cells_count, 3);
+
+        planet.kill()
+    }
+
+    #[test]
+    fn t10_charge_threshold() -> Result<(), String> {
+        println!("+++++ Test charge threshold defense +++++");
+        let planet = PlanetHarness::spawn(
+            rocket_planet(PlanetBuilder::new(0)).defense_strategy(Box::new(ChargeThreshold(3))),
+        )?;
+        planet.start()?;
+
+        planet.sunrays(2)?;
+        assert!(planet.asteroid()?.is_none());
+        assert_eq!(planet.internal_state()?.charged_cells_count, 2);
+
+        planet.sunray()?;
+        assert!(planet.asteroid()?.is_some());
+        assert_eq!(planet.internal_state()?.charged_cells_count, 2);
+
+        planet.kill()
+    }
+
+    #[test]
+    fn t11_reserve_cells() -> Result<(), String> {
+        println!("+++++ Test reserve cells defense +++++");
+        let planet = PlanetHarness::spawn(
+            rocket_planet(PlanetBuilder::new(0)).defense_strategy(Box::new(ReserveCells(2))),
+        )?;
+        planet.start()?;
+        let explorer = planet.land_explorer(1)?;
+
+        planet.sunrays(3)?;
+        assert_eq!(explorer.available_cells()?, 1);
+
+        // only the first request can be served, the other two cells are for rockets
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_some());
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_none());
+
+        assert!(planet.asteroid()?.is_some());
+        assert!(planet.asteroid()?.is_some());
+        assert!(planet.asteroid()?.is_none());
+
+        planet.kill()
+    }
+
+    #[test]
+    fn t12_prebuild_rocket() -> Result<(), String> {
+        println!("+++++ Test prebuild rocket +++++");
+        let planet = PlanetHarness::spawn(
+            rocket_planet(PlanetBuilder::new(0))
+                .rocket_policy(RocketPolicy::Prebuild { explorer_cells: 0 }),
+        )?;
+        planet.start()?;
+
+        planet.sunray()?;
+        let state = planet.internal_state()?;
+        assert!(state.has_rocket);
+        assert_eq!(state.charged_cells_count, 0);
+
+        // the stored rocket is used, then the next sunray builds a new one
+        assert!(planet.asteroid()?.is_some());
+        assert!(!planet.internal_state()?.has_rocket);
+        planet.sunrays(2)?;
+        let state = planet.internal_state()?;
+        assert!(state.has_rocket);
+        assert_eq!(state.charged_cells_count, 1);
+
+        planet.kill()
+    }
+
+    #[test]
+    fn t13_prebuild_rocket_keeps_explorer_cells() -> Result<(), String> {
+        println!("+++++ Test prebuild rocket keeps explorer cells +++++");
+        let planet = PlanetHarness::spawn(
+            rocket_planet(PlanetBuilder::new(0))
+                .rocket_policy(RocketPolicy::Prebuild { explorer_cells: 2 }),
+        )?;
+        planet.start()?;
+
+        planet.sunrays(2)?;
+        let state = planet.internal_state()?;
+        assert!(!state.has_rocket);
+        assert_eq!(state.charged_cells_count, 2);
+
+        planet.sunray()?;
+        let state = planet.internal_state()?;
+        assert!(state.has_rocket);
+        assert_eq!(state.charged_cells_count, 2);
+
+        planet.kill()
+    }
+
+    #[test]
+    fn t14_cell_reservation() -> Result<(), String> {
+        println!("+++++ Test cell reservation +++++");
+        let planet = PlanetHarness::spawn(
+            PlanetBuilder::new(0).reservation_window(Duration::from_secs(60)),
+        )?;
+        planet.start()?;
+        let first = planet.land_explorer(1)?;
+        let second = planet.land_explorer(2)?;
+
+        planet.sunray()?;
+        assert_eq!(first.available_cells()?, 1);
+        // the only charged cell is held by the first explorer
+        assert_eq!(second.available_cells()?, 0);
+        assert!(second.generate(BasicResourceType::Carbon)?.is_none());
+        assert!(first.generate(BasicResourceType::Carbon)?.is_some());
+        assert_eq!(planet.internal_state()?.charged_cells_count, 0);
+
+        planet.kill()
+    }
+
+    #[test]
+    fn t15_cell_reservation_expiry_and_departure() -> Result<(), String> {
+        println!("+++++ Test cell reservation expiry and departure +++++");
+        let planet = PlanetHarness::spawn(
+            PlanetBuilder::new(0).reservation_window(Duration::from_millis(50)),
+        )?;
+        planet.start()?;
+        let first = planet.land_explorer(1)?;
+        let second = planet.land_explorer(2)?;
+
+        // the reservation times out
+        planet.sunray()?;
+        assert_eq!(first.available_cells()?, 1);
+        thread::sleep(Duration::from_millis(100));
+        assert!(second.generate(BasicResourceType::Oxygen)?.is_some());
+
+        // the reservation is released when the explorer leaves
+        planet.sunray()?;
+        assert_eq!(first.available_cells()?, 1);
+        planet.remove_explorer(1)?;
+        assert!(second.generate(BasicResourceType::Oxygen)?.is_some());
+
+        planet.kill()
+    }
+
+    type SunrayHook = Box<dyn FnMut(&mut OneMillionCrabs, &mut PlanetState, &Generator) + Send>;
+
+    /// Default AI that runs `after_sunray` at the end of every sunray handler,
+    /// like a custom AI built on top of `OneMillionCrabs` would.
+    struct HookedAI {
+        inner: OneMillionCrabs,
+        after_sunray: SunrayHook,
+    }
+
+    impl HookedAI {
+        fn new(
+            inner: OneMillionCrabs,
+            after_sunray: impl FnMut(&mut OneMillionCrabs, &mut PlanetState, &Generator)
+            + Send
+            + 'static,
+        ) -> Box<Self> {
+            Box::new(Self {
+                inner,
+                after_sunray: Box::new(after_sunray),
+            })
+        }
+    }
+
+    impl PlanetAI for HookedAI {
+        fn handle_sunray(
+            &mut self,
+            state: &mut PlanetState,
+            generator: &Generator,
+            combinator: &Combinator,
+            sunray: Sunray,
+        ) {
+            self.inner
+                .handle_sunray(state, generator, combinator, sunray);
+            (self.after_sunray)(&mut self.inner, state, generator);
+        }
+
+        fn handle_asteroid(
+            &mut self,
+            state: &mut PlanetState,
+            generator: &Generator,
+            combinator: &Combinator,
+        ) -> Option<Rocket> {
+            self.inner.handle_asteroid(state, generator, combinator)
+        }
+
+        fn handle_internal_state_req(
+            &mut self,
+            state: &mut PlanetState,
+            generator: &Generator,
+            combinator: &Combinator,
+        ) -> DummyPlanetState {
+            self.inner
+                .handle_internal_state_req(state, generator, combinator)
+        }
+
+        fn handle_explorer_msg(
+            &mut self,
+            state: &mut PlanetState,
+            generator: &Generator,
+            combinator: &Combinator,
+            msg: ExplorerToPlanet,
+        ) -> Option<PlanetToExplorer> {
+            self.inner
+                .handle_explorer_msg(state, generator, combinator, msg)
+        }
+
+        fn on_explorer_arrival(
+            &mut self,
+            state: &mut PlanetState,
+            generator: &Generator,
+            combinator: &Combinator,
+            explorer_id: ID,
+        ) {
+            self.inner
+                .on_explorer_arrival(state, generator, combinator, explorer_id)
+        }
+
+        fn on_explorer_departure(
+            &mut self,
+            state: &mut PlanetState,
+            generator: &Generator,
+            combinator: &Combinator,
+            explorer_id: ID,
+        ) {
+            self.inner
+                .on_explorer_departure(state, generator, combinator, explorer_id)
+        }
+
+        fn on_start(
+            &mut self,
+            state: &PlanetState,
+            generator: &Generator,
+            combinator: &Combinator,
+        ) {
+            self.inner.on_start(state, generator, combinator)
+        }
+
+        fn on_stop(&mut self, state: &PlanetState, generator: &Generator, combinator: &Combinator) {
+            self.inner.on_stop(state, generator, combinator)
+        }
+    }
+
+    /// AI that runs `batch` for `explorer_id` after the sunray number `after`
+    /// and sends the report to the test.
+    fn batching_ai(
+        inner: OneMillionCrabs,
+        after: usize,
+        explorer_id: ID,
+        batch: Vec<BasicResourceType>,
+    ) -> (Box<HookedAI>, Receiver<BatchReport>) {
+        let (report_sender, report_receiver) = unbounded::<BatchReport>();
+        let mut sunrays = 0;
+        let ai = HookedAI::new(inner, move |ai, state, generator| {
+            sunrays += 1;
+            if sunrays == after {
+                let _ =
+                    report_sender.send(ai.generate_batch(state, generator, explorer_id, &batch));
+            }
+        });
+        (ai, report_receiver)
+    }
+
+    #[test]
+    fn t16_batch_generation() -> Result<(), String> {
+        println!("+++++ Test batch generation +++++");
+        let batch = vec![
+            BasicResourceType::Oxygen,
+            BasicResourceType::Carbon,
+            BasicResourceType::Hydrogen,
+            BasicResourceType::Oxygen,
+        ];
+        // only three cells are charged: the last request fails
+        let (ai, reports) = batching_ai(OneMillionCrabs::new(0), 3, 1, batch.clone());
+        let planet = PlanetHarness::spawn(PlanetBuilder::new(0).ai(ai))?;
+        planet.start()?;
+        planet.land_explorer(1)?;
+        planet.sunrays(3)?;
+
+        let report = reports
+            .recv_timeout(harness::DEFAULT_TIMEOUT)
+            .map_err(|e| e.to_string())?;
+        assert!(!report.is_complete());
+        let produced: Vec<BasicResourceType> =
+            report.produced.iter().map(|r| r.get_type()).collect();
+        assert_eq!(produced, batch[..3].to_vec());
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, BasicResourceType::Oxygen);
+        assert_eq!(planet.internal_state()?.charged_cells_count, 0);
+        planet.kill()?;
+
+        // an explorer that is not on the planet gets nothing
+        let (ai, reports) = batching_ai(OneMillionCrabs::new(0), 1, 2, batch.clone());
+        let planet = PlanetHarness::spawn(PlanetBuilder::new(0).ai(ai))?;
+        planet.start()?;
+        planet.sunray()?;
+        let report = reports
+            .recv_timeout(harness::DEFAULT_TIMEOUT)
+            .map_err(|e| e.to_string())?;
+        assert!(report.produced.is_empty());
+        assert_eq!(report.failed.len(), batch.len());
+        assert_eq!(planet.internal_state()?.charged_cells_count, 1);
+        planet.kill()
+    }
+
+    #[test]
+    fn t17_json_log_roundtrip() -> Result<(), String> {
+        println!("+++++ Test json log roundtrip +++++");
+        let mut payload = Payload::new();
+        payload.insert("Message".to_string(), "Sunray".to_string());
+        let event = LogEvent::new(
+            Some(Participant::new(ActorType::Orchestrator, 0u32)),
+            None,
+            EventType::MessageOrchestratorToPlanet,
+            Channel::Warning,
+            payload,
+        );
+        let line = json_log::to_json(&event).to_string();
+        assert!(!line.contains('\n'));
+        let value = serde_json::from_str(&line).map_err(|e| e.to_string())?;
+        assert_eq!(json_log::from_json(&value)?, event);
+        Ok(())
+    }
+
+    #[test]
+    fn t18_json_log_sink() -> Result<(), String> {
+        println!("+++++ Test json log sink +++++");
+        const PLANET_ID: u32 = 800;
+        let path = std::env::temp_dir().join(format!("crab_rave_t18_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let planet = PlanetHarness::spawn(
+            PlanetBuilder::new(PLANET_ID).log_sink(LogSink::Json(path.clone())),
+        )?;
+        planet.start()?;
+        planet.sunray()?;
+        planet.kill()?;
+
+        let events = json_log::read_events(&path)?;
+        let _ = std::fs::remove_file(&path);
+        assert!(events.iter().any(|e| {
+            e.event_type == EventType::MessageOrchestratorToPlanet
+                && e.receiver == Some(Participant::new(ActorType::Planet, PLANET_ID))
+                && e.payload.get("Message").map(String::as_str) == Some("Sunray")
+        }));
+        // every event belongs to the planet
+        assert!(events.iter().all(|e| {
+            [&e.sender, &e.receiver]
+                .into_iter()
+                .flatten()
+                .any(|p| p.actor_type == ActorType::Planet && p.id == PLANET_ID)
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn t19_log_config_defaults() {
+        println!("+++++ Test log config defaults +++++");
+        let config = LogConfig::default();
+        assert_eq!(config.channel(LogCategory::Received), Some(Channel::Info));
+        assert_eq!(config.channel(LogCategory::Ack), Some(Channel::Info));
+        assert_eq!(config.channel(LogCategory::Error), Some(Channel::Error));
+        assert_eq!(config.channel(LogCategory::Warning), Some(Channel::Warning));
+        assert_eq!(config.channel(LogCategory::Debug), Some(Channel::Debug));
+        assert_eq!(config.channel(LogCategory::Trace), Some(Channel::Trace));
+        assert_eq!(
+            config.channel(LogCategory::InternalAction),
+            Some(Channel::Info)
+        );
+
+        let config = config
+            .route(LogCategory::Trace, Channel::Warning)
+            .disable(LogCategory::Ack);
+        assert_eq!(config.channel(LogCategory::Trace), Some(Channel::Warning));
+        assert_eq!(config.channel(LogCategory::Ack), None);
+    }
+
+    #[test]
+    fn t20_log_config_routing() -> Result<(), String> {
+        println!("+++++ Test log config routing +++++");
+        const PLANET_ID: u32 = 801;
+        let path = std::env::temp_dir().join(format!("crab_rave_t20_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let config = LogConfig::default()
+            .route(LogCategory::Received, Channel::Error)
+            .route(LogCategory::Debug, Channel::Warning)
+            .disable(LogCategory::Ack);
+        let planet = PlanetHarness::spawn(
+            PlanetBuilder::new(PLANET_ID)
+                .log_config(config)
+                .log_sink(LogSink::Json(path.clone())),
+        )?;
+        planet.start()?;
+        planet.sunray()?;
+        planet.kill()?;
+
+        let events = json_log::read_events(&path)?;
+        let _ = std::fs::remove_file(&path);
+        // the sunray is logged on the channel chosen for the received messages
+        assert!(events.iter().any(|e| {
+            e.event_type == EventType::MessageOrchestratorToPlanet
+                && e.payload.get("Message").map(String::as_str) == Some("Sunray")
+                && e.channel == Channel::Error
+        }));
+        // the acks are disabled
+        assert!(
+            !events
+                .iter()
+                .any(|e| e.event_type == EventType::MessagePlanetToOrchestrator)
+        );
+        // the debug messages moved to the warning channel
+        assert!(events.iter().all(|e| e.channel != Channel::Debug));
+        assert!(events.iter().any(|e| {
+            e.event_type == EventType::InternalPlanetAction && e.channel == Channel::Warning
+        }));
+        // untouched categories keep their channel
+        assert!(events.iter().any(|e| e.channel == Channel::Trace));
+        Ok(())
+    }
+
+    #[test]
+    fn t21_e2e_start_stop() -> Result<(), String> {
+        println!("+++++ Test e2e start and stop +++++");
+        let planet = PlanetHarness::create(810)?;
+
+        // the planet runs stopped until the orchestrator starts it
+        assert!(matches!(
+            planet.request(OrchestratorToPlanet::Sunray(Sunray::default()))?,
+            PlanetToOrchestrator::Stopped { planet_id: 810 }
+        ));
+        planet.start()?;
+        let explorer = planet.land_explorer(1)?;
+        planet.sunray()?;
+        assert_eq!(explorer.available_cells()?, 1);
+
+        planet.stop()?;
+        assert!(matches!(
+            planet.request(OrchestratorToPlanet::InternalStateRequest)?,
+            PlanetToOrchestrator::Stopped { .. }
+        ));
+        assert!(matches!(
+            explorer.request(ExplorerToPlanet::AvailableEnergyCellRequest { explorer_id: 1 })?,
+            PlanetToExplorer::Stopped
+        ));
+
+        // the AI state survives the stop
+        planet.start()?;
+        assert_eq!(explorer.available_cells()?, 1);
+        planet.kill()
+    }
+
+    #[test]
+    fn t22_e2e_sunray_asteroid() -> Result<(), String> {
+        println!("+++++ Test e2e sunray and asteroid +++++");
+        // type D planets can't build rockets
+        let planet = PlanetHarness::create(811)?;
+        planet.start()?;
+        planet.sunrays(3)?;
+        assert_eq!(planet.internal_state()?.charged_cells_count, 3);
+        assert!(planet.asteroid()?.is_none());
+        planet.kill()?;
+
+        let planet = PlanetHarness::spawn(rocket_planet(PlanetBuilder::new(812)))?;
+        planet.start()?;
+        assert!(planet.asteroid()?.is_none());
+        planet.sunrays(2)?;
+        assert!(planet.asteroid()?.is_some());
+        let state = planet.internal_state()?;
+        assert_eq!(state.charged_cells_count, 1);
+        assert!(!state.has_rocket);
+        planet.kill()
+    }
+
+    #[test]
+    fn t23_e2e_explorer_requests() -> Result<(), String> {
+        println!("+++++ Test e2e explorer requests +++++");
+        use BasicResourceType::*;
+        let planet = PlanetHarness::create(813)?;
+        planet.start()?;
+        let explorer = planet.land_explorer(7)?;
+
+        assert_eq!(
+            explorer.supported_resources()?,
+            [Carbon, Hydrogen, Oxygen, Silicon].into_iter().collect()
+        );
+        assert!(explorer.supported_combinations()?.is_empty());
+        assert_eq!(explorer.available_cells()?, 0);
+        assert!(explorer.generate(Carbon)?.is_none());
+
+        planet.sunrays(2)?;
+        assert_eq!(explorer.available_cells()?, 2);
+        let hydrogen = explorer.generate(Hydrogen)?.ok_or("no hydrogen")?;
+        assert_eq!(hydrogen.get_type(), Hydrogen);
+        let oxygen = explorer.generate(Oxygen)?.ok_or("no oxygen")?;
+        assert_eq!(explorer.available_cells()?, 0);
+
+        // a type D planet has no combination rules, the ingredients come back
+        let request = ComplexResourceRequest::Water(hydrogen.to_hydrogen()?, oxygen.to_oxygen()?);
+        match explorer.combine(request)? {
+            Ok(water) => return Err(format!("unexpected combination: {:?}", water)),
+            Err((_, first, second)) => {
+                assert!(first.to_hydrogen().is_ok());
+                assert!(second.to_oxygen().is_ok());
+            }
+        }
+
+        planet.remove_explorer(7)?;
+        planet.kill()
+    }
+
+    #[test]
+    fn t24_e2e_kill() -> Result<(), String> {
+        println!("+++++ Test e2e kill +++++");
+        // a planet can be killed before it is started
+        PlanetHarness::create(814)?.kill()?;
+
+        // and while it is stopped
+        let planet = PlanetHarness::create(815)?;
+        planet.start()?;
+        planet.stop()?;
+        planet.kill()
+    }
+
+    #[test]
+    fn t25_crafting_planet_types() -> Result<(), String> {
+        println!("+++++ Test crafting planet types +++++");
+        use ComplexResourceType::*;
+        // a single recipe fits a type B planet, which keeps every generation rule
+        let planet = PlanetHarness::spawn(PlanetBuilder::crafting(820, vec![Dolphin]))?;
+        planet.start()?;
+        let explorer = planet.land_explorer(1)?;
+        assert_eq!(
+            explorer.supported_combinations()?,
+            [Dolphin].into_iter().collect()
+        );
+        assert_eq!(explorer.supported_resources()?.len(), 4);
+        planet.kill()?;
+
+        // more recipes need a type C planet
+        let recipes = vec![Water, Diamond, Life];
+        let planet = PlanetHarness::spawn(PlanetBuilder::crafting(821, recipes.clone()))?;
+        planet.start()?;
+        let explorer = planet.land_explorer(1)?;
+        assert_eq!(
+            explorer.supported_combinations()?,
+            recipes.into_iter().collect()
+        );
+        assert_eq!(
+            explorer.supported_resources()?,
+            [BasicResourceType::Carbon].into_iter().collect()
+        );
+        planet.kill()?;
+
+        // types A and D can't combine
+        for planet_type in [PlanetType::A, PlanetType::D] {
+            let builder = PlanetBuilder::crafting(0, vec![Water]).planet_type(planet_type);
+            assert!(builder.validate().is_err());
+        }
+        assert!(
+            PlanetBuilder::crafting(0, vec![Water, Water, Life])
+                .validate()
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn t26_craft_every_complex_resource() -> Result<(), String> {
+        println!("+++++ Test craft every complex resource +++++");
+        use BasicResourceType::*;
+        use ComplexResourceType::*;
+
+        // the default planet supplies the basic resources, the crafting planet combines them
+        let supplier = PlanetHarness::create(822)?;
+        supplier.start()?;
+        let miner = supplier.land_explorer(1)?;
+        let crafter = PlanetHarness::spawn(PlanetBuilder::crafting(
+            823,
+            vec![Water, Diamond, Life, Robot, Dolphin, AIPartner],
+        ))?;
+        crafter.start()?;
+        let artisan = crafter.land_explorer(1)?;
+
+        let mine = |resource: BasicResourceType| -> Result<BasicResource, String> {
+            supplier.sunray()?;
+            miner
+                .generate(resource)?
+                .ok_or(format!("{:?} not generated", resource))
+        };
+        // the crafting planet has a single cell, it is charged before every combination
+        let craft = |request: ComplexResourceRequest| -> Result<ComplexResource, String> {
+            crafter.sunray()?;
+            artisan
+                .combine(request)?
+                .map_err(|(e, _, _)| format!("combination failed: {}", e))
+        };
+
+        let water = || -> Result<ComplexResource, String> {
+            craft(ComplexResourceRequest::Water(
+                mine(Hydrogen)?.to_hydrogen()?,
+                mine(Oxygen)?.to_oxygen()?,
+            ))
+        };
+        let life = || -> Result<ComplexResource, String> {
+            craft(ComplexResourceRequest::Life(
+                water()?.to_water()?,
+                mine(Carbon)?.to_carbon()?,
+            ))
+        };
+        let diamond = || -> Result<ComplexResource, String> {
+            craft(ComplexResourceRequest::Diamond(
+                mine(Carbon)?.to_carbon()?,
+                mine(Carbon)?.to_carbon()?,
+            ))
+        };
+        let robot = || -> Result<ComplexResource, String> {
+            craft(ComplexResourceRequest::Robot(
+                mine(Silicon)?.to_silicon()?,
+                life()?.to_life()?,
+            ))
+        };
+
+        assert_eq!(water()?.get_type(), Water);
+        assert_eq!(diamond()?.get_type(), Diamond);
+        assert_eq!(life()?.get_type(), Life);
+        assert_eq!(robot()?.get_type(), Robot);
+        let dolphin = craft(ComplexResourceRequest::Dolphin(
+            water()?.to_water()?,
+            life()?.to_life()?,
+        ))?;
+        assert_eq!(dolphin.get_type(), Dolphin);
+        let partner = craft(ComplexResourceRequest::AIPartner(
+            robot()?.to_robot()?,
+            diamond()?.to_diamond()?,
+        ))?;
+        assert_eq!(partner.get_type(), AIPartner);
+
+        // every combination used the charge of the cell
+        assert_eq!(crafter.internal_state()?.charged_cells_count, 0);
+        supplier.kill()?;
+        crafter.kill()
+    }
+
+    #[test]
+    fn t27_rebuild_stacks() -> Result<(), String> {
+        println!("+++++ Test rebuild stacks +++++");
+        let (_orch_sender, planet_receiver) = unbounded::<OrchestratorToPlanet>();
+        let (planet_sender, _orch_receiver) = unbounded::<PlanetToOrchestrator>();
+        let (_explorer_sender, explorer_receiver) = unbounded::<ExplorerToPlanet>();
+        let planet = create_planet(planet_receiver, planet_sender, explorer_receiver, 830)?;
+
+        let mut allocator = CellAllocator::with_cells(830, CELLS);
+        // the stacks lose two cells and claim a charge the planet doesn't have
+        allocator.get_free_cell_index();
+        allocator.get_free_cell_index();
+        allocator.push_charged_cell(0);
+
+        // no cell of the planet is charged, the stacks follow the state
+        allocator.rebuild(planet.state(), &[]);
+        assert_eq!(allocator.get_charged_cell_index(), None);
+        for idx in 0..5 {
+            assert_eq!(allocator.get_free_cell_index(), Some(idx));
+        }
+        assert_eq!(allocator.get_free_cell_index(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn t28_rebuild_reports_drifted_stacks() -> Result<(), String> {
+        println!("+++++ Test rebuild reports drifted stacks +++++");
+        const PLANET_ID: u32 = 831;
+        let path = std::env::temp_dir().join(format!("crab_rave_t28_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (_orch_sender, planet_receiver) = unbounded::<OrchestratorToPlanet>();
+        let (planet_sender, _orch_receiver) = unbounded::<PlanetToOrchestrator>();
+        let (_explorer_sender, explorer_receiver) = unbounded::<ExplorerToPlanet>();
+        let planet = create_planet(planet_receiver, planet_sender, explorer_receiver, PLANET_ID)?;
+        let log = PlanetLog::with_sink(LogConfig::default(), &LogSink::Json(path.clone()))?;
+        let mut allocator = CellAllocator::with_log(PLANET_ID, 0, Arc::new(log));
+
+        // sizing an empty allocator and rebuilding matching stacks are not errors
+        allocator.rebuild(planet.state(), &[]);
+        allocator.rebuild(planet.state(), &[]);
+        // a cell claims a charge the planet doesn't have
+        let idx = allocator.get_free_cell_index().ok_or("no free cell")?;
+        allocator.push_charged_cell(idx);
+        allocator.rebuild(planet.state(), &[]);
+
+        let events = json_log::read_events(&path)?;
+        let _ = std::fs::remove_file(&path);
+        let rebuilt: Vec<&LogEvent> = events
+            .iter()
+            .filter(|e| e.payload.get("Event").map(String::as_str) == Some("CellStacksRebuilt"))
+            .collect();
+        assert_eq!(rebuilt.len(), 1);
+        assert_eq!(rebuilt[0].channel, Channel::Error);
+        assert_eq!(
+            rebuilt[0].payload.get("After").map(String::as_str),
+            Some("free: [0, 1, 2, 3, 4], charged: []")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn t29_cell_drift() -> Result<(), String> {
+        println!("+++++ Test cell drift +++++");
+        let (_orch_sender, planet_receiver) = unbounded::<OrchestratorToPlanet>();
+        let (planet_sender, _orch_receiver) = unbounded::<PlanetToOrchestrator>();
+        let (_explorer_sender, explorer_receiver) = unbounded::<ExplorerToPlanet>();
+        let planet = create_planet(planet_receiver, planet_sender, explorer_receiver, 832)?;
+        // no cell of the planet is charged
+        let state = planet.state();
+
+        assert!(invariants::compare(&[4, 3, 2, 1, 0], &[], &[], state).is_empty());
+        let drift = invariants::compare(&[4, 3, 3], &[1], &[7], state);
+        assert_eq!(
+            drift,
+            CellDrift {
+                lost: vec![0, 2],
+                duplicated: vec![3],
+                charged_in_free_stack: vec![],
+                empty_in_charged_stack: vec![1],
+                out_of_range: vec![7],
+            }
+        );
+
+        let mut ai = OneMillionCrabs::new(832);
+        ai.resync_cells(state);
+        assert!(ai.check_cells(state).is_ok());
+        let idx = ai.cells().get_free_cell_index().ok_or("no free cell")?;
+        ai.cells().push_charged_cell(idx);
+        assert_eq!(
+            ai.check_cells(state).map_err(|d| d.empty_in_charged_stack),
+            Err(vec![idx])
+        );
+        ai.resync_cells(state);
+        assert!(ai.check_cells(state).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn t30_resync_drifted_stacks() -> Result<(), String> {
+        println!("+++++ Test resync drifted stacks +++++");
+        const PLANET_ID: u32 = 833;
+        let path = std::env::temp_dir().join(format!("crab_rave_t30_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let log = PlanetLog::with_sink(LogConfig::default(), &LogSink::LogAndJson(path.clone()))?;
+        let inner = OneMillionCrabs::with_log(PLANET_ID, Arc::new(log)).with_invariant_policy(
+            InvariantPolicy {
+                after_every_handler: true,
+                resync: true,
+            },
+        );
+        // the charged cell is dropped by the allocator after the first sunray
+        let mut done = false;
+        let ai = HookedAI::new(inner, move |ai, _, _| {
+            if !done {
+                done = true;
+                ai.cells().get_charged_cell_index();
+            }
+        });
+        let planet = PlanetHarness::spawn(PlanetBuilder::new(PLANET_ID).ai(ai))?;
+        planet.start()?;
+        let explorer = planet.land_explorer(1)?;
+        planet.sunray()?;
+        // the check after the next handler finds the lost cell and gives it back
+        planet.internal_state()?;
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_some());
+        planet.kill()?;
+
+        let events = json_log::read_events(&path)?;
+        let _ = std::fs::remove_file(&path);
+        let drift = events
+            .iter()
+            .find(|e| e.payload.get("Event").map(String::as_str) == Some("CellDrift"))
+            .ok_or("no drift reported")?;
+        assert_eq!(drift.channel, Channel::Error);
+        assert_eq!(drift.payload.get("Lost").map(String::as_str), Some("[0]"));
+        Ok(())
+    }
+
+    #[test]
+    fn t31_metrics() -> Result<(), String> {
+        println!("+++++ Test metrics +++++");
+        let planet = PlanetHarness::spawn(rocket_planet(PlanetBuilder::new(840)))?;
+        planet.start()?;
+        let explorer = planet.land_explorer(3)?;
+        // 5 cells, the last 2 sunrays are wasted
+        planet.sunrays(7)?;
+        assert!(planet.asteroid()?.is_some());
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_some());
+        assert!(explorer.generate(BasicResourceType::Oxygen)?.is_none());
+        explorer.available_cells()?;
+
+        let snapshot = metrics::snapshot(840).ok_or("no metrics")?;
+        assert_eq!(snapshot.sunrays_received, 7);
+        assert_eq!(snapshot.sunrays_wasted, 2);
+        assert_eq!(snapshot.asteroids_survived, 1);
+        assert_eq!(snapshot.asteroids_failed, 0);
+        assert_eq!(snapshot.rockets_built, 1);
+        assert_eq!(snapshot.generated.get(&BasicResourceType::Carbon), Some(&1));
+        assert_eq!(snapshot.total_generated(), 1);
+        assert_eq!(snapshot.total_combined(), 0);
+        assert_eq!(snapshot.explorer_requests.get(&3), Some(&3));
+        planet.kill()?;
+
+        // type D planets can't defend themselves
+        let planet = PlanetHarness::create(841)?;
+        planet.start()?;
+        assert!(planet.asteroid()?.is_none());
+        let snapshot = metrics::snapshot(841).ok_or("no metrics")?;
+        assert_eq!(snapshot.asteroids_failed, 1);
+        assert_eq!(snapshot.rockets_built, 0);
+        assert!(metrics::planets().contains(&841));
+        // the metrics go away with the AI
+        planet.kill()?;
+        assert!(metrics::snapshot(841).is_none());
+        assert!(!metrics::planets().contains(&841));
+        Ok(())
+    }
+
+    #[cfg(feature = "prometheus")]
+    fn http_get(addr: std::net::SocketAddr, path: &str) -> Result<String, String> {
+        use std::io::{Read, Write};
+        let mut stream = std::net::TcpStream::connect(addr).map_err(|e| e.to_string())?;
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)
+            .map_err(|e| e.to_string())?;
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .map_err(|e| e.to_string())?;
+        Ok(response)
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn t32_prometheus_endpoint() -> Result<(), String> {
+        println!("+++++ Test prometheus endpoint +++++");
+        let server = crate::prometheus::serve(0)?;
+        let planet = PlanetHarness::create(850)?;
+        planet.start()?;
+        planet.sunrays(3)?;
+        let explorer = planet.land_explorer(4)?;
+        explorer.generate(BasicResourceType::Carbon)?;
+
+        let response = http_get(server.local_addr(), "/metrics")?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("# TYPE crab_rave_sunrays_received_total counter"));
+        assert!(response.contains("crab_rave_sunrays_received_total{planet=\"850\"} 3"));
+        assert!(response.contains("crab_rave_charged_cells{planet=\"850\"} 2"));
+        assert!(response.contains("crab_rave_cells{planet=\"850\"} 5"));
+        assert!(
+            response.contains(
+                "crab_rave_resources_generated_total{planet=\"850\",resource=\"Carbon\"} 1"
+            )
+        );
+        assert!(
+            response.contains("crab_rave_explorer_requests_total{planet=\"850\",explorer=\"4\"} 1")
+        );
+
+        let response = http_get(server.local_addr(), "/")?;
+        assert!(response.starts_with("HTTP/1.1 404"));
+        planet.kill()?;
+        server.shutdown();
+        Ok(())
+    }
+
+    #[test]
+    fn t33_snapshot_restore() -> Result<(), String> {
+        println!("+++++ Test snapshot restore +++++");
+        let path = std::env::temp_dir().join(format!("crab_rave_t33_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let planet = PlanetHarness::spawn(
+            rocket_planet(PlanetBuilder::new(860))
+                .reservation_window(Duration::from_secs(60))
+                .checkpoint(&path),
+        )?;
+        planet.start()?;
+        planet.sunrays(3)?;
+        let explorer = planet.land_explorer(7)?;
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_some());
+        // reserves one of the two charged cells
+        assert_eq!(explorer.available_cells()?, 2);
+        planet.internal_state()?;
+        planet.kill()?;
+
+        let snapshot = PlanetSnapshot::load(&path)?;
+        assert_eq!(snapshot.planet_id, 860);
+        assert!(matches!(snapshot.planet_type, PlanetType::A));
+        assert_eq!(snapshot.gen_rules, vec![BasicResourceType::Carbon]);
+        assert_eq!(snapshot.reservation_window, Some(Duration::from_secs(60)));
+        assert_eq!(snapshot.metrics.sunrays_received, 3);
+        let (explorer_id, visitor) = &snapshot.visitors[0];
+        assert_eq!(*explorer_id, 7);
+        assert_eq!(visitor.consumed_cells, 1);
+
+        // the restored planet keeps the counters but its cells are empty
+        let planet = PlanetHarness::spawn_with(|rx_orch, tx_orch, rx_explorer| {
+            create_planet_from_snapshot(rx_orch, tx_orch, rx_explorer, &path)
+        })?;
+        planet.start()?;
+        let restored = metrics::snapshot(860).ok_or("no metrics")?;
+        assert_eq!(restored.sunrays_received, 3);
+        assert_eq!(restored.generated.get(&BasicResourceType::Carbon), Some(&1));
+        assert_eq!(restored.charged_cells, 0);
+        let explorer = planet.land_explorer(7)?;
+        // the stacks follow the empty cells, not the ones of the old planet
+        assert_eq!(explorer.available_cells()?, 0);
+
+        planet.sunray()?;
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_some());
+        planet.internal_state()?;
+        planet.kill()?;
+
+        let snapshot = PlanetSnapshot::load(&path)?;
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(snapshot.metrics.sunrays_received, 4);
+        assert_eq!(snapshot.metrics.explorer_requests.get(&7), Some(&4));
+        assert_eq!(snapshot.visitors[0].1.consumed_cells, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn t34_record_replay() -> Result<(), String> {
+        println!("+++++ Test record replay +++++");
+        let path = std::env::temp_dir().join(format!("crab_rave_t34_{}.jsonl", std::process::id()));
+        let planet = PlanetHarness::spawn_with(|rx_orch, tx_orch, rx_explorer| {
+            replay::create_recorded_planet(rx_orch, tx_orch, rx_explorer, 870, &path)
+        })?;
+        planet.start()?;
+        planet.sunrays(4)?;
+        let explorer = planet.land_explorer(5)?;
+        explorer.supported_resources()?;
+        let hydrogen = explorer
+            .generate(BasicResourceType::Hydrogen)?
+            .ok_or("no hydrogen")?;
+        let oxygen = explorer
+            .generate(BasicResourceType::Oxygen)?
+            .ok_or("no oxygen")?;
+        // type D planets can't combine, the ingredients come back
+        let water = ComplexResourceRequest::Water(hydrogen.to_hydrogen()?, oxygen.to_oxygen()?);
+        assert!(explorer.combine(water)?.is_err());
+        planet.asteroid()?;
+        planet.internal_state()?;
+        planet.remove_explorer(5)?;
+        planet.kill()?;
+
+        let entries = replay::read_recording(&path)?;
+        let inbound = entries
+            .iter()
+            .filter(|e| e.direction == replay::Direction::ToPlanet)
+            .count();
+        // start, 4 sunrays, landing, 4 explorer requests, asteroid, internal state, departure, kill
+        assert_eq!(inbound, 14);
+        assert_eq!(entries.len(), 28);
+
+        let report = replay::Replayer::load(&path)?.run(870)?;
+        assert!(report.is_clean(), "{}", report);
+        assert_eq!(report.replayed, 14);
+        assert_eq!(report.responses, 14);
+
+        // a planet generating only Carbon answers the explorer differently
+        let report = replay::Replayer::load(&path)?.run_with(|rx_orch, tx_orch, rx_explorer| {
+            rocket_planet(PlanetBuilder::new(870)).build(rx_orch, tx_orch, rx_explorer)
+        })?;
+        let _ = std::fs::remove_file(&path);
+        assert!(!report.is_clean());
+        assert!(
+            report
+                .mismatches
+                .iter()
+                .any(|m| m.stream == replay::Stream::Explorer(5))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn t35_explorer_quotas() -> Result<(), String> {
+        println!("+++++ Test explorer quotas +++++");
+        // every sunray starts a new tick
+        let quota = ExplorerQuota {
+            requests_per_tick: Some(2),
+            cells_per_visit: Some(3),
+        };
+        let planet = PlanetHarness::spawn(PlanetBuilder::new(880).explorer_quota(quota))?;
+        planet.start()?;
+        planet.sunrays(4)?;
+        let explorer = planet.land_explorer(1)?;
+        // the informative requests are not counted
+        assert_eq!(explorer.available_cells()?, 4);
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_some());
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_some());
+        // third request of the tick
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_none());
+        // and they are answered truthfully
+        assert_eq!(explorer.available_cells()?, 2);
+        planet.sunray()?;
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_some());
+        // fourth cell of the visit
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_none());
+        planet.remove_explorer(1)?;
+        let explorer = planet.land_explorer(1)?;
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_some());
+        let snapshot = metrics::snapshot(880).ok_or("no metrics")?;
+        assert_eq!(snapshot.throttled_requests.get(&1), Some(&2));
+        planet.kill()?;
+
+        // round robin: a second cell only after the other explorer had its own
+        let planet =
+            PlanetHarness::spawn(PlanetBuilder::new(881).fairness(FairnessPolicy::RoundRobin))?;
+        planet.start()?;
+        planet.sunrays(5)?;
+        let greedy = planet.land_explorer(1)?;
+        let polite = planet.land_explorer(2)?;
+        assert!(greedy.generate(BasicResourceType::Carbon)?.is_some());
+        assert!(greedy.generate(BasicResourceType::Carbon)?.is_none());
+        assert!(polite.generate(BasicResourceType::Carbon)?.is_some());
+        assert!(greedy.generate(BasicResourceType::Carbon)?.is_some());
+        // a new tick gives everyone a new round
+        planet.sunray()?;
+        assert!(greedy.generate(BasicResourceType::Carbon)?.is_some());
+        planet.kill()?;
+
+        // weighted: explorer 1 gets two cells per round
+        let planet = PlanetHarness::spawn(
+            PlanetBuilder::new(882).fairness(FairnessPolicy::weighted([(1, 2)])),
+        )?;
+        planet.start()?;
+        planet.sunrays(5)?;
+        let heavy = planet.land_explorer(1)?;
+        let light = planet.land_explorer(2)?;
+        assert!(heavy.generate(BasicResourceType::Carbon)?.is_some());
+        assert!(heavy.generate(BasicResourceType::Carbon)?.is_some());
+        assert!(heavy.generate(BasicResourceType::Carbon)?.is_none());
+        assert!(light.generate(BasicResourceType::Carbon)?.is_some());
+        // everyone had its share, a new round starts
+        assert!(heavy.generate(BasicResourceType::Carbon)?.is_some());
+        assert!(light.generate(BasicResourceType::Carbon)?.is_some());
+
+        planet.sunrays(3)?;
+        assert!(heavy.generate(BasicResourceType::Carbon)?.is_some());
+        assert!(heavy.generate(BasicResourceType::Carbon)?.is_some());
+        assert!(heavy.generate(BasicResourceType::Carbon)?.is_none());
+        // explorer 1 doesn't wait for an explorer that left
+        planet.remove_explorer(2)?;
+        assert!(heavy.generate(BasicResourceType::Carbon)?.is_some());
+        let snapshot = metrics::snapshot(882).ok_or("no metrics")?;
+        assert_eq!(snapshot.throttled_requests.get(&1), Some(&2));
+        assert_eq!(snapshot.throttled_requests.get(&2), None);
+        planet.kill()?;
+        Ok(())
+    }
+
+    #[test]
+    fn t36_interleaved_explorers() -> Result<(), String> {
+        println!("+++++ Test interleaved explorers +++++");
+        let generate = |explorer: &harness::MockExplorer, resource| {
+            explorer.send(ExplorerToPlanet::GenerateResourceRequest {
+                explorer_id: explorer.id(),
+                resource,
+            })
+        };
+        let generated = |explorer: &harness::MockExplorer| match explorer.recv()? {
+            PlanetToExplorer::GenerateResourceResponse { resource } => {
+                Ok(resource.map(|r| r.get_type()))
+            }
+            other => Err(format!("unexpected response {:?}", other)),
+        };
+
+        let planet = PlanetHarness::create(890)?;
+        planet.start()?;
+        planet.sunrays(5)?;
+        let first = planet.land_explorer(1)?;
+        let second = planet.land_explorer(2)?;
+        // every request is sent before any response is read
+        generate(&first, BasicResourceType::Hydrogen)?;
+        generate(&second, BasicResourceType::Oxygen)?;
+        generate(&first, BasicResourceType::Carbon)?;
+        generate(&second, BasicResourceType::Silicon)?;
+        assert_eq!(generated(&second)?, Some(BasicResourceType::Oxygen));
+        assert_eq!(generated(&first)?, Some(BasicResourceType::Hydrogen));
+        assert_eq!(generated(&second)?, Some(BasicResourceType::Silicon));
+        assert_eq!(generated(&first)?, Some(BasicResourceType::Carbon));
+
+        planet.sunrays(2)?;
+        let hydrogen = first
+            .generate(BasicResourceType::Hydrogen)?
+            .ok_or("no hydrogen")?;
+        let oxygen = second
+            .generate(BasicResourceType::Oxygen)?
+            .ok_or("no oxygen")?;
+        planet.kill()?;
+
+        // a single cell: the combine request of the first explorer takes it
+        let planet = PlanetHarness::spawn(PlanetBuilder::crafting(
+            891,
+            vec![ComplexResourceType::Water],
+        ))?;
+        planet.start()?;
+        planet.sunray()?;
+        let first = planet.land_explorer(1)?;
+        let second = planet.land_explorer(2)?;
+        first.send(ExplorerToPlanet::CombineResourceRequest {
+            explorer_id: first.id(),
+            msg: ComplexResourceRequest::Water(hydrogen.to_hydrogen()?, oxygen.to_oxygen()?),
+        })?;
+        generate(&second, BasicResourceType::Carbon)?;
+        assert_eq!(generated(&second)?, None);
+        match first.recv()? {
+            PlanetToExplorer::CombineResourceResponse {
+                complex_response: Ok(water),
+            } => assert_eq!(water.get_type(), ComplexResourceType::Water),
+            other => return Err(format!("unexpected response {:?}", other)),
+        }
+        planet.kill()?;
+        Ok(())
+    }
+
+    /// What the AI can do with the cell stacks.
+    #[derive(Debug, Clone, Copy)]
+    enum CellOp {
+        /// a sunray charges a free cell
+        Charge,
+        /// a charged cell is spent, by a generator, a combinator or a rocket
+        Spend,
+        /// the build_rocket or generator call fails, the cell goes back charged
+        SpendFailed,
+        /// an explorer reserves a charged cell, held outside the stacks
+        Reserve,
+        /// a reservation expires or its explorer leaves
+        Release,
+    }
+
+    fn cell_op() -> impl proptest::strategy::Strategy<Value = CellOp> {
+        proptest::prop_oneof![
+            proptest::strategy::Just(CellOp::Charge),
+            proptest::strategy::Just(CellOp::Spend),
+            proptest::strategy::Just(CellOp::SpendFailed),
+            proptest::strategy::Just(CellOp::Reserve),
+            proptest::strategy::Just(CellOp::Release),
+        ]
+    }
+
+    /// Every cell is in exactly one place, and its place matches its charge in `charged`.
+    fn check_stacks(
+        cells: &CellAllocator,
+        charged: &[bool],
+        held: &[u32],
+    ) -> Result<(), proptest::test_runner::TestCaseError> {
+        let (free_stack, charged_stack) = cells.snapshot();
+        let mut seen = vec![0; charged.len()];
+        for idx in free_stack.iter().chain(&charged_stack).chain(held) {
+            proptest::prop_assert!((*idx as usize) < charged.len(), "cell {} out of range", idx);
+            seen[*idx as usize] += 1;
+        }
+        proptest::prop_assert!(
+            seen.iter().all(|n| *n == 1),
+            "duplicated or lost cells: free {:?}, charged {:?}, held {:?}",
+            free_stack,
+            charged_stack,
+            held
+        );
+        for idx in &free_stack {
+            proptest::prop_assert!(!charged[*idx as usize], "charged cell {} is free", idx);
+        }
+        for idx in charged_stack.iter().chain(held) {
+            proptest::prop_assert!(charged[*idx as usize], "empty cell {} is charged", idx);
+        }
+        Ok(())
+    }
+
+    /// Cells of the allocators built by the stack tests, as many as a type D planet.
+    const CELLS: usize = 5;
+
+    proptest::proptest! {
+        #[test]
+        fn t37_cell_stack_properties(ops in proptest::collection::vec(cell_op(), 0..200)) {
+            let mut cells = CellAllocator::with_cells(0, CELLS);
+            // the cells of the planet and the ones reserved by explorers
+            let mut charged = vec![false; CELLS];
+            let mut held = Vec::new();
+            check_stacks(&cells, &charged, &held)?;
+            for op in ops {
+                match op {
+                    CellOp::Charge => match cells.get_free_cell_index() {
+                        Some(idx) => {
+                            charged[idx as usize] = true;
+                            cells.push_charged_cell(idx);
+                        }
+                        None => proptest::prop_assert!(charged.iter().all(|c| *c)),
+                    },
+                    CellOp::Spend | CellOp::SpendFailed | CellOp::Reserve => {
+                        match cells.get_charged_cell_index() {
+                            Some(idx) => match op {
+                                CellOp::Spend => {
+                                    charged[idx as usize] = false;
+                                    cells.push_free_cell(idx);
+                                }
+                                CellOp::SpendFailed => cells.push_charged_cell(idx),
+                                _ => held.push(idx),
+                            },
+                            None => proptest::prop_assert_eq!(
+                                charged.iter().filter(|c| **c).count(),
+                                held.len()
+                            ),
+                        }
+                    }
+                    CellOp::Release => {
+                        if let Some(idx) = held.pop() {
+                            cells.push_charged_cell(idx);
+                        }
+                    }
+                }
+                check_stacks(&cells, &charged, &held)?;
+            }
+        }
+    }
+
+    #[test]
+    fn t38_cell_stack_overflow() {
+        println!("+++++ Test cell stack overflow +++++");
+        let mut cells = CellAllocator::with_cells(0, CELLS);
+        // the free stack is full, a push is ignored
+        cells.push_free_cell(0);
+        assert_eq!(cells.snapshot().0.len(), CELLS);
+        for idx in 0..=CELLS as u32 {
+            cells.push_charged_cell(idx);
+        }
+        assert_eq!(cells.snapshot().1.len(), CELLS);
+        // popping an empty stack gives nothing and changes nothing
+        let mut cells = CellAllocator::with_cells(0, CELLS);
+        for _ in 0..CELLS {
+            assert!(cells.get_free_cell_index().is_some());
+        }
+        assert_eq!(cells.get_free_cell_index(), None);
+        assert_eq!(cells.get_charged_cell_index(), None);
+        assert_eq!(cells.snapshot(), (vec![], vec![]));
+    }
+
+    #[test]
+    fn t39_cells_from_planet_state() -> Result<(), String> {
+        println!("+++++ Test cells from planet state +++++");
+        // a type B planet has a single cell, a type D planet has five
+        for (planet_id, builder, n_cells) in [
+            (
+                900,
+                PlanetBuilder::crafting(900, vec![ComplexResourceType::Water]),
+                1,
+            ),
+            (901, PlanetBuilder::new(901), 5),
+        ] {
+            let path = std::env::temp_dir().join(format!(
+                "crab_rave_t39_{}_{}.jsonl",
+                planet_id,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            let planet = PlanetHarness::spawn(
+                builder
+                    .invariant_policy(InvariantPolicy {
+                        after_every_handler: true,
+                        resync: false,
+                    })
+                    .log_sink(LogSink::LogAndJson(path.clone())),
+            )?;
+            planet.start()?;
+            planet.sunrays(n_cells + 2)?;
+            let state = planet.internal_state()?;
+            assert_eq!(state.energy_cells.len(), n_cells);
+            assert_eq!(state.charged_cells_count, n_cells);
+
+            let explorer = planet.land_explorer(1)?;
+            assert_eq!(explorer.available_cells()?, n_cells as u32);
+            for _ in 0..n_cells {
+                assert!(explorer.generate(BasicResourceType::Carbon)?.is_some());
+            }
+            assert!(explorer.generate(BasicResourceType::Carbon)?.is_none());
+            planet.sunray()?;
+            assert!(explorer.generate(BasicResourceType::Carbon)?.is_some());
+            planet.kill()?;
+
+            // the stacks never drifted from the cells of the planet
+            let events = json_log::read_events(&path)?;
+            let _ = std::fs::remove_file(&path);
+            assert!(
+                !events
+                    .iter()
+                    .any(|e| e.channel == Channel::Warning || e.channel == Channel::Error),
+                "unexpected warnings for {} cells: {:?}",
+                n_cells,
+                events
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t40_inventory() -> Result<(), String> {
+        println!("+++++ Test inventory +++++");
+        let planet = PlanetHarness::spawn(PlanetBuilder::new(902).inventory(2))?;
+        planet.start()?;
+        // the sixth and seventh sunray find every cell charged, the eighth a full inventory
+        planet.sunrays(8)?;
+        let m = metrics::snapshot(902).ok_or("no metrics")?;
+        assert_eq!(
+            m.inventory,
+            [
+                (BasicResourceType::Carbon, 1),
+                (BasicResourceType::Hydrogen, 1)
+            ]
+            .into()
+        );
+        assert_eq!(m.sunrays_wasted, 1);
+        assert_eq!(planet.internal_state()?.charged_cells_count, 5);
+
+        // the cells are drained, the stocked resources are still served
+        let explorer = planet.land_explorer(1)?;
+        for _ in 0..5 {
+            assert!(explorer.generate(BasicResourceType::Oxygen)?.is_some());
+        }
+        assert!(explorer.generate(BasicResourceType::Oxygen)?.is_none());
+        let carbon = explorer.generate(BasicResourceType::Carbon)?;
+        assert_eq!(
+            carbon.map(|r| r.get_type()),
+            Some(BasicResourceType::Carbon)
+        );
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_none());
+        assert!(explorer.generate(BasicResourceType::Hydrogen)?.is_some());
+
+        let m = metrics::snapshot(902).ok_or("no metrics")?;
+        assert!(m.inventory.is_empty());
+        assert_eq!(m.served_from_inventory, 2);
+        assert_eq!(m.generated.get(&BasicResourceType::Carbon), Some(&1));
+        planet.kill()?;
+
+        assert!(
+            PlanetBuilder::new(903)
+                .ai(Box::new(OneMillionCrabs::new(903)))
+                .inventory(1)
+                .validate()
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn t41_demand_planner() -> Result<(), String> {
+        println!("+++++ Test demand planner +++++");
+        let supported = [
+            BasicResourceType::Carbon,
+            BasicResourceType::Hydrogen,
+            BasicResourceType::Oxygen,
+            BasicResourceType::Silicon,
+        ];
+        let inventory = Inventory::new(2);
+        let mut planner = DemandPlanner::new(0.5);
+        // nothing asked yet: a rocket first, otherwise the least stocked resource
+        assert_eq!(planner.plan(true, &supported, &inventory), Plan::Rocket);
+        assert_eq!(
+            planner.plan(false, &supported, &inventory),
+            Plan::Preproduce(BasicResourceType::Carbon)
+        );
+
+        planner.request_basic(BasicResourceType::Oxygen);
+        planner.request_complex(ComplexResourceType::Water);
+        planner.new_tick();
+        let demand = planner.demand();
+        assert_eq!(demand.basic.get(&BasicResourceType::Oxygen), Some(&0.5));
+        assert_eq!(demand.need(BasicResourceType::Oxygen), 1.0);
+        assert_eq!(demand.need(BasicResourceType::Hydrogen), 0.5);
+        assert_eq!(demand.cells_per_tick(), 1.0);
+        // the explorers use every sunray, the inventory comes before the rocket
+        assert_eq!(
+            planner.plan(true, &supported, &inventory),
+            Plan::Preproduce(BasicResourceType::Oxygen)
+        );
+        // a quiet tick halves the demand
+        planner.new_tick();
+        assert_eq!(planner.demand().cells_per_tick(), 0.5);
+        assert_eq!(planner.plan(true, &supported, &inventory), Plan::Rocket);
+        assert_eq!(
+            planner.plan(false, &supported, &Inventory::new(0)),
+            Plan::Idle
+        );
+
+        // the first resource stocked by the planet is the one the explorer asked for
+        let path = std::env::temp_dir().join(format!("crab_rave_t41_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let planet = PlanetHarness::spawn(PlanetBuilder::new(904).inventory(2).checkpoint(&path))?;
+        planet.start()?;
+        planet.sunrays(5)?;
+        let explorer = planet.land_explorer(1)?;
+        assert!(explorer.generate(BasicResourceType::Silicon)?.is_some());
+        planet.sunrays(2)?;
+        let m = metrics::snapshot(904).ok_or("no metrics")?;
+        assert_eq!(m.inventory, [(BasicResourceType::Silicon, 1)].into());
+        assert!(explorer.generate(BasicResourceType::Silicon)?.is_some());
+        assert_eq!(planet.internal_state()?.charged_cells_count, 5);
+        planet.kill()?;
+        let snapshot = PlanetSnapshot::load(&path)?;
+        let _ = std::fs::remove_file(&path);
+        assert!(snapshot.demand.basic[&BasicResourceType::Silicon] > 0.0);
+
+        // with no demand a rocket planet keeps its spare cell in a rocket
+        let planet = PlanetHarness::spawn(rocket_planet(PlanetBuilder::new(905)).inventory(2))?;
+        planet.start()?;
+        planet.sunrays(6)?;
+        assert!(planet.internal_state()?.has_rocket);
+        planet.sunray()?;
+        let m = metrics::snapshot(905).ok_or("no metrics")?;
+        assert_eq!(m.rockets_built, 1);
+        assert_eq!(m.inventory, [(BasicResourceType::Carbon, 1)].into());
+        planet.kill()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "scripting")]
+    #[test]
+    fn t42_scripted_planet() -> Result<(), String> {
+        use crate::planet::script::PlanetScript;
+        println!("+++++ Test scripted planet +++++");
+        let dir = std::env::temp_dir();
+        let write_script = |name: &str, source: &str| {
+            let path = dir.join(format!(
+                "crab_rave_t42_{}_{}.rhai",
+                name,
+                std::process::id()
+            ));
+            std::fs::write(&path, source)
+                .map(|_| path)
+                .map_err(|e| e.to_string())
+        };
+
+        let path = write_script(
+            "rules",
+            r#"
+            fn on_sunray(planet) { if planet.charged >= 3 { "rocket" } }
+            fn on_asteroid(planet) { if planet.has_rocket { "defend" } else { "surrender" } }
+            fn on_explorer_request(planet, request) {
+                if request.kind == "generate" && request.explorer_id == 2 { "refuse" } else { "serve" }
+            }
+            "#,
+        )?;
+        let planet = PlanetHarness::spawn(rocket_planet(PlanetBuilder::new(906)).script(&path))?;
+        planet.start()?;
+        // the fourth sunray finds three charged cells, one of them becomes a rocket
+        planet.sunrays(4)?;
+        let state = planet.internal_state()?;
+        assert!(state.has_rocket);
+        assert_eq!(state.charged_cells_count, 3);
+        let first = planet.land_explorer(1)?;
+        let second = planet.land_explorer(2)?;
+        assert_eq!(first.available_cells()?, 3);
+        assert!(second.generate(BasicResourceType::Carbon)?.is_none());
+        // only the requests consuming a cell can be refused
+        assert_eq!(second.available_cells()?, 3);
+        assert!(planet.asteroid()?.is_some());
+        // no rocket left: the script surrenders even with charged cells
+        assert!(planet.asteroid()?.is_none());
+        let m = metrics::snapshot(906).ok_or("no metrics")?;
+        assert_eq!((m.asteroids_survived, m.asteroids_failed), (1, 1));
+        // a refused request is a throttled one
+        assert_eq!(m.throttled_requests.get(&2), Some(&1));
+        planet.kill()?;
+        let _ = std::fs::remove_file(&path);
+
+        // a wrong decision and a hook that never ends fall back to the default behaviour,
+        // what the script prints goes to the log of the planet
+        let log = dir.join(format!("crab_rave_t42_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+        let path = write_script(
+            "broken",
+            r#"
+            fn on_sunray(planet) { print(`charged ${planet.charged}`); "explode" }
+            fn on_explorer_request(planet, request) { loop { } }
+            "#,
+        )?;
+        let planet = PlanetHarness::spawn(
+            PlanetBuilder::new(907)
+                .script(&path)
+                .log_sink(LogSink::LogAndJson(log.clone())),
+        )?;
+        planet.start()?;
+        planet.sunrays(2)?;
+        let explorer = planet.land_explorer(1)?;
+        assert!(explorer.generate(BasicResourceType::Carbon)?.is_some());
+        assert_eq!(planet.internal_state()?.charged_cells_count, 1);
+        planet.kill()?;
+        let _ = std::fs::remove_file(&path);
+        let events = json_log::read_events(&log)?;
+        let _ = std::fs::remove_file(&log);
+        let script_errors = events
+            .iter()
+            .filter(|e| e.channel == Channel::Error)
+            .filter_map(|e| e.payload.get("Action"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            script_errors,
+            [
+                "script on_sunray",
+                "script on_sunray",
+                "script on_explorer_request"
+            ]
+        );
+        let printed = events
+            .iter()
+            .filter(|e| e.payload.get("Action").is_some_and(|a| a == "script print"))
+            .filter_map(|e| e.payload.get("Message"))
+            .collect::<Vec<_>>();
+        assert_eq!(printed, ["charged 0", "charged 1"]);
+
+        // eval is disabled, a missing script is a build error
+        assert!(PlanetScript::compile(r#"fn on_sunray(planet) { eval("1") }"#).is_err());
+        let (_orch_sender, planet_receiver) = unbounded::<OrchestratorToPlanet>();
+        let (planet_sender, _orch_receiver) = unbounded::<PlanetToOrchestrator>();
+        let (_explorer_sender, explorer_receiver) = unbounded::<ExplorerToPlanet>();
+        assert!(
+            PlanetBuilder::new(908)
+                .script(dir.join("crab_rave_t42_missing.rhai"))
+                .build(planet_receiver, planet_sender, explorer_receiver)
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn t43_log_per_planet() -> Result<(), String> {
+        println!("+++++ Test log per planet +++++");
+        // two planets with the same id, each one keeps its own configuration and sink
+        let dir = std::env::temp_dir();
+        let first_path = dir.join(format!("crab_rave_t43_first_{}.jsonl", std::process::id()));
+        let second_path = dir.join(format!("crab_rave_t43_second_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&first_path);
+        let _ = std::fs::remove_file(&second_path);
+
+        let first = PlanetHarness::spawn(
+            PlanetBuilder::new(0)
+                .log_config(LogConfig::default().route(LogCategory::Received, Channel::Error))
+                .log_sink(LogSink::Json(first_path.clone())),
+        )?;
+        let second = PlanetHarness::spawn(
+            PlanetBuilder::new(0)
+                .log_config(LogConfig::default().disable(LogCategory::Received))
+                .log_sink(LogSink::Json(second_path.clone())),
+        )?;
+        first.start()?;
+        second.start()?;
+        first.sunrays(2)?;
+        second.sunray()?;
+        first.kill()?;
+        second.kill()?;
+
+        let first_events = json_log::read_events(&first_path)?;
+        let second_events = json_log::read_events(&second_path)?;
+        let _ = std::fs::remove_file(&first_path);
+        let _ = std::fs::remove_file(&second_path);
+        let sunrays = |events: &[LogEvent]| {
+            events
+                .iter()
+                .filter(|e| e.payload.get("Message").map(String::as_str) == Some("Sunray"))
+                .map(|e| e.channel.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sunrays(&first_events), vec![Channel::Error, Channel::Error]);
+        assert!(sunrays(&second_events).is_empty());
+        // the second planet still logs its acks
+        assert!(
+            second_events
+                .iter()
+                .any(|e| e.event_type == EventType::MessagePlanetToOrchestrator)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn t44_cells_sized_at_build() -> Result<(), String> {
+        println!("+++++ Test cells sized at build +++++");
+        let path = std::env::temp_dir().join(format!("crab_rave_t44_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sizing = |path: &std::path::Path| -> Result<usize, String> {
+            let events = json_log::read_events(path)?;
+            let _ = std::fs::remove_file(path);
+            Ok(events
+                .iter()
+                .filter(|e| e.payload.get("Action").map(String::as_str) == Some("size cell stacks"))
+                .count())
+        };
+
+        // the builder sizes the single cell of a type B planet before it starts
+        let planet = PlanetHarness::spawn(
+            PlanetBuilder::crafting(0, vec![ComplexResourceType::Water])
+                .log_sink(LogSink::Json(path.clone())),
+        )?;
+        planet.start()?;
+        planet.sunrays(2)?;
+        assert_eq!(planet.internal_state()?.charged_cells_count, 1);
+        planet.kill()?;
+        assert_eq!(sizing(&path)?, 0);
+
+        // an AI created on its own is sized when it starts
+        let log = PlanetLog::with_sink(LogConfig::default(), &LogSink::Json(path.clone()))?;
+        let planet = PlanetHarness::spawn(
+            PlanetBuilder::crafting(0, vec![ComplexResourceType::Water])
+                .ai(Box::new(OneMillionCrabs::with_log(0, Arc::new(log)))),
+        )?;
+        planet.start()?;
+        planet.sunrays(2)?;
+        assert_eq!(planet.internal_state()?.charged_cells_count, 1);
+        planet.kill()?;
+        assert_eq!(sizing(&path)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn t45_batch_quotas() -> Result<(), String> {
+        println!("+++++ Test batch quotas +++++");
+        let batch = vec![
+            BasicResourceType::Carbon,
+            BasicResourceType::Hydrogen,
+            BasicResourceType::Oxygen,
+        ];
+        let quota = ExplorerQuota {
+            requests_per_tick: Some(2),
+            cells_per_visit: None,
+        };
+        let inner = OneMillionCrabs::new(883).with_quotas(quota, FairnessPolicy::FirstCome);
+        let (ai, reports) = batching_ai(inner, 5, 1, batch.clone());
+        let planet = PlanetHarness::spawn(PlanetBuilder::new(883).ai(ai))?;
+        planet.start()?;
+        planet.land_explorer(1)?;
+        planet.sunrays(5)?;
+
+        // every item counts against the quota, the third one is throttled
+        let report = reports
+            .recv_timeout(harness::DEFAULT_TIMEOUT)
+            .map_err(|e| e.to_string())?;
+        let produced: Vec<BasicResourceType> =
+            report.produced.iter().map(|r| r.get_type()).collect();
+        assert_eq!(produced, batch[..2].to_vec());
+        assert_eq!(
+            report.failed,
+            vec![(
+                BasicResourceType::Oxygen,
+                Throttle::RequestsPerTick(2).to_string()
+            )]
+        );
+        assert_eq!(planet.internal_state()?.charged_cells_count, 3);
+        let snapshot = metrics::snapshot(883).ok_or("no metrics")?;
+        assert_eq!(snapshot.throttled_requests.get(&1), Some(&1));
+        planet.kill()
+    }
+
+    #[test]
+    fn t46_demand_after_quotas() -> Result<(), String> {
+        println!("+++++ Test demand after quotas +++++");
+        let path = std::env::temp_dir().join(format!("crab_rave_t46_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let quota = ExplorerQuota {
+            requests_per_tick: Some(1),
+            cells_per_visit: None,
+        };
+        let planet = PlanetHarness::spawn(
+            PlanetBuilder::new(884)
+                .explorer_quota(quota)
+                .checkpoint(&path),
+        )?;
+        planet.start()?;
+        planet.sunrays(3)?;
+        let explorer = planet.land_explorer(1)?;
+        assert!(explorer.generate(BasicResourceType::Silicon)?.is_some());
+        // throttled, they are not demand
+        assert!(explorer.generate(BasicResourceType::Silicon)?.is_none());
+        assert!(explorer.generate(BasicResourceType::Silicon)?.is_none());
+        planet.sunray()?;
+        planet.internal_state()?;
+        planet.kill()?;
+
+        let snapshot = PlanetSnapshot::load(&path)?;
+        let _ = std::fs::remove_file(&path);
+        let demand = snapshot.demand.basic[&BasicResourceType::Silicon];
+        assert!(
+            (demand - DEFAULT_SMOOTHING).abs() < 1e-9,
+            "demand {}",
+            demand
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn t47_prometheus_silent_client() -> Result<(), String> {
+        println!("+++++ Test prometheus silent client +++++");
+        let server = crate::prometheus::serve(0)?;
+        // a client that connects and sends nothing doesn't block the others
+        let _silent =
+            std::net::TcpStream::connect(server.local_addr()).map_err(|e| e.to_string())?;
+        let response = http_get(server.local_addr(), "/metrics")?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        server.shutdown();
+        Ok(())
+    }
+
+    #[test]
+    fn t48_metrics_shared_id() -> Result<(), String> {
+        println!("+++++ Test metrics shared id +++++");
+        let first = PlanetHarness::create(885)?;
+        first.start()?;
+        first.sunrays(2)?;
+        // the id is taken, the metrics of the second planet are not registered
+        let second = PlanetHarness::create(885)?;
+        second.start()?;
+        second.sunray()?;
+        let m = metrics::snapshot(885).ok_or("no metrics")?;
+        assert_eq!(m.sunrays_received, 2);
+
+        // dropping the second planet leaves the metrics of the first one
+        second.kill()?;
+        let m = metrics::snapshot(885).ok_or("no metrics")?;
+        assert_eq!(m.sunrays_received, 2);
+        first.kill()?;
+        assert!(metrics::snapshot(885).is_none());
+
+        // the id is free again
+        let third = PlanetHarness::create(885)?;
+        assert_eq!(metrics::snapshot(885).map(|m| m.sunrays_received), Some(0));
+        third.kill()?;
+        Ok(())
+    }
+
+    #[test]
+    fn t49_explorer_hooks_bracketed() -> Result<(), String> {
+        println!("+++++ Test explorer hooks bracketed +++++");
+        // the expired reservations are given back before an explorer lands or leaves
+        let path = std::env::temp_dir().join(format!("crab_rave_t49_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let planet = PlanetHarness::spawn(
+            PlanetBuilder::new(849)
+                .reservation_window(Duration::from_millis(50))
+                .log_sink(LogSink::Json(path.clone())),
+        )?;
+        planet.start()?;
+        let first = planet.land_explorer(1)?;
+        let _second = planet.land_explorer(2)?;
+        planet.sunray()?;
+        assert_eq!(first.available_cells()?, 1);
+        thread::sleep(Duration::from_millis(100));
+        let _third = planet.land_explorer(3)?;
+        // the expired cell is charged again
+        planet.sunray()?;
+        assert_eq!(first.available_cells()?, 2);
+        thread::sleep(Duration::from_millis(100));
+        planet.remove_explorer(2)?;
+        planet.kill()?;
+
+        let events = json_log::read_events(&path)?;
+        let _ = std::fs::remove_file(&path);
+        let field = |e: &LogEvent, key: &str| e.payload.get(key).cloned().unwrap_or_default();
+        let preceding = |message: &str, explorer_id: &str| {
+            events
+                .iter()
+                .position(|e| {
+                    field(e, "Message") == message && field(e, "explorer_id") == explorer_id
+                })
+                .and_then(|i| i.checked_sub(1))
+                .map(|i| field(&events[i], "Action"))
+        };
+        assert_eq!(
+            preceding("Incoming explorer", "3").as_deref(),
+            Some("expire_reservations")
+        );
+        assert_eq!(
+            preceding("Outgoing explorer", "2").as_deref(),
+            Some("expire_reservations")
+        );
+        Ok(())
+    }
 }
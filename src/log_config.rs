@@ -0,0 +1,171 @@
+//! Runtime routing of the planet log events.
+//!
+//! Every event logged by a planet belongs to a [`LogCategory`]; the
+//! [`LogConfig`] of the planet maps each category to a log channel or
+//! disables it. The configuration is kept in the [`PlanetLog`] of the
+//! planet AI, and `log_msg!` applies the mapping when the event is logged.
+
+use crate::json_log::{JsonWriter, LogSink};
+use common_game::logging::{Channel, LogEvent};
+use std::collections::HashMap;
+
+/// Kind of a planet log event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LogCategory {
+    /// messages received from the orchestrator or the explorers
+    Received,
+    /// responses sent by the planet
+    Ack,
+    Error,
+    Warning,
+    Debug,
+    Trace,
+    /// internal actions of the planet, like its creation
+    InternalAction,
+}
+
+impl LogCategory {
+    pub const ALL: [LogCategory; 7] = [
+        LogCategory::Received,
+        LogCategory::Ack,
+        LogCategory::Error,
+        LogCategory::Warning,
+        LogCategory::Debug,
+        LogCategory::Trace,
+        LogCategory::InternalAction,
+    ];
+
+    /// Channel used when the configuration doesn't say otherwise.
+    pub fn default_channel(&self) -> Channel {
+        match self {
+            LogCategory::Received => Channel::Info,
+            LogCategory::Ack => Channel::Info,
+            LogCategory::Error => Channel::Error,
+            LogCategory::Warning => Channel::Warning,
+            LogCategory::Debug => Channel::Debug,
+            LogCategory::Trace => Channel::Trace,
+            LogCategory::InternalAction => Channel::Info,
+        }
+    }
+
+    /// Name of the environment variable read by [`LogConfig::from_env`].
+    pub fn env_var(&self) -> &'static str {
+        match self {
+            LogCategory::Received => "CRAB_RAVE_LOG_RECEIVED",
+            LogCategory::Ack => "CRAB_RAVE_LOG_ACK",
+            LogCategory::Error => "CRAB_RAVE_LOG_ERROR",
+            LogCategory::Warning => "CRAB_RAVE_LOG_WARNING",
+            LogCategory::Debug => "CRAB_RAVE_LOG_DEBUG",
+            LogCategory::Trace => "CRAB_RAVE_LOG_TRACE",
+            LogCategory::InternalAction => "CRAB_RAVE_LOG_INTERNAL_ACTION",
+        }
+    }
+}
+
+/// Maps every [`LogCategory`] to a channel, or to None to disable it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    routes: HashMap<LogCategory, Option<Channel>>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            routes: LogCategory::ALL
+                .iter()
+                .map(|c| (*c, Some(c.default_channel())))
+                .collect(),
+        }
+    }
+}
+
+impl LogConfig {
+    /// Sends the category to `channel`.
+    pub fn route(mut self, category: LogCategory, channel: Channel) -> Self {
+        self.routes.insert(category, Some(channel));
+        self
+    }
+
+    /// Drops every event of the category.
+    pub fn disable(mut self, category: LogCategory) -> Self {
+        self.routes.insert(category, None);
+        self
+    }
+
+    /// Channel of the category, None if it is disabled.
+    pub fn channel(&self, category: LogCategory) -> Option<Channel> {
+        self.routes
+            .get(&category)
+            .cloned()
+            .unwrap_or_else(|| Some(category.default_channel()))
+    }
+
+    /// Default configuration overridden by the `CRAB_RAVE_LOG_*` environment
+    /// variables (see [`LogCategory::env_var`]), whose value is one of
+    /// `error`, `warning`, `info`, `debug`, `trace` or `off`.
+    /// Unknown values are ignored.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        for category in LogCategory::ALL {
+            if let Ok(value) = std::env::var(category.env_var())
+                && let Some(route) = parse_route(&value)
+            {
+                config.routes.insert(category, route);
+            }
+        }
+        config
+    }
+}
+
+fn parse_route(value: &str) -> Option<Option<Channel>> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "error" => Some(Some(Channel::Error)),
+        "warning" | "warn" => Some(Some(Channel::Warning)),
+        "info" => Some(Some(Channel::Info)),
+        "debug" => Some(Some(Channel::Debug)),
+        "trace" => Some(Some(Channel::Trace)),
+        "off" | "none" => Some(None),
+        _ => None,
+    }
+}
+
+/// Logging of a single planet, owned by its AI and shared with its cell allocator.
+#[derive(Debug, Default)]
+pub struct PlanetLog {
+    config: LogConfig,
+    json: Option<JsonWriter>,
+}
+
+impl PlanetLog {
+    /// Logging to the `log` crate only.
+    pub fn new(config: LogConfig) -> Self {
+        Self { config, json: None }
+    }
+
+    /// Logging to `sink`, whose file is opened here.
+    pub fn with_sink(config: LogConfig, sink: &LogSink) -> Result<Self, String> {
+        Ok(Self {
+            config,
+            json: JsonWriter::open(sink)?,
+        })
+    }
+
+    pub fn config(&self) -> &LogConfig {
+        &self.config
+    }
+
+    /// Channel an event of the category is logged on, None if it is disabled.
+    /// Used by `log_msg!`.
+    pub fn channel(&self, category: LogCategory) -> Option<Channel> {
+        self.config.channel(category)
+    }
+    /// Writes the event to the JSON sink, if any.
+    /// Returns true if the event must also go to the `log` crate.
+    /// Used by `log_msg!`.
+    pub fn write_json(&self, event: &LogEvent) -> bool {
+        match &self.json {
+            Some(json) => json.write(event),
+            None => true,
+        }
+    }
+}
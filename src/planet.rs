@@ -1,3 +1,4 @@
+use common_game::components::energy_cell::EnergyCell;
 use common_game::components::planet::{
     DummyPlanetState, Planet, PlanetAI, PlanetState, PlanetType,
 };
@@ -12,28 +13,65 @@ use common_game::protocols::orchestrator_planet::{OrchestratorToPlanet, PlanetTo
 use common_game::protocols::planet_explorer::{ExplorerToPlanet, PlanetToExplorer};
 use crossbeam_channel::{Receiver, Sender};
 
+use crate::json_log::LogSink;
+use crate::log_config::{LogCategory, LogConfig, PlanetLog};
 use common_game::logging::EventType::{
     MessageOrchestratorToPlanet, MessagePlanetToExplorer, MessagePlanetToOrchestrator,
 };
 use common_game::logging::{ActorType, Channel, EventType, LogEvent, Participant, Payload};
 use common_game::utils::ID;
-use stacks::{
-    get_charged_cell_index, get_free_cell_index, initialize_free_cell_stack, push_charged_cell,
-    push_free_cell,
-};
+use defense::{AlwaysDefend, DefenseStrategy, DefenseView, RocketPolicy};
+use demand::{DemandPlanner, Plan};
+use invariants::{CellDrift, InvariantPolicy};
+use inventory::Inventory;
+use metrics::{MetricsHandle, PlanetMetrics};
+use quotas::{ExplorerQuota, FairnessPolicy, QuotaBook, Throttle};
+use reservations::ReservationBook;
+use snapshot::PlanetSnapshot;
+use stacks::CellAllocator;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use visitors::VisitorRegistry;
+
+pub mod defense;
+pub mod demand;
+pub mod invariants;
+pub mod inventory;
+pub mod metrics;
+pub mod quotas;
+pub mod reservations;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod snapshot;
+pub mod visitors;
+
 ///////////////////////////////////////////////////////////////////////////////////////////
 // CrabRave Constructor
 ///////////////////////////////////////////////////////////////////////////////////////////
-const RCV_MSG_LOG_CHNL: Channel = Channel::Info; // change this 2 in order to change the channel of the logs
-const ACK_MSG_LOG_CHNL: Channel = Channel::Info;
-const ERR_LOG_CHNL: Channel = Channel::Error;
-const DEBUG_LOG_CHNL: Channel = Channel::Debug;
-const INTRNL_ACTN_LOG_CHNL: Channel = Channel::Info;
-const TRACE_LOG_CHNL: Channel = Channel::Trace;
-const WARN_LOG_CHNL: Channel = Channel::Warning;
+// categories of the planet logs, the channel of each one is chosen at runtime by the LogConfig of the planet
+const RCV_MSG_LOG_CHNL: LogCategory = LogCategory::Received;
+const ACK_MSG_LOG_CHNL: LogCategory = LogCategory::Ack;
+const ERR_LOG_CHNL: LogCategory = LogCategory::Error;
+const DEBUG_LOG_CHNL: LogCategory = LogCategory::Debug;
+const INTRNL_ACTN_LOG_CHNL: LogCategory = LogCategory::InternalAction;
+const TRACE_LOG_CHNL: LogCategory = LogCategory::Trace;
+const WARN_LOG_CHNL: LogCategory = LogCategory::Warning;
 
 #[macro_export]
 macro_rules! log_msg {
+    ($log:expr; $event:expr, $category:expr) => {{
+        let log = &$log;
+        let mut event = $event;
+        // the LogConfig of the planet picks the channel, or drops the event
+        if let Some(channel) = log.channel($category) {
+            event.channel = channel.clone();
+            // the JSON sink of the planet, if any, decides whether the event also goes to the log crate
+            if log.write_json(&event) {
+                $crate::log_msg!(event, channel);
+            }
+        }
+    }};
     ($event:expr, $channel:expr) => {{
         match $channel {
             Channel::Info => {
@@ -56,6 +94,20 @@ macro_rules! log_msg {
 }
 #[macro_export]
 macro_rules! create_internal_log_msg {
+    ($log:expr; $id:expr, $category:expr $(,$a:expr, $b:expr)* $(,)?) => {{
+        let mut payload=Payload::new();
+        $(
+            payload.insert($a, $b);
+        )*
+        let event_deb = LogEvent::new(
+            Some(Participant::new(ActorType::Planet,$id)),
+            Some(Participant::new(ActorType::Planet,$id)),
+            EventType::InternalPlanetAction,
+            $category.default_channel(),
+            payload,
+        );
+        $crate::log_msg!($log; event_deb, $category);
+    }};
     ($id:expr, $channel:expr $(,$a:expr, $b:expr)* $(,)?) => {{
         let mut payload=Payload::new();
         $(
@@ -73,248 +125,1449 @@ macro_rules! create_internal_log_msg {
 }
 #[macro_export]
 macro_rules! create_internal_action_log_msg {
+    ($log:expr; $payload:expr, $id:expr) => {{
+        let event_deb = LogEvent::new(
+            Some(Participant::new(ActorType::Planet, $id)),
+            Some(Participant::new(ActorType::Planet, $id)),
+            EventType::InternalPlanetAction,
+            DEBUG_LOG_CHNL.default_channel(),
+            $payload,
+        );
+        $crate::log_msg!($log; event_deb, DEBUG_LOG_CHNL);
+    }};
     ($payload:expr, $id:expr) => {{
         let event_deb = LogEvent::new(
             Some(Participant::new(ActorType::Planet, $id)),
             Some(Participant::new(ActorType::Planet, $id)),
             EventType::InternalPlanetAction,
-            DEBUG_LOG_CHNL,
+            DEBUG_LOG_CHNL.default_channel(),
             $payload,
         );
-        log_msg!(event_deb, DEBUG_LOG_CHNL);
+        $crate::log_msg!(event_deb, DEBUG_LOG_CHNL.default_channel());
     }};
 }
 //This function will be called by the Orchestrator
+//The log channels can be changed with the CRAB_RAVE_LOG_* environment variables, see LogConfig::from_env
 pub fn create_planet(
     rx_orchestrator: Receiver<OrchestratorToPlanet>,
     tx_orchestrator: Sender<PlanetToOrchestrator>,
     rx_explorer: Receiver<ExplorerToPlanet>,
     planet_id: u32,
 ) -> Result<Planet, String> {
-    let (planet_type, ai, gen_rules, comb_rules, orchestrator_channels, explorer_channels) = (
-        PlanetType::D,
-        OneMillionCrabs::new(),
-        vec![Carbon, Hydrogen, Oxygen, Silicon],
-        vec![],
-        (rx_orchestrator, tx_orchestrator),
+    PlanetBuilder::new(planet_id).build(rx_orchestrator, tx_orchestrator, rx_explorer)
+}
+
+/// Creates a crab-rave planet able to combine the resources in `comb_rules`,
+/// see [`PlanetBuilder::crafting`].
+pub fn create_crafting_planet(
+    rx_orchestrator: Receiver<OrchestratorToPlanet>,
+    tx_orchestrator: Sender<PlanetToOrchestrator>,
+    rx_explorer: Receiver<ExplorerToPlanet>,
+    planet_id: u32,
+    comb_rules: Vec<ComplexResourceType>,
+) -> Result<Planet, String> {
+    PlanetBuilder::crafting(planet_id, comb_rules).build(
+        rx_orchestrator,
+        tx_orchestrator,
         rx_explorer,
-    );
-
-    //LOG
-    let mut payload = Payload::new();
-    payload.insert(
-        String::from("gen_rules"),
-        gen_rules.iter().map(|x| x.to_string_2() + ", ").collect(),
-    );
-    payload.insert("Message".to_string(), "New planet created".to_string());
-    //LOG
-
-    let new_planet = Planet::new(
-        planet_id,
-        planet_type,
-        Box::new(ai),
-        gen_rules,
-        comb_rules,
-        orchestrator_channels,
-        explorer_channels,
-    )?;
-
-    //LOG
-    let event = LogEvent::new(
-        Some(Participant::new(ActorType::Planet, planet_id)),
-        Some(Participant::new(ActorType::Planet, planet_id)),
-        EventType::InternalPlanetAction,
-        INTRNL_ACTN_LOG_CHNL,
-        payload,
-    );
-    log_msg!(event, INTRNL_ACTN_LOG_CHNL);
-    //LOG
-
-    Ok(new_planet)
+    )
+}
+
+/// Creates the crab-rave planet saved in the snapshot at `path`, which keeps
+/// being updated by the new planet, see [`PlanetBuilder::from_snapshot`].
+pub fn create_planet_from_snapshot(
+    rx_orchestrator: Receiver<OrchestratorToPlanet>,
+    tx_orchestrator: Sender<PlanetToOrchestrator>,
+    rx_explorer: Receiver<ExplorerToPlanet>,
+    path: impl Into<PathBuf>,
+) -> Result<Planet, String> {
+    let path = path.into();
+    PlanetBuilder::from_snapshot(PlanetSnapshot::load(&path)?)
+        .checkpoint(path)
+        .build(rx_orchestrator, tx_orchestrator, rx_explorer)
+}
+
+/// Builder used to configure a crab-rave planet before creating it.
+/// Every option defaults to what [`create_planet`] uses: a type D planet
+/// with the four basic generation rules, no combination rules and
+/// a [`OneMillionCrabs`] AI.
+pub struct PlanetBuilder {
+    planet_id: ID,
+    planet_type: PlanetType,
+    gen_rules: Vec<BasicResourceType>,
+    comb_rules: Vec<ComplexResourceType>,
+    ai: Option<Box<dyn PlanetAI>>,
+    defense: Option<Box<dyn DefenseStrategy>>,
+    rocket_policy: Option<RocketPolicy>,
+    reservation_window: Option<Duration>,
+    invariant_policy: Option<InvariantPolicy>,
+    explorer_quota: Option<ExplorerQuota>,
+    fairness: Option<FairnessPolicy>,
+    checkpoint: Option<PathBuf>,
+    snapshot: Option<PlanetSnapshot>,
+    inventory: Option<usize>,
+    #[cfg(feature = "scripting")]
+    script: Option<PathBuf>,
+    log_level: Option<log::LevelFilter>,
+    log_sink: LogSink,
+    log_config: LogConfig,
+}
+
+impl PlanetBuilder {
+    pub fn new(planet_id: ID) -> Self {
+        Self {
+            planet_id,
+            planet_type: PlanetType::D,
+            gen_rules: vec![Carbon, Hydrogen, Oxygen, Silicon],
+            comb_rules: vec![],
+            ai: None,
+            defense: None,
+            rocket_policy: None,
+            reservation_window: None,
+            invariant_policy: None,
+            explorer_quota: None,
+            fairness: None,
+            checkpoint: None,
+            snapshot: None,
+            inventory: None,
+            #[cfg(feature = "scripting")]
+            script: None,
+            log_level: None,
+            log_sink: LogSink::Log,
+            log_config: LogConfig::from_env(),
+        }
+    }
+
+    /// Planet supporting the recipes in `comb_rules`, with the planet type
+    /// chosen accordingly: a single recipe gives a type B planet, which keeps
+    /// the four basic generation rules, more recipes give a type C planet,
+    /// which can only generate Carbon.
+    /// An empty list is rejected when the planet is built.
+    pub fn crafting(planet_id: ID, comb_rules: Vec<ComplexResourceType>) -> Self {
+        let builder = Self::new(planet_id);
+        if comb_rules.len() <= 1 {
+            builder.planet_type(PlanetType::B).comb_rules(comb_rules)
+        } else {
+            builder
+                .planet_type(PlanetType::C)
+                .gen_rules(vec![Carbon])
+                .comb_rules(comb_rules)
+        }
+    }
+
+    /// Planet restored from a snapshot written by [`PlanetBuilder::checkpoint`]:
+    /// same id, type, rules and AI state. The defense strategy and the explorer
+    /// quotas are not saved, the cells and the inventory start empty, see [`snapshot`].
+    /// The restored visitors are only known by the AI: the orchestrator must
+    /// send `IncomingExplorerRequest` again for each of them.
+    /// The other options can still be changed before building.
+    pub fn from_snapshot(snapshot: PlanetSnapshot) -> Self {
+        let mut builder = Self::new(snapshot.planet_id)
+            .planet_type(snapshot.planet_type)
+            .gen_rules(snapshot.gen_rules.clone())
+            .comb_rules(snapshot.comb_rules.clone())
+            .rocket_policy(snapshot.rocket_policy)
+            .invariant_policy(snapshot.invariant_policy);
+        builder.reservation_window = snapshot.reservation_window;
+        builder.snapshot = Some(snapshot);
+        builder
+    }
+
+    pub fn planet_type(mut self, planet_type: PlanetType) -> Self {
+        self.planet_type = planet_type;
+        self
+    }
+
+    pub fn gen_rules(mut self, gen_rules: Vec<BasicResourceType>) -> Self {
+        self.gen_rules = gen_rules;
+        self
+    }
+
+    pub fn comb_rules(mut self, comb_rules: Vec<ComplexResourceType>) -> Self {
+        self.comb_rules = comb_rules;
+        self
+    }
+
+    /// Replaces the default [`OneMillionCrabs`] AI. The log sink and
+    /// configuration of the builder don't reach a custom AI, build it with
+    /// [`OneMillionCrabs::with_log`] to choose how it logs. The options of the
+    /// default AI (defense, rockets, reservations, invariants, quotas, fairness,
+    /// checkpoint, inventory and script) can't be combined with it.
+    pub fn ai(mut self, ai: Box<dyn PlanetAI>) -> Self {
+        self.ai = Some(ai);
+        self
+    }
+
+    /// Selects how the default [`OneMillionCrabs`] AI answers asteroids,
+    /// [`AlwaysDefend`] is used if not set.
+    pub fn defense_strategy(mut self, defense: Box<dyn DefenseStrategy>) -> Self {
+        self.defense = Some(defense);
+        self
+    }
+
+    /// Selects when the default [`OneMillionCrabs`] AI builds its rockets,
+    /// [`RocketPolicy::OnDemand`] is used if not set.
+    pub fn rocket_policy(mut self, rocket_policy: RocketPolicy) -> Self {
+        self.rocket_policy = Some(rocket_policy);
+        self
+    }
+
+    /// Lets explorers reserve a charged cell for `window`: the cell is reserved
+    /// when the explorer sends an `AvailableEnergyCellRequest` and is consumed
+    /// by its next generate or combine request.
+    pub fn reservation_window(mut self, window: Duration) -> Self {
+        self.reservation_window = Some(window);
+        self
+    }
+
+    /// Selects when the default [`OneMillionCrabs`] AI compares its cell stacks
+    /// with the planet state, see [`InvariantPolicy`].
+    pub fn invariant_policy(mut self, policy: InvariantPolicy) -> Self {
+        self.invariant_policy = Some(policy);
+        self
+    }
+
+    /// Limits the requests and the cells of every explorer, see [`ExplorerQuota`].
+    pub fn explorer_quota(mut self, quota: ExplorerQuota) -> Self {
+        self.explorer_quota = Some(quota);
+        self
+    }
+
+    /// Selects how the charged cells are shared between the landed explorers,
+    /// [`FairnessPolicy::FirstCome`] is used if not set.
+    pub fn fairness(mut self, fairness: FairnessPolicy) -> Self {
+        self.fairness = Some(fairness);
+        self
+    }
+
+    /// Makes the default [`OneMillionCrabs`] AI save a [`PlanetSnapshot`] to `path`
+    /// every time the orchestrator asks for its internal state and when it stops.
+    pub fn checkpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
+    /// Makes the default [`OneMillionCrabs`] AI keep up to `capacity` basic
+    /// resources produced ahead of time, see [`Inventory`]. The [`DemandPlanner`]
+    /// only spends cells with an inventory, and only when a sunray finds every
+    /// cell charged: without one, or with a capacity of 0, the demand is
+    /// learned but never acted on.
+    pub fn inventory(mut self, capacity: usize) -> Self {
+        self.inventory = Some(capacity);
+        self
+    }
+
+    /// Lets the Rhai script in `path` decide how the default [`OneMillionCrabs`]
+    /// AI reacts to sunrays, asteroids and explorer requests, see [`script`].
+    /// The planet is not built if the script can't be read or compiled.
+    #[cfg(feature = "scripting")]
+    pub fn script(mut self, path: impl Into<PathBuf>) -> Self {
+        self.script = Some(path.into());
+        self
+    }
+
+    /// Installs an `env_logger` with the given level when the planet is built.
+    /// If the process already has a logger this option is ignored.
+    pub fn log_level(mut self, level: log::LevelFilter) -> Self {
+        self.log_level = Some(level);
+        self
+    }
+
+    /// Selects where the log events of the planet are written,
+    /// by default they go to the `log` crate.
+    pub fn log_sink(mut self, log_sink: LogSink) -> Self {
+        self.log_sink = log_sink;
+        self
+    }
+
+    /// Selects the channel of every log category of the planet, or disables it.
+    /// Defaults to [`LogConfig::from_env`].
+    pub fn log_config(mut self, log_config: LogConfig) -> Self {
+        self.log_config = log_config;
+        self
+    }
+
+    /// Checks that the configuration will be accepted by `Planet::new`,
+    /// and also rejects duplicated rules that `Planet::new` would silently drop
+    /// and type B or C planets without combination rules.
+    pub fn validate(&self) -> Result<(), String> {
+        let (unbounded_gen_rules, n_comb_rules) = match self.planet_type {
+            PlanetType::A => (false, 0),
+            PlanetType::B => (true, 1),
+            PlanetType::C => (false, 6),
+            PlanetType::D => (true, 0),
+        };
+
+        let ai_options = self.defense.is_some()
+            || self.rocket_policy.is_some()
+            || self.reservation_window.is_some()
+            || self.invariant_policy.is_some()
+            || self.explorer_quota.is_some()
+            || self.fairness.is_some()
+            || self.checkpoint.is_some()
+            || self.snapshot.is_some()
+            || self.inventory.is_some();
+        #[cfg(feature = "scripting")]
+        let ai_options = ai_options || self.script.is_some();
+        if self.ai.is_some() && ai_options {
+            return Err("AI options can only be used with the default AI".to_string());
+        }
+        if self.gen_rules.is_empty() {
+            return Err("gen_rules is empty".to_string());
+        }
+        if !unbounded_gen_rules && self.gen_rules.len() > 1 {
+            return Err(format!(
+                "Too many generation rules (Planet type {:?} is limited to 1)",
+                self.planet_type
+            ));
+        }
+        if n_comb_rules > 0 && self.comb_rules.is_empty() {
+            return Err(format!(
+                "Planet type {:?} is a crafting planet, comb_rules is empty",
+                self.planet_type
+            ));
+        }
+        if self.comb_rules.len() > n_comb_rules {
+            return Err(format!(
+                "Too many combination rules (Planet type {:?} is limited to {})",
+                self.planet_type, n_comb_rules
+            ));
+        }
+        for (i, rule) in self.gen_rules.iter().enumerate() {
+            if self.gen_rules[..i].contains(rule) {
+                return Err(format!(
+                    "Duplicated generation rule: {}",
+                    rule.to_string_2()
+                ));
+            }
+        }
+        for (i, rule) in self.comb_rules.iter().enumerate() {
+            if self.comb_rules[..i].contains(rule) {
+                return Err(format!(
+                    "Duplicated combination rule: {}",
+                    rule.to_string_2()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn build(
+        self,
+        rx_orchestrator: Receiver<OrchestratorToPlanet>,
+        tx_orchestrator: Sender<PlanetToOrchestrator>,
+        rx_explorer: Receiver<ExplorerToPlanet>,
+    ) -> Result<Planet, String> {
+        self.validate()?;
+
+        if let Some(level) = self.log_level {
+            // fails only if a logger is already installed, in that case we keep it
+            let _ = env_logger::Builder::new().filter_level(level).try_init();
+        }
+        let log = Arc::new(PlanetLog::with_sink(self.log_config, &self.log_sink)?);
+
+        let planet_id = self.planet_id;
+        let (planet_type, gen_rules, comb_rules, orchestrator_channels, explorer_channels) = (
+            self.planet_type,
+            self.gen_rules.clone(),
+            self.comb_rules.clone(),
+            (rx_orchestrator, tx_orchestrator),
+            rx_explorer,
+        );
+
+        //LOG
+        let mut payload = Payload::new();
+        payload.insert(String::from("planet_type"), format!("{:?}", planet_type));
+        payload.insert(
+            String::from("gen_rules"),
+            gen_rules.iter().map(|x| x.to_string_2() + ", ").collect(),
+        );
+        payload.insert(
+            String::from("comb_rules"),
+            comb_rules.iter().map(|x| x.to_string_2() + ", ").collect(),
+        );
+        payload.insert("Message".to_string(), "New planet created".to_string());
+        //LOG
+
+        // the AI is plugged in once the planet exists, so that its cells follow the planet state
+        let mut new_planet = Planet::new(
+            planet_id,
+            planet_type,
+            Box::new(Inert),
+            gen_rules,
+            comb_rules,
+            orchestrator_channels,
+            explorer_channels,
+        )?;
+        new_planet.ai = match self.ai {
+            Some(ai) => ai,
+            None => {
+                let mut ai = OneMillionCrabs::with_log(planet_id, log.clone());
+                if let Some(snapshot) = &self.snapshot {
+                    ai.apply_snapshot(snapshot);
+                }
+                ai.resync_cells(new_planet.state());
+                if let Some(defense) = self.defense {
+                    ai.defense = defense;
+                }
+                ai.rocket_policy = self.rocket_policy.unwrap_or_default();
+                ai.reservation_window = self.reservation_window;
+                ai.invariant_policy = self.invariant_policy.unwrap_or_default();
+                ai.quotas = QuotaBook::new(
+                    self.explorer_quota.unwrap_or_default(),
+                    self.fairness.unwrap_or_default(),
+                );
+                ai.inventory = Inventory::new(self.inventory.unwrap_or_default());
+                ai.checkpoint = self.checkpoint.map(|path| Checkpoint {
+                    path,
+                    planet_type: self.planet_type,
+                    gen_rules: self.gen_rules,
+                    comb_rules: self.comb_rules,
+                });
+                #[cfg(feature = "scripting")]
+                let ai: Box<dyn PlanetAI> = match self.script {
+                    Some(path) => Box::new(script::ScriptedAI::new(
+                        ai,
+                        script::PlanetScript::load(path)?,
+                    )),
+                    None => Box::new(ai),
+                };
+                #[cfg(not(feature = "scripting"))]
+                let ai: Box<dyn PlanetAI> = Box::new(ai);
+                ai
+            }
+        };
+
+        //LOG
+        let event = LogEvent::new(
+            Some(Participant::new(ActorType::Planet, planet_id)),
+            Some(Participant::new(ActorType::Planet, planet_id)),
+            EventType::InternalPlanetAction,
+            INTRNL_ACTN_LOG_CHNL.default_channel(),
+            payload,
+        );
+        log_msg!(log; event, INTRNL_ACTN_LOG_CHNL);
+        //LOG
+
+        Ok(new_planet)
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////
 // PlanetAI
 ///////////////////////////////////////////////////////////////////////////////////////////
 
-pub struct OneMillionCrabs;
+pub struct OneMillionCrabs {
+    planet_id: ID,
+    log: Arc<PlanetLog>,
+    cells: CellAllocator,
+    visitors: VisitorRegistry,
+    defense: Box<dyn DefenseStrategy>,
+    rocket_policy: RocketPolicy,
+    reservations: ReservationBook,
+    reservation_window: Option<Duration>,
+    invariant_policy: InvariantPolicy,
+    quotas: QuotaBook,
+    inventory: Inventory,
+    demand: DemandPlanner,
+    metrics: MetricsHandle,
+    checkpoint: Option<Checkpoint>,
+    /// the restored snapshot had a rocket, checked when the AI starts
+    restored_rocket: bool,
+    /// handlers running, more than one when a wrapping AI opens its own
+    open_handlers: u32,
+}
+
+impl Drop for OneMillionCrabs {
+    /// The metrics of a killed planet don't outlive its AI.
+    fn drop(&mut self) {
+        metrics::unregister(self.planet_id, &self.metrics);
+    }
+}
+
+/// Where the AI saves its snapshots, with the configuration of the planet
+/// that only the builder knows.
+struct Checkpoint {
+    path: PathBuf,
+    planet_type: PlanetType,
+    gen_rules: Vec<BasicResourceType>,
+    comb_rules: Vec<ComplexResourceType>,
+}
 
 impl OneMillionCrabs {
-    fn new() -> Self {
+    /// Creates the AI with the default options,
+    /// use [`PlanetBuilder`] to configure it.
+    pub fn new(planet_id: ID) -> Self {
+        Self::with_log(planet_id, Arc::default())
+    }
+
+    /// Creates the AI with the default options, logging with `log`.
+    /// Its cells are sized from the planet state by its first handler,
+    /// [`PlanetBuilder`] does it as soon as the planet is created.
+    pub fn with_log(planet_id: ID, log: Arc<PlanetLog>) -> Self {
         //LOG
         let mut payload = Payload::new();
         payload.insert(String::from("Message"), String::from("New AI created"));
         let event = LogEvent::new(
-            Some(Participant::new(ActorType::Planet, 0u32)),
-            Some(Participant::new(ActorType::Planet, 0u32)),
+            Some(Participant::new(ActorType::Planet, planet_id)),
+            Some(Participant::new(ActorType::Planet, planet_id)),
             EventType::InternalPlanetAction,
-            INTRNL_ACTN_LOG_CHNL,
+            INTRNL_ACTN_LOG_CHNL.default_channel(),
             payload,
         );
-        log_msg!(event, INTRNL_ACTN_LOG_CHNL);
+        log_msg!(log; event, INTRNL_ACTN_LOG_CHNL);
         //LOG
-        initialize_free_cell_stack(0u32);
-        Self
-    }
-}
-
-impl PlanetAI for OneMillionCrabs {
-    fn handle_sunray(
-        &mut self,
-        state: &mut PlanetState,
-        _generator: &Generator,
-        _combinator: &Combinator,
-        sunray: Sunray,
-    ) {
-        let mut payload_ris = Payload::new();
-        if let Some(idx) = get_free_cell_index(state.id()) {
+        let handle = MetricsHandle::default();
+        if let Err(err) = metrics::register(planet_id, &handle) {
             //LOG
-            let mut payload_deb = Payload::new();
-            payload_deb.insert("Action".to_string(), "get_free_cell_index".to_string());
-            payload_deb.insert("Result".to_string(), format!("Some({})", idx));
-            let event_deb = LogEvent::new(
-                Some(Participant::new(ActorType::Planet, state.id())),
-                Some(Participant::new(ActorType::Planet, state.id())),
-                EventType::InternalPlanetAction,
-                DEBUG_LOG_CHNL,
-                payload_deb,
+            create_internal_log_msg!(
+                log;
+                planet_id,
+                WARN_LOG_CHNL,
+                "WARN".to_string(),
+                format!("{}, the metrics of this AI are not shared", err)
             );
-            log_msg!(event_deb, DEBUG_LOG_CHNL);
             //LOG
+        }
+        Self {
+            planet_id,
+            cells: CellAllocator::with_log(planet_id, 0, log.clone()),
+            log,
+            visitors: VisitorRegistry::new(),
+            defense: Box::new(AlwaysDefend),
+            rocket_policy: RocketPolicy::default(),
+            reservations: ReservationBook::new(),
+            reservation_window: None,
+            invariant_policy: InvariantPolicy::default(),
+            quotas: QuotaBook::default(),
+            inventory: Inventory::default(),
+            demand: DemandPlanner::default(),
+            metrics: handle,
+            checkpoint: None,
+            restored_rocket: false,
+            open_handlers: 0,
+        }
+    }
 
-            state.cell_mut(idx as usize).charge(sunray);
-            push_charged_cell(idx, state.id());
-
-            //LOG
-            let mut payload_deb = Payload::new();
-            payload_deb.insert(
-                "Action".to_string(),
-                "cell_mut(index).charge(sunray)".to_string(),
-            );
-            payload_deb.insert("Data".to_string(), format!("index: {}", idx));
-            let event_deb = LogEvent::new(
-                Some(Participant::new(ActorType::Planet, state.id())),
-                Some(Participant::new(ActorType::Planet, state.id())),
-                EventType::InternalPlanetAction,
-                DEBUG_LOG_CHNL,
-                payload_deb,
-            );
-            log_msg!(event_deb, DEBUG_LOG_CHNL);
-            //LOG
+    /// Creates the AI with the state saved in `snapshot`.
+    /// Its cells are rebuilt from the planet state by its first handler.
+    pub fn restore(snapshot: &PlanetSnapshot) -> Self {
+        let mut ai = Self::new(snapshot.planet_id);
+        ai.apply_snapshot(snapshot);
+        ai
+    }
 
-            payload_ris.insert("Message".to_string(), "SunrayAck".to_string());
-            payload_ris.insert(String::from("Result"), String::from("EnergyCell charged"));
-            payload_ris.insert(String::from("EnergyCell index"), format!("{}", idx));
-            payload_ris.insert(
-                String::from("Response data"),
-                format!("planet_id: {}", state.id()),
-            );
-        } else {
-            payload_ris.insert("Response to".to_string(), "Sunray".to_string());
-            payload_ris.insert(String::from("Result"), String::from("No free cell found"));
+    /// Replaces the state of the AI with the one saved in `snapshot`.
+    fn apply_snapshot(&mut self, snapshot: &PlanetSnapshot) {
+        for (explorer_id, visitor) in &snapshot.visitors {
+            self.visitors.restore(*explorer_id, visitor.clone());
         }
+        self.rocket_policy = snapshot.rocket_policy;
+        self.reservation_window = snapshot.reservation_window;
+        self.invariant_policy = snapshot.invariant_policy;
+        self.restored_rocket = snapshot.has_rocket;
+        self.demand.restore(snapshot.demand.clone());
+        self.record(|m| *m = snapshot.metrics.clone());
 
         //LOG
-        let mut payload = Payload::new();
-        payload.insert(String::from("Message"), String::from("Sunray"));
-        let event = LogEvent::new(
-            Some(Participant::new(ActorType::Orchestrator, 0u32)),
-            Some(Participant::new(ActorType::Planet, state.id())),
-            EventType::MessageOrchestratorToPlanet,
-            RCV_MSG_LOG_CHNL,
-            payload,
-        );
-        log_msg!(event, RCV_MSG_LOG_CHNL);
-        let event_ris = LogEvent::new(
-            Some(Participant::new(ActorType::Planet, state.id())),
-            Some(Participant::new(ActorType::Orchestrator, 0u32)),
-            MessagePlanetToOrchestrator,
-            ACK_MSG_LOG_CHNL,
-            payload_ris,
+        create_internal_log_msg!(
+            self.log;
+            snapshot.planet_id,
+            INTRNL_ACTN_LOG_CHNL,
+            "Message".to_string(),
+            "AI restored from snapshot".to_string(),
+            "TakenAt".to_string(),
+            format!("{}", snapshot.taken_at_unix),
+            "Visitors".to_string(),
+            format!("{:?}", self.visitors.present())
         );
-        log_msg!(event_ris, ACK_MSG_LOG_CHNL);
         //LOG
     }
 
-    /// Handler used to determine the strategy in case of an incoming asteroid.
-    /// It will usually try to build a rocket if it can and if it has any
-    /// energy cells available.
-    /// As for our planet, it's a type D, so the planet will ALWAYS die
-    /// when it gets an asteroid. Any other behavior is unexpected and
-    /// should be reported.
-    /// Refer to the common crate documentation for more info on the
-    /// default behavior of this function.
-    fn handle_asteroid(
-        &mut self,
-        state: &mut PlanetState,
-        _generator: &Generator,
-        _combinator: &Combinator,
-    ) -> Option<Rocket> {
-        //if the planet can't build rockets, you're screwed
+    /// Current state of the AI, with the planet configuration of `checkpoint`.
+    fn snapshot(&self, state: &PlanetState, checkpoint: &Checkpoint) -> PlanetSnapshot {
+        PlanetSnapshot {
+            planet_id: state.id(),
+            taken_at_unix: snapshot::now_unix(),
+            planet_type: checkpoint.planet_type,
+            gen_rules: checkpoint.gen_rules.clone(),
+            comb_rules: checkpoint.comb_rules.clone(),
+            has_rocket: state.has_rocket(),
+            rocket_policy: self.rocket_policy,
+            reservation_window: self.reservation_window,
+            invariant_policy: self.invariant_policy,
+            visitors: self
+                .visitors
+                .present()
+                .into_iter()
+                .filter_map(|id| self.visitors.get(id).map(|v| (id, v.clone())))
+                .collect(),
+            metrics: self.metrics(),
+            demand: self.demand.demand().clone(),
+        }
+    }
 
+    /// Saves the snapshot of the AI if it has a checkpoint file.
+    /// A failed write is only logged, the planet keeps running.
+    fn write_checkpoint(&self, state: &PlanetState) {
+        let Some(checkpoint) = &self.checkpoint else {
+            return;
+        };
+        let res = self.snapshot(state, checkpoint).save(&checkpoint.path);
         //LOG
-        let mut payload_deb = Payload::new();
-        payload_deb.insert("Message".to_string(), "handle_orchestrator_msg".to_string());
-        payload_deb.insert(
-            "Data".to_string(),
-            format!("planet state: {:?}", PlanetState::to_dummy(state)),
-        );
-        let event_deb = LogEvent::new(
-            Some(Participant::new(ActorType::Planet, state.id())),
-            Some(Participant::new(ActorType::Planet, state.id())),
-            EventType::InternalPlanetAction,
-            DEBUG_LOG_CHNL,
-            payload_deb,
-        );
-        log_msg!(event_deb, DEBUG_LOG_CHNL);
+        match res {
+            Ok(()) => create_internal_log_msg!(
+                self.log;
+                state.id(),
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                "write_checkpoint".to_string(),
+                "Path".to_string(),
+                checkpoint.path.display().to_string()
+            ),
+            Err(e) => create_internal_log_msg!(
+                self.log;
+                state.id(),
+                ERR_LOG_CHNL,
+                "Action".to_string(),
+                "write_checkpoint".to_string(),
+                "ERR".to_string(),
+                e
+            ),
+        }
+        //LOG
+    }
 
-        let mut payload = Payload::new();
-        let mut payload_ris = Payload::new();
-        payload.insert("Message".to_string(), "Asteroid".to_string());
-        payload_ris.insert("Response to".to_string(), "Asteroid".to_string());
+    /// Replaces the [`InvariantPolicy`], for AIs built without [`PlanetBuilder`].
+    pub fn with_invariant_policy(mut self, policy: InvariantPolicy) -> Self {
+        self.invariant_policy = policy;
+        self
+    }
 
-        let mut payload_deb = Payload::new();
-        payload_deb.insert("Action".to_string(), "can_have_rocket()".to_string());
-        payload_deb.insert(
-            "Response".to_string(),
-            format!("{}", state.can_have_rocket()),
+    /// Replaces the quotas of the explorers, for AIs built without [`PlanetBuilder`].
+    pub fn with_quotas(mut self, quota: ExplorerQuota, fairness: FairnessPolicy) -> Self {
+        self.quotas = QuotaBook::new(quota, fairness);
+        self
+    }
+
+    /// Copy of the current metrics of the planet,
+    /// also available from other threads with [`metrics::snapshot`].
+    pub fn metrics(&self) -> PlanetMetrics {
+        self.metrics
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    fn record(&self, update: impl FnOnce(&mut PlanetMetrics)) {
+        update(
+            &mut self
+                .metrics
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner),
         );
-        create_internal_action_log_msg!(payload_deb, state.id());
+    }
 
-        //LOG
+    /// Counts a request of the explorer in the metrics, served or not.
+    fn record_request(&self, explorer_id: ID) {
+        self.record(|m| *m.explorer_requests.entry(explorer_id).or_default() += 1);
+    }
 
-        let mut ris = None;
-        if !state.can_have_rocket() {
-            ris = None;
-        }
-        //if you've already got a rocket ready, use it!
-        else {
-            //LOG
-            let mut payload_deb = Payload::new();
+    /// Updates the charge level and the inventory in the metrics.
+    fn record_cells(&self, state: &PlanetState) {
+        let inventory = self.inventory.counts();
+        self.record(|m| {
+            m.charged_cells = charged_cells(state);
+            m.total_cells = state.cells_count();
+            m.inventory = inventory;
+        });
+    }
+
+    /// Explorers currently landed on the planet.
+    pub fn visitors(&self) -> &VisitorRegistry {
+        &self.visitors
+    }
+
+    #[cfg(test)]
+    pub(crate) fn cells(&mut self) -> &mut CellAllocator {
+        &mut self.cells
+    }
+
+    /// Number of charged cells that explorers can consume without a reservation,
+    /// the cells reserved by explorers and by the defense strategy are excluded.
+    fn explorer_available_cells(&self, state: &PlanetState) -> usize {
+        let unreserved = charged_cells(state).saturating_sub(self.reservations.len());
+        if state.can_have_rocket() {
+            unreserved.saturating_sub(self.defense.reserved_cells())
+        } else {
+            unreserved
+        }
+    }
+
+    /// Pulls out a charged cell for an explorer request: the cell reserved by
+    /// the explorer if it has one, otherwise a free one, or None if only
+    /// reserved cells are left.
+    fn get_explorer_cell_index(&mut self, state: &PlanetState, explorer_id: ID) -> Option<u32> {
+        if let Some(idx) = self.reservations.take(explorer_id) {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                state.id(),
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                format!("reservations.take({})", explorer_id),
+                "Result".to_string(),
+                format!("Some({})", idx)
+            );
+            //LOG
+            return Some(idx);
+        }
+        if self.explorer_available_cells(state) == 0 {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                state.id(),
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                "get_explorer_cell_index".to_string(),
+                "Result".to_string(),
+                format!(
+                    "None, {} cells reserved by {}",
+                    self.defense.reserved_cells(),
+                    self.defense.name()
+                )
+            );
+            //LOG
+            return None;
+        }
+        self.cells.get_charged_cell_index()
+    }
+}
+
+impl OneMillionCrabs {
+    /// Checks a request of the explorer that consumes a cell against its
+    /// requests per tick, its cells per visit and the fairness policy.
+    /// Informative requests are never checked, they are always answered.
+    fn check_quotas(&mut self, explorer_id: ID) -> Result<(), Throttle> {
+        self.quotas.request(explorer_id)?;
+        let consumed = self.consumed_cells(explorer_id);
+        self.quotas
+            .check_cell(explorer_id, consumed, &self.visitors.present())
+    }
+
+    /// Counts a throttled request of the explorer in the metrics and logs it.
+    fn throttled(&self, planet_id: ID, explorer_id: ID, request: String, throttle: &Throttle) {
+        self.record(|m| *m.throttled_requests.entry(explorer_id).or_default() += 1);
+        //LOG
+        create_internal_log_msg!(
+            self.log;
+            planet_id,
+            WARN_LOG_CHNL,
+            "Event".to_string(),
+            "Throttled".to_string(),
+            "Explorer".to_string(),
+            format!("{}", explorer_id),
+            "Request".to_string(),
+            request,
+            "Reason".to_string(),
+            throttle.to_string()
+        );
+        //LOG
+    }
+
+    /// Cells consumed by the explorer during its current visit.
+    fn consumed_cells(&self, explorer_id: ID) -> u32 {
+        self.visitors
+            .get(explorer_id)
+            .map_or(0, |visitor| visitor.consumed_cells)
+    }
+}
+
+impl OneMillionCrabs {
+    /// Builds a rocket ahead of time if the [`RocketPolicy`] asks for it,
+    /// so that the next asteroid is answered without spending a cell.
+    fn prebuild_rocket(&mut self, state: &mut PlanetState) {
+        let RocketPolicy::Prebuild { explorer_cells } = self.rocket_policy else {
+            return;
+        };
+        let unreserved = charged_cells(state).saturating_sub(self.reservations.len());
+        if !state.can_have_rocket() || state.has_rocket() || unreserved <= explorer_cells {
+            return;
+        }
+        self.build_spare_rocket(state);
+    }
+
+    /// Builds a rocket with a charged cell, if there is one.
+    fn build_spare_rocket(&mut self, state: &mut PlanetState) {
+        if let Some(idx) = self.cells.get_charged_cell_index() {
+            //LOG
+            let mut payload_deb = Payload::new();
+            payload_deb.insert("Action".to_string(), format!("build_rocket({})", idx));
+            //LOG
+            match state.build_rocket(idx as usize) {
+                Ok(_) => {
+                    //LOG
+                    payload_deb.insert("Response".to_string(), "Ok".to_string());
+                    create_internal_action_log_msg!(self.log; payload_deb, state.id());
+                    //LOG
+                    self.cells.push_free_cell(idx);
+                    self.record(|m| m.rockets_built += 1);
+                }
+                Err(err) => {
+                    //LOG
+                    payload_deb.insert("Response".to_string(), "Err".to_string());
+                    create_internal_action_log_msg!(self.log; payload_deb, state.id());
+                    create_internal_log_msg!(
+                        self.log;
+                        state.id(),
+                        ERR_LOG_CHNL,
+                        "ERR".to_string(),
+                        format!("{}", err)
+                    );
+                    //LOG
+                    self.cells.push_charged_cell(idx);
+                }
+            }
+        }
+    }
+}
+
+impl OneMillionCrabs {
+    /// Spends a charged cell as chosen by the [`DemandPlanner`]:
+    /// on a rocket, or on a resource for the inventory.
+    fn spend_spare_cell(&mut self, state: &mut PlanetState, generator: &Generator) {
+        let supported = [Carbon, Hydrogen, Oxygen, Silicon]
+            .into_iter()
+            .filter(|resource| generator.contains(*resource))
+            .collect::<Vec<_>>();
+        let can_build_rocket = state.can_have_rocket() && !state.has_rocket();
+        let plan = self
+            .demand
+            .plan(can_build_rocket, &supported, &self.inventory);
+        //LOG
+        create_internal_log_msg!(
+            self.log;
+            state.id(),
+            DEBUG_LOG_CHNL,
+            "Action".to_string(),
+            "plan".to_string(),
+            "Plan".to_string(),
+            format!("{:?}", plan),
+            "Demand".to_string(),
+            self.demand.demand().to_string()
+        );
+        //LOG
+        match plan {
+            Plan::Rocket => self.build_spare_rocket(state),
+            Plan::Preproduce(resource) => self.preproduce(state, generator, resource),
+            Plan::Idle => {}
+        }
+    }
+
+    /// Spends a spare charged cell to produce `resource` into the inventory.
+    /// The cells kept for rockets or reserved by explorers are not used.
+    fn preproduce(
+        &mut self,
+        state: &mut PlanetState,
+        generator: &Generator,
+        resource: BasicResourceType,
+    ) {
+        if self.explorer_available_cells(state) == 0 {
+            return;
+        }
+        let Some(idx) = self.cells.get_charged_cell_index() else {
+            return;
+        };
+        match make_basic(generator, state.cell_mut(idx as usize), resource) {
+            Ok(produced) => {
+                self.cells.push_free_cell(idx);
+                self.record(|m| *m.generated.entry(resource).or_default() += 1);
+                // can't fail, the planner checked there is room
+                let _ = self.inventory.put(produced);
+                //LOG
+                create_internal_log_msg!(
+                    self.log;
+                    state.id(),
+                    DEBUG_LOG_CHNL,
+                    "Action".to_string(),
+                    format!("preproduce({:?})", resource),
+                    "Inventory".to_string(),
+                    format!("{}/{}", self.inventory.len(), self.inventory.capacity())
+                );
+                //LOG
+            }
+            Err(err) => {
+                //LOG
+                create_internal_log_msg!(
+                    self.log;
+                    state.id(),
+                    ERR_LOG_CHNL,
+                    "Action".to_string(),
+                    format!("preproduce({:?})", resource),
+                    "ERR".to_string(),
+                    err
+                );
+                //LOG
+                self.cells.push_charged_cell(idx);
+            }
+        }
+    }
+}
+
+impl OneMillionCrabs {
+    /// Reserves a charged cell for the explorer, or extends the window of
+    /// the one it already holds. Does nothing if reservations are disabled.
+    fn reserve_cell(&mut self, state: &PlanetState, explorer_id: ID) {
+        let Some(window) = self.reservation_window else {
+            return;
+        };
+        if self.reservations.refresh(explorer_id, window) {
+            return;
+        }
+        if self.explorer_available_cells(state) == 0 {
+            return;
+        }
+        if let Some(idx) = self.cells.get_charged_cell_index() {
+            self.reservations.reserve(explorer_id, idx, window);
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                state.id(),
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                format!("reservations.reserve({}, {})", explorer_id, idx),
+                "Window".to_string(),
+                format!("{:?}", window)
+            );
+            //LOG
+        }
+    }
+
+    /// Gives back the cell reserved by the explorer, if any.
+    fn release_reservation(&mut self, planet_id: ID, explorer_id: ID) {
+        if let Some(idx) = self.reservations.take(explorer_id) {
+            self.cells.push_charged_cell(idx);
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                planet_id,
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                format!("release_reservation({})", explorer_id),
+                "Cell".to_string(),
+                format!("{}", idx)
+            );
+            //LOG
+        }
+    }
+
+    /// Work done before every handler: sizes the cell stacks if needed
+    /// and gives back the expired reservations. Handlers can be nested,
+    /// only the outermost one does the work.
+    fn begin_handler(&mut self, state: &PlanetState) {
+        self.open_handlers += 1;
+        if self.open_handlers > 1 {
+            return;
+        }
+        self.size_cells(state);
+        self.expire_reservations(state.id());
+    }
+
+    /// Sizes the cell stacks to the cells of `state`. An AI created without
+    /// the builder doesn't know them until it starts, or until its first
+    /// handler if it is wrapped by an AI that doesn't forward `on_start`.
+    fn size_cells(&mut self, state: &PlanetState) {
+        if self.cells.cells_count() == state.cells_count() {
+            return;
+        }
+        //LOG
+        create_internal_log_msg!(
+            self.log;
+            state.id(),
+            DEBUG_LOG_CHNL,
+            "Action".to_string(),
+            "size cell stacks".to_string(),
+            "Cells".to_string(),
+            format!("{} -> {}", self.cells.cells_count(), state.cells_count())
+        );
+        //LOG
+        self.resync_cells(state);
+    }
+
+    /// Work done after every handler: updates the cell metrics, checks the cell
+    /// stacks against `state` if the [`InvariantPolicy`] asks for it, and resyncs them on drift.
+    fn end_handler(&mut self, state: &PlanetState) {
+        self.open_handlers = self.open_handlers.saturating_sub(1);
+        if self.open_handlers > 0 {
+            return;
+        }
+        self.record_cells(state);
+        if !self.invariant_policy.after_every_handler {
+            return;
+        }
+        if let Err(drift) = self.check_cells(state) {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                state.id(),
+                ERR_LOG_CHNL,
+                "Event".to_string(),
+                "CellDrift".to_string(),
+                "Lost".to_string(),
+                format!("{:?}", drift.lost),
+                "Duplicated".to_string(),
+                format!("{:?}", drift.duplicated),
+                "ChargedInFreeStack".to_string(),
+                format!("{:?}", drift.charged_in_free_stack),
+                "EmptyInChargedStack".to_string(),
+                format!("{:?}", drift.empty_in_charged_stack),
+                "OutOfRange".to_string(),
+                format!("{:?}", drift.out_of_range),
+                "Resync".to_string(),
+                format!("{}", self.invariant_policy.resync)
+            );
+            //LOG
+            if self.invariant_policy.resync {
+                self.resync_cells(state);
+            }
+        }
+    }
+
+    /// Compares the cell stacks, and the reserved cells, with the cells of `state`.
+    pub fn check_cells(&self, state: &PlanetState) -> Result<(), CellDrift> {
+        let (free, charged) = self.cells.snapshot();
+        let drift = invariants::compare(&free, &charged, &self.reservations.cells(), state);
+        if drift.is_empty() { Ok(()) } else { Err(drift) }
+    }
+
+    /// Rebuilds the cell stacks from the cells of `state`,
+    /// keeping the reserved cells out of them.
+    pub fn resync_cells(&mut self, state: &PlanetState) {
+        self.cells.rebuild(state, &self.reservations.cells());
+    }
+
+    /// Gives back the cells whose reservation window is over.
+    fn expire_reservations(&mut self, planet_id: ID) {
+        for (explorer_id, idx) in self.reservations.expire(Instant::now()) {
+            self.cells.push_charged_cell(idx);
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                planet_id,
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                "expire_reservations".to_string(),
+                "Expired".to_string(),
+                format!("explorer: {}, cell: {}", explorer_id, idx)
+            );
+            //LOG
+        }
+    }
+}
+
+impl OneMillionCrabs {
+    /// Generates one basic resource for the explorer, consuming one charged cell.
+    /// On failure the cell, if any was taken, is given back as charged.
+    fn generate_resource(
+        &mut self,
+        state: &mut PlanetState,
+        generator: &Generator,
+        explorer_id: ID,
+        requested_resource: BasicResourceType,
+    ) -> Result<BasicResource, String> {
+        if let Some(resource) = self.inventory.take(requested_resource) {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                state.id(),
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                format!("inventory.take({:?})", requested_resource),
+                "Left".to_string(),
+                format!("{}", self.inventory.len())
+            );
+            //LOG
+            self.visitors.record_served(explorer_id, requested_resource);
+            self.record(|m| m.served_from_inventory += 1);
+            return Ok(resource);
+        }
+        // controllo se c'è una cella carica
+        let Some(cell_idx) = self.get_explorer_cell_index(state, explorer_id) else {
+            return Err("no charged cell available".to_string());
+        };
+
+        //LOG
+        let mut payload_deb2 = Payload::new();
+        //LOG
+
+        // ottengo la cella da passare al generator
+        let cell = state.cell_mut(cell_idx as usize);
+        payload_deb2.insert(
+            "Action".to_string(),
+            format!("generator.make_{:?}()", requested_resource).to_lowercase(),
+        );
+        let generated_resource = make_basic(generator, cell, requested_resource);
+
+        //LOG
+        payload_deb2.insert("Result".to_string(), format!("{:?}", generated_resource));
+        create_internal_action_log_msg!(self.log; payload_deb2, state.id());
+        //LOG
+
+        // verifico il risultato di state.generator.make...
+        match generated_resource {
+            Ok(resource) => {
+                self.cells.push_free_cell(cell_idx);
+                self.visitors
+                    .record_generated(explorer_id, requested_resource);
+                self.record(|m| *m.generated.entry(requested_resource).or_default() += 1);
+                Ok(resource)
+            }
+            Err(err) => {
+                //LOG
+                create_internal_log_msg!(
+                    self.log;
+                    state.id(),
+                    ERR_LOG_CHNL,
+                    "ERR".to_string(),
+                    format!("{:?}", err)
+                );
+                //LOG
+                self.cells.push_charged_cell(cell_idx);
+                Err(err)
+            }
+        }
+    }
+
+    /// Generates several basic resources for the explorer in one call.
+    /// The requests are served in the given order, one charged cell each,
+    /// until the planet runs out of cells or the quotas of the explorer
+    /// throttle them: the report lists what was produced and why the other
+    /// requests failed.
+    ///
+    /// No explorer message leads here, the explorer protocol has no batch
+    /// request: this is for AIs built on top of `OneMillionCrabs`, which call
+    /// it from their own handlers and deliver the resources themselves.
+    pub fn generate_batch(
+        &mut self,
+        state: &mut PlanetState,
+        generator: &Generator,
+        explorer_id: ID,
+        requests: &[BasicResourceType],
+    ) -> BatchReport {
+        let mut report = BatchReport::default();
+        // every item counts as a request, like a single generate request
+        for _ in requests {
+            self.record_request(explorer_id);
+        }
+
+        // nothing is touched, no need to go through begin_handler and end_handler
+        if !self.visitors.is_present(explorer_id) {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                state.id(),
+                ERR_LOG_CHNL,
+                "ERR".to_string(),
+                format!("rejected batch from explorer {}", explorer_id)
+            );
+            //LOG
+            report.failed = requests
+                .iter()
+                .map(|r| (*r, "explorer is not on the planet".to_string()))
+                .collect();
+            return report;
+        }
+
+        self.begin_handler(state);
+        for requested_resource in requests {
+            // every item goes through the quotas, like a single generate request
+            if let Err(throttle) = self.check_quotas(explorer_id) {
+                let request = format!("batch item {:?}", requested_resource);
+                self.throttled(state.id(), explorer_id, request, &throttle);
+                report
+                    .failed
+                    .push((*requested_resource, throttle.to_string()));
+                continue;
+            }
+            self.demand.request_basic(*requested_resource);
+            let consumed_before = self.consumed_cells(explorer_id);
+            match self.generate_resource(state, generator, explorer_id, *requested_resource) {
+                Ok(resource) => report.produced.push(resource),
+                Err(err) => report.failed.push((*requested_resource, err)),
+            }
+            if self.consumed_cells(explorer_id) > consumed_before {
+                self.quotas.cell_consumed(explorer_id);
+            }
+        }
+
+        //LOG
+        create_internal_log_msg!(
+            self.log;
+            state.id(),
+            INTRNL_ACTN_LOG_CHNL,
+            "Action".to_string(),
+            format!("generate_batch({}, {:?})", explorer_id, requests),
+            "Result".to_string(),
+            format!(
+                "produced: {}, failed: {:?}",
+                report.produced.len(),
+                report.failed
+            )
+        );
+        //LOG
+        self.end_handler(state);
+        report
+    }
+}
+
+/// Outcome of [`OneMillionCrabs::generate_batch`].
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    /// resources produced, in the order they were requested
+    pub produced: Vec<BasicResource>,
+    /// requests that were not served, in order, with the reason
+    pub failed: Vec<(BasicResourceType, String)>,
+}
+
+impl BatchReport {
+    /// True if every requested resource was produced.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Generates `resource` with the charge of `cell`.
+fn make_basic(
+    generator: &Generator,
+    cell: &mut EnergyCell,
+    resource: BasicResourceType,
+) -> Result<BasicResource, String> {
+    // make_ controlla già se la risorsa è presente in generator
+    match resource {
+        BasicResourceType::Carbon => generator.make_carbon(cell).map(BasicResource::Carbon),
+        BasicResourceType::Silicon => generator.make_silicon(cell).map(BasicResource::Silicon),
+        BasicResourceType::Oxygen => generator.make_oxygen(cell).map(BasicResource::Oxygen),
+        BasicResourceType::Hydrogen => generator.make_hydrogen(cell).map(BasicResource::Hydrogen),
+    }
+}
+
+/// Number of charged cells of the planet.
+fn charged_cells(state: &PlanetState) -> usize {
+    state.cells_iter().filter(|cell| cell.is_charged()).count()
+}
+
+impl PlanetAI for OneMillionCrabs {
+    fn handle_sunray(
+        &mut self,
+        state: &mut PlanetState,
+        generator: &Generator,
+        _combinator: &Combinator,
+        sunray: Sunray,
+    ) {
+        self.begin_handler(state);
+        self.record(|m| m.sunrays_received += 1);
+        // every sunray starts a new tick for the explorer quotas and the demand
+        self.quotas.new_tick();
+        self.demand.new_tick();
+        // with every cell charged the sunray would be wasted, the planner spends a cell
+        if self.inventory.capacity() > 0 && state.cells_iter().all(|cell| cell.is_charged()) {
+            self.spend_spare_cell(state, generator);
+        }
+        let mut payload_ris = Payload::new();
+        if let Some(idx) = self.cells.get_free_cell_index() {
+            //LOG
+            let mut payload_deb = Payload::new();
+            payload_deb.insert("Action".to_string(), "get_free_cell_index".to_string());
+            payload_deb.insert("Result".to_string(), format!("Some({})", idx));
+            let event_deb = LogEvent::new(
+                Some(Participant::new(ActorType::Planet, state.id())),
+                Some(Participant::new(ActorType::Planet, state.id())),
+                EventType::InternalPlanetAction,
+                DEBUG_LOG_CHNL.default_channel(),
+                payload_deb,
+            );
+            log_msg!(self.log; event_deb, DEBUG_LOG_CHNL);
+            //LOG
+
+            state.cell_mut(idx as usize).charge(sunray);
+            self.cells.push_charged_cell(idx);
+
+            //LOG
+            let mut payload_deb = Payload::new();
+            payload_deb.insert(
+                "Action".to_string(),
+                "cell_mut(index).charge(sunray)".to_string(),
+            );
+            payload_deb.insert("Data".to_string(), format!("index: {}", idx));
+            let event_deb = LogEvent::new(
+                Some(Participant::new(ActorType::Planet, state.id())),
+                Some(Participant::new(ActorType::Planet, state.id())),
+                EventType::InternalPlanetAction,
+                DEBUG_LOG_CHNL.default_channel(),
+                payload_deb,
+            );
+            log_msg!(self.log; event_deb, DEBUG_LOG_CHNL);
+            //LOG
+
+            payload_ris.insert("Message".to_string(), "SunrayAck".to_string());
+            payload_ris.insert(String::from("Result"), String::from("EnergyCell charged"));
+            payload_ris.insert(String::from("EnergyCell index"), format!("{}", idx));
+            payload_ris.insert(
+                String::from("Response data"),
+                format!("planet_id: {}", state.id()),
+            );
+        } else {
+            payload_ris.insert("Response to".to_string(), "Sunray".to_string());
+            payload_ris.insert(String::from("Result"), String::from("No free cell found"));
+            self.record(|m| m.sunrays_wasted += 1);
+        }
+
+        self.prebuild_rocket(state);
+        payload_ris.insert(
+            String::from("Rocket ready"),
+            format!("{}", state.has_rocket()),
+        );
+
+        //LOG
+        let mut payload = Payload::new();
+        payload.insert(String::from("Message"), String::from("Sunray"));
+        let event = LogEvent::new(
+            Some(Participant::new(ActorType::Orchestrator, 0u32)),
+            Some(Participant::new(ActorType::Planet, state.id())),
+            EventType::MessageOrchestratorToPlanet,
+            RCV_MSG_LOG_CHNL.default_channel(),
+            payload,
+        );
+        log_msg!(self.log; event, RCV_MSG_LOG_CHNL);
+        let event_ris = LogEvent::new(
+            Some(Participant::new(ActorType::Planet, state.id())),
+            Some(Participant::new(ActorType::Orchestrator, 0u32)),
+            MessagePlanetToOrchestrator,
+            ACK_MSG_LOG_CHNL.default_channel(),
+            payload_ris,
+        );
+        log_msg!(self.log; event_ris, ACK_MSG_LOG_CHNL);
+        //LOG
+        self.end_handler(state);
+    }
+
+    /// Handler used to determine the strategy in case of an incoming asteroid.
+    /// The [`DefenseStrategy`] chosen at construction decides whether to defend:
+    /// if so, the rocket already built is used, otherwise one is built with
+    /// a charged cell, cancelling the reservations if they hold every cell.
+    /// Planet types that can't have rockets (B and D) always die,
+    /// and so does any planet whose strategy gives up.
+    /// Refer to the common crate documentation for more info on the
+    /// default behavior of this function.
+    fn handle_asteroid(
+        &mut self,
+        state: &mut PlanetState,
+        _generator: &Generator,
+        _combinator: &Combinator,
+    ) -> Option<Rocket> {
+        self.begin_handler(state);
+        //if the planet can't build rockets, you're screwed
+
+        //LOG
+        let mut payload_deb = Payload::new();
+        payload_deb.insert("Message".to_string(), "handle_orchestrator_msg".to_string());
+        payload_deb.insert(
+            "Data".to_string(),
+            format!("planet state: {:?}", PlanetState::to_dummy(state)),
+        );
+        let event_deb = LogEvent::new(
+            Some(Participant::new(ActorType::Planet, state.id())),
+            Some(Participant::new(ActorType::Planet, state.id())),
+            EventType::InternalPlanetAction,
+            DEBUG_LOG_CHNL.default_channel(),
+            payload_deb,
+        );
+        log_msg!(self.log; event_deb, DEBUG_LOG_CHNL);
+
+        let mut payload = Payload::new();
+        let mut payload_ris = Payload::new();
+        payload.insert("Message".to_string(), "Asteroid".to_string());
+        payload_ris.insert("Response to".to_string(), "Asteroid".to_string());
+
+        let mut payload_deb = Payload::new();
+        payload_deb.insert("Action".to_string(), "can_have_rocket()".to_string());
+        payload_deb.insert(
+            "Response".to_string(),
+            format!("{}", state.can_have_rocket()),
+        );
+        create_internal_action_log_msg!(self.log; payload_deb, state.id());
+
+        let view = DefenseView {
+            charged_cells: charged_cells(state),
+            total_cells: state.cells_count(),
+            has_rocket: state.has_rocket(),
+        };
+        let defend = self.defense.should_defend(&view);
+
+        let mut payload_deb = Payload::new();
+        payload_deb.insert(
+            "Action".to_string(),
+            format!("{}.should_defend({:?})", self.defense.name(), view),
+        );
+        payload_deb.insert("Response".to_string(), format!("{}", defend));
+        create_internal_action_log_msg!(self.log; payload_deb, state.id());
+
+        //LOG
+
+        let mut ris = None;
+        // the strategy can decide to let the asteroid hit the planet
+        if !state.can_have_rocket() || !defend {
+            ris = None;
+        }
+        //if you've already got a rocket ready, use it!
+        else {
+            //LOG
+            let mut payload_deb = Payload::new();
             payload_deb.insert("Action".to_string(), "has_rocket()".to_string());
             payload_deb.insert("Response".to_string(), format!("{}", state.has_rocket()));
-            create_internal_action_log_msg!(payload_deb, state.id());
+            create_internal_action_log_msg!(self.log; payload_deb, state.id());
             //LOG
 
             if state.has_rocket() {
                 //LOG
                 let mut payload_deb = Payload::new();
                 payload_deb.insert("Action".to_string(), "take_rocket()".to_string());
-                create_internal_action_log_msg!(payload_deb, state.id());
+                create_internal_action_log_msg!(self.log; payload_deb, state.id());
                 //LOG
                 ris = state.take_rocket();
             }
             //try to build a rocket if you have any energy left
             else {
+                // surviving comes first: if every charged cell is reserved
+                // by an explorer the reservations are cancelled
+                if !self.reservations.is_empty() && charged_cells(state) == self.reservations.len()
+                {
+                    for (explorer_id, idx) in self.reservations.release_all() {
+                        self.cells.push_charged_cell(idx);
+                        //LOG
+                        create_internal_log_msg!(
+                            self.log;
+                            state.id(),
+                            WARN_LOG_CHNL,
+                            "WARN".to_string(),
+                            format!(
+                                "reservation of cell {} by explorer {} cancelled to build a rocket",
+                                idx, explorer_id
+                            )
+                        );
+                        //LOG
+                    }
+                }
+
                 //LOG
                 let mut payload_deb = Payload::new();
                 payload_deb.insert("Action".to_string(), "get_charged_cell_index()".to_string());
                 //LOG
 
-                if let Some(idx) = get_charged_cell_index(state.id()) {
+                if let Some(idx) = self.cells.get_charged_cell_index() {
                     //LOG
                     payload_deb.insert("Response".to_string(), format!("Some({})", idx));
-                    create_internal_action_log_msg!(payload_deb, state.id());
+                    create_internal_action_log_msg!(self.log; payload_deb, state.id());
 
                     let mut payload_deb2 = Payload::new();
                     payload_deb2.insert("Action".to_string(), format!("build_rocket({})", idx));
@@ -324,10 +1577,11 @@ impl PlanetAI for OneMillionCrabs {
                         Ok(_) => {
                             //LOG
                             payload_deb2.insert("Response".to_string(), "Ok".to_string());
-                            create_internal_action_log_msg!(payload_deb2, state.id());
+                            create_internal_action_log_msg!(self.log; payload_deb2, state.id());
                             //LOG
 
-                            push_free_cell(idx, state.id());
+                            self.cells.push_free_cell(idx);
+                            self.record(|m| m.rockets_built += 1);
                             //println!("Used a charged cell at index {}, to build a rocket", idx);
                             ris = state.take_rocket();
                         }
@@ -335,31 +1589,34 @@ impl PlanetAI for OneMillionCrabs {
                         Err(err) => {
                             //LOG
                             payload_deb2.insert("Response".to_string(), "Err".to_string());
-                            create_internal_action_log_msg!(payload_deb2, state.id());
+                            create_internal_action_log_msg!(self.log; payload_deb2, state.id());
 
                             create_internal_log_msg!(
+                                self.log;
                                 state.id(),
                                 ERR_LOG_CHNL,
                                 "ERR".to_string(),
                                 format!("{}", err)
                             );
                             //LOG
-                            push_charged_cell(idx, state.id());
+                            self.cells.push_charged_cell(idx);
                             ris = None;
                         }
                     }
                 } else {
                     //LOG
                     payload_deb.insert("Response".to_string(), "None".to_string());
-                    create_internal_action_log_msg!(payload_deb, state.id());
+                    create_internal_action_log_msg!(self.log; payload_deb, state.id());
                     //LOG
                 }
             }
         }
         if ris.is_none() {
             payload_ris.insert("Result".to_string(), "no rocket available".to_string());
+            self.record(|m| m.asteroids_failed += 1);
         } else {
             payload_ris.insert("Result".to_string(), "a rocket is available".to_string());
+            self.record(|m| m.asteroids_survived += 1);
         }
 
         //LOG
@@ -368,24 +1625,25 @@ impl PlanetAI for OneMillionCrabs {
             Some(Participant::new(ActorType::Orchestrator, 0u32)),
             Some(Participant::new(ActorType::Planet, state.id())),
             MessageOrchestratorToPlanet,
-            RCV_MSG_LOG_CHNL,
+            RCV_MSG_LOG_CHNL.default_channel(),
             payload,
         );
 
-        log_msg!(event, RCV_MSG_LOG_CHNL);
+        log_msg!(self.log; event, RCV_MSG_LOG_CHNL);
 
         let event_ris = LogEvent::new(
             Some(Participant::new(ActorType::Planet, state.id())),
             Some(Participant::new(ActorType::Orchestrator, 0u32)),
             MessagePlanetToOrchestrator,
-            ACK_MSG_LOG_CHNL,
+            ACK_MSG_LOG_CHNL.default_channel(),
             payload_ris,
         );
 
-        log_msg!(event_ris, ACK_MSG_LOG_CHNL);
+        log_msg!(self.log; event_ris, ACK_MSG_LOG_CHNL);
 
         //LOG
 
+        self.end_handler(state);
         ris
         //shouldn't be able to get here, but just in case...
         //None
@@ -397,6 +1655,24 @@ impl PlanetAI for OneMillionCrabs {
         _generator: &Generator,
         _combinator: &Combinator,
     ) -> DummyPlanetState {
+        self.begin_handler(state);
+        //LOG
+        create_internal_log_msg!(
+            self.log;
+            state.id(),
+            INTRNL_ACTN_LOG_CHNL,
+            "Message".to_string(),
+            "Production".to_string(),
+            "Demand".to_string(),
+            self.demand.demand().to_string(),
+            "Inventory".to_string(),
+            format!("{:?}", self.inventory.counts()),
+            "Capacity".to_string(),
+            format!("{}", self.inventory.capacity())
+        );
+        //LOG
+        self.end_handler(state);
+        self.write_checkpoint(state);
         state.to_dummy()
     }
 
@@ -407,6 +1683,7 @@ impl PlanetAI for OneMillionCrabs {
         combinator: &Combinator,
         msg: ExplorerToPlanet,
     ) -> Option<PlanetToExplorer> {
+        self.begin_handler(state);
         //LOG
         let mut payload_deb = Payload::new();
         payload_deb.insert("Message".to_string(), "handle_orchestrator_msg".to_string());
@@ -422,24 +1699,75 @@ impl PlanetAI for OneMillionCrabs {
             Some(Participant::new(ActorType::Planet, state.id())),
             Some(Participant::new(ActorType::Planet, state.id())),
             EventType::InternalPlanetAction,
-            DEBUG_LOG_CHNL,
+            DEBUG_LOG_CHNL.default_channel(),
             payload_deb,
         );
-        log_msg!(event_deb, DEBUG_LOG_CHNL);
+        log_msg!(self.log; event_deb, DEBUG_LOG_CHNL);
         //LOG
 
-        match msg {
+        // requests from explorers that are not landed are flagged,
+        // the ones that would consume an energy cell are also rejected
+        let sender_id = msg.explorer_id();
+        self.record_request(sender_id);
+        if !self.visitors.is_present(sender_id) {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                state.id(),
+                WARN_LOG_CHNL,
+                "WARN".to_string(),
+                format!(
+                    "{} from explorer {} that is not on the planet",
+                    msg.to_string_2(),
+                    sender_id
+                )
+            );
+            //LOG
+            if consumes_cell(&msg) {
+                //LOG
+                create_internal_log_msg!(
+                    self.log;
+                    state.id(),
+                    ERR_LOG_CHNL,
+                    "ERR".to_string(),
+                    format!("rejected request from explorer {}", sender_id)
+                );
+                //LOG
+                self.end_handler(state);
+                return refused_response(msg, "explorer is not on the planet");
+            }
+        }
+
+        if consumes_cell(&msg)
+            && let Err(throttle) = self.check_quotas(sender_id)
+        {
+            self.throttled(state.id(), sender_id, msg.to_string_2(), &throttle);
+            self.end_handler(state);
+            return throttled_response(msg, throttle);
+        }
+        // only the requests that passed the quotas count as demand
+        match &msg {
+            ExplorerToPlanet::GenerateResourceRequest { resource, .. } => {
+                self.demand.request_basic(*resource)
+            }
+            ExplorerToPlanet::CombineResourceRequest { msg, .. } => {
+                self.demand.request_complex(recipe(msg))
+            }
+            _ => {}
+        }
+        let consumed_before = self.consumed_cells(sender_id);
+
+        let response = match msg {
             ExplorerToPlanet::AvailableEnergyCellRequest { explorer_id: id } => {
                 // restituisce la prima cell carica, se c'è
 
                 let mut payload_ris = Payload::new();
                 //add debug LOGS
-                let mut n_available_cells = 0;
-                for i in 0..N_CELLS {
-                    if state.cell(i).is_charged() {
-                        n_available_cells += 1;
-                    }
-                }
+                self.reserve_cell(state, id);
+                // cells kept for rockets or reserved by other explorers are not offered
+                let own_reservation = self.reservations.get(id).is_some() as usize;
+                let n_available_cells =
+                    (self.explorer_available_cells(state) + own_reservation) as u32;
 
                 payload_ris.insert(
                     "Message".to_string(),
@@ -467,18 +1795,18 @@ impl PlanetAI for OneMillionCrabs {
                     Some(Participant::new(ActorType::Explorer, id)),
                     Some(Participant::new(ActorType::Planet, state.id())),
                     EventType::MessageExplorerToPlanet,
-                    RCV_MSG_LOG_CHNL,
+                    RCV_MSG_LOG_CHNL.default_channel(),
                     payload,
                 );
                 let event_ris = LogEvent::new(
                     Some(Participant::new(ActorType::Planet, state.id())),
                     Some(Participant::new(ActorType::Orchestrator, 0u32)),
                     MessagePlanetToOrchestrator,
-                    ACK_MSG_LOG_CHNL,
+                    ACK_MSG_LOG_CHNL.default_channel(),
                     payload_ris,
                 );
-                log_msg!(event, RCV_MSG_LOG_CHNL);
-                log_msg!(event_ris, ACK_MSG_LOG_CHNL);
+                log_msg!(self.log; event, RCV_MSG_LOG_CHNL);
+                log_msg!(self.log; event_ris, ACK_MSG_LOG_CHNL);
                 //LOG
                 ris
             }
@@ -502,20 +1830,21 @@ impl PlanetAI for OneMillionCrabs {
                     Some(Participant::new(ActorType::Explorer, id)),
                     Some(Participant::new(ActorType::Planet, state.id())),
                     EventType::MessageExplorerToPlanet,
-                    RCV_MSG_LOG_CHNL,
+                    RCV_MSG_LOG_CHNL.default_channel(),
                     payload,
                 );
                 let event_ris = LogEvent::new(
                     Some(Participant::new(ActorType::Planet, state.id())),
                     Some(Participant::new(ActorType::Explorer, id)),
                     EventType::MessagePlanetToExplorer,
-                    ACK_MSG_LOG_CHNL,
+                    ACK_MSG_LOG_CHNL.default_channel(),
                     payload_ris,
                 );
-                log_msg!(event, RCV_MSG_LOG_CHNL);
-                log_msg!(event_ris, ACK_MSG_LOG_CHNL);
+                log_msg!(self.log; event, RCV_MSG_LOG_CHNL);
+                log_msg!(self.log; event_ris, ACK_MSG_LOG_CHNL);
 
                 create_internal_log_msg!(
+                    self.log;
                     state.id(),
                     DEBUG_LOG_CHNL,
                     "Action".to_string(),
@@ -546,20 +1875,21 @@ impl PlanetAI for OneMillionCrabs {
                     Some(Participant::new(ActorType::Explorer, id)),
                     Some(Participant::new(ActorType::Planet, state.id())),
                     EventType::MessageExplorerToPlanet,
-                    RCV_MSG_LOG_CHNL,
+                    RCV_MSG_LOG_CHNL.default_channel(),
                     payload,
                 );
                 let event_ris = LogEvent::new(
                     Some(Participant::new(ActorType::Planet, state.id())),
                     Some(Participant::new(ActorType::Explorer, id)),
                     MessagePlanetToExplorer,
-                    ACK_MSG_LOG_CHNL,
+                    ACK_MSG_LOG_CHNL.default_channel(),
                     payload_ris,
                 );
-                log_msg!(event, RCV_MSG_LOG_CHNL);
-                log_msg!(event_ris, ACK_MSG_LOG_CHNL);
+                log_msg!(self.log; event, RCV_MSG_LOG_CHNL);
+                log_msg!(self.log; event_ris, ACK_MSG_LOG_CHNL);
 
                 create_internal_log_msg!(
+                    self.log;
                     state.id(),
                     DEBUG_LOG_CHNL,
                     "Action".to_string(),
@@ -571,7 +1901,8 @@ impl PlanetAI for OneMillionCrabs {
                 })
             }
 
-            //TODO use explorer_id to send the gen resource to correct Explorer
+            // the response goes back to explorer_id: Planet::run keeps the sender of every
+            // landed explorer, from IncomingExplorerRequest to OutgoingExplorerRequest
             ExplorerToPlanet::GenerateResourceRequest {
                 explorer_id,
                 resource,
@@ -590,92 +1921,30 @@ impl PlanetAI for OneMillionCrabs {
                     Some(Participant::new(ActorType::Explorer, explorer_id)),
                     Some(Participant::new(ActorType::Planet, state.id())),
                     EventType::MessageExplorerToPlanet,
-                    RCV_MSG_LOG_CHNL,
+                    RCV_MSG_LOG_CHNL.default_channel(),
                     payload,
                 );
-                log_msg!(event, RCV_MSG_LOG_CHNL);
+                log_msg!(self.log; event, RCV_MSG_LOG_CHNL);
 
                 //LOG
-                let requested_resource = resource;
-                // controllo se c'è una cella carica
-
-                if let Some(cell_idx) = get_charged_cell_index(state.id()) {
-                    //LOG
-                    let mut payload_deb2 = Payload::new();
+                if let Ok(resource) =
+                    self.generate_resource(state, generator, explorer_id, resource)
+                {
                     //LOG
+                    payload_ris.insert(
+                        "Mesage".to_string(),
+                        "Generated Resource Response".to_string(),
+                    );
 
-                    // se c'è una cella carica
-                    // ottengo la cella da passare al generator
-                    let cell = state.cell_mut(cell_idx as usize);
-                    // pattern matching per generare la risorsa corretta
-                    let generated_resource = match requested_resource {
-                        BasicResourceType::Carbon => {
-                            payload_deb2.insert(
-                                "Action".to_string(),
-                                "generator.make_carbon()".to_string(),
-                            );
-                            generator.make_carbon(cell).map(BasicResource::Carbon)
-                        } // make_ controlla già se la risorsa è presente in generator
-                        BasicResourceType::Silicon => {
-                            payload_deb2.insert(
-                                "Action".to_string(),
-                                "generator.make_silicon()".to_string(),
-                            );
-                            generator.make_silicon(cell).map(BasicResource::Silicon)
-                        }
-                        BasicResourceType::Oxygen => {
-                            payload_deb2.insert(
-                                "Action".to_string(),
-                                "generator.make_oxygen()".to_string(),
-                            );
-                            generator.make_oxygen(cell).map(BasicResource::Oxygen)
-                        }
-                        BasicResourceType::Hydrogen => {
-                            payload_deb2.insert(
-                                "Action".to_string(),
-                                "generator.make_hydrogen()".to_string(),
-                            );
-                            generator.make_hydrogen(cell).map(BasicResource::Hydrogen)
-                        }
-                    };
-
-                    //LOG
-                    payload_deb2.insert("Result".to_string(), format!("{:?}", generated_resource));
-                    create_internal_action_log_msg!(payload_deb2, state.id());
+                    payload_ris.insert(
+                        "Result".to_string(),
+                        format!("produced resource: {:?}", resource),
+                    );
                     //LOG
-
-                    // verifico il risultato di state.generator.make...
-                    match generated_resource {
-                        Ok(resource) => {
-                            //LOG
-                            payload_ris.insert(
-                                "Mesage".to_string(),
-                                "Generated Resource Response".to_string(),
-                            );
-
-                            payload_ris.insert(
-                                "Result".to_string(),
-                                format!("produced resource: {:?}", resource),
-                            );
-                            //LOG
-                            push_free_cell(cell_idx, state.id());
-                            res_type = true;
-                            res = Some(PlanetToExplorer::GenerateResourceResponse {
-                                resource: Some(resource),
-                            });
-                        }
-                        Err(err) => {
-                            //LOG
-                            create_internal_log_msg!(
-                                state.id(),
-                                ERR_LOG_CHNL,
-                                "ERR".to_string(),
-                                format!("{:?}", err)
-                            );
-                            //LOG
-                            push_charged_cell(cell_idx, state.id());
-                        }
-                    }
+                    res_type = true;
+                    res = Some(PlanetToExplorer::GenerateResourceResponse {
+                        resource: Some(resource),
+                    });
                 }
                 //LOG
                 if !res_type {
@@ -694,16 +1963,16 @@ impl PlanetAI for OneMillionCrabs {
                     Some(Participant::new(ActorType::Planet, state.id())),
                     Some(Participant::new(ActorType::Explorer, explorer_id)),
                     MessagePlanetToExplorer,
-                    ACK_MSG_LOG_CHNL,
+                    ACK_MSG_LOG_CHNL.default_channel(),
                     payload_ris,
                 );
 
-                log_msg!(event_ris, ACK_MSG_LOG_CHNL);
+                log_msg!(self.log; event_ris, ACK_MSG_LOG_CHNL);
 
                 //LOG
                 res
             }
-            //TODO use explorer_id to send the gen resource to correct Explorer
+            // routed to explorer_id by Planet::run, like GenerateResourceRequest
             ExplorerToPlanet::CombineResourceRequest {
                 explorer_id,
                 msg: resource,
@@ -728,14 +1997,14 @@ impl PlanetAI for OneMillionCrabs {
                     Some(Participant::new(ActorType::Explorer, explorer_id)),
                     Some(Participant::new(ActorType::Planet, state.id())),
                     EventType::MessageExplorerToPlanet,
-                    RCV_MSG_LOG_CHNL,
+                    RCV_MSG_LOG_CHNL.default_channel(),
                     payload,
                 );
 
-                log_msg!(event, RCV_MSG_LOG_CHNL);
+                log_msg!(self.log; event, RCV_MSG_LOG_CHNL);
                 //LOG
 
-                if let Some(cell_idx) = get_charged_cell_index(state.id()) {
+                if let Some(cell_idx) = self.get_explorer_cell_index(state, explorer_id) {
                     //LOG
                     let mut payload_deb2 = Payload::new();
                     //LOG
@@ -863,7 +2132,7 @@ impl PlanetAI for OneMillionCrabs {
 
                     //LOG
                     payload_deb2.insert("Result".to_string(), format!("{:?}", complex_resource));
-                    create_internal_action_log_msg!(payload_deb2, state.id());
+                    create_internal_action_log_msg!(self.log; payload_deb2, state.id());
                     //LOG
 
                     // checking the result of complex_resource
@@ -882,13 +2151,18 @@ impl PlanetAI for OneMillionCrabs {
                             );
                             //LOG
 
-                            push_free_cell(cell_idx, state.id());
+                            self.cells.push_free_cell(cell_idx);
+                            self.visitors
+                                .record_combined(explorer_id, resource.get_type());
+                            self.record(|m| {
+                                *m.combined.entry(resource.get_type()).or_default() += 1
+                            });
                             res = Some(PlanetToExplorer::CombineResourceResponse {
                                 complex_response: Ok(resource),
                             });
                         }
                         Err(err) => {
-                            push_charged_cell(cell_idx, state.id());
+                            self.cells.push_charged_cell(cell_idx);
                             //LOG
                             payload_ris.insert(
                                 "Message".to_string(),
@@ -903,10 +2177,10 @@ impl PlanetAI for OneMillionCrabs {
                                 Some(Participant::new(ActorType::Planet, state.id())),
                                 Some(Participant::new(ActorType::Planet, state.id())),
                                 EventType::InternalPlanetAction,
-                                ERR_LOG_CHNL,
+                                ERR_LOG_CHNL.default_channel(),
                                 payload_deb2,
                             );
-                            log_msg!(event_deb2, ERR_LOG_CHNL);
+                            log_msg!(self.log; event_deb2, ERR_LOG_CHNL);
 
                             //LOG
 
@@ -918,6 +2192,7 @@ impl PlanetAI for OneMillionCrabs {
                 } else {
                     //LOG
                     create_internal_log_msg!(
+                        self.log;
                         state.id(),
                         ERR_LOG_CHNL,
                         "ERR".to_string(),
@@ -926,32 +2201,7 @@ impl PlanetAI for OneMillionCrabs {
                     );
                     //LOG
 
-                    let (ret1, ret2) = match resource {
-                        ComplexResourceRequest::Water(r1, r2) => (
-                            GenericResource::BasicResources(BasicResource::Hydrogen(r1)),
-                            GenericResource::BasicResources(BasicResource::Oxygen(r2)),
-                        ),
-                        ComplexResourceRequest::AIPartner(r1, r2) => (
-                            GenericResource::ComplexResources(ComplexResource::Robot(r1)),
-                            GenericResource::ComplexResources(ComplexResource::Diamond(r2)),
-                        ),
-                        ComplexResourceRequest::Life(r1, r2) => (
-                            GenericResource::ComplexResources(ComplexResource::Water(r1)),
-                            GenericResource::BasicResources(BasicResource::Carbon(r2)),
-                        ),
-                        ComplexResourceRequest::Diamond(r1, r2) => (
-                            GenericResource::BasicResources(BasicResource::Carbon(r1)),
-                            GenericResource::BasicResources(BasicResource::Carbon(r2)),
-                        ),
-                        ComplexResourceRequest::Dolphin(r1, r2) => (
-                            GenericResource::ComplexResources(ComplexResource::Water(r1)),
-                            GenericResource::ComplexResources(ComplexResource::Life(r2)),
-                        ),
-                        ComplexResourceRequest::Robot(r1, r2) => (
-                            GenericResource::BasicResources(BasicResource::Silicon(r1)),
-                            GenericResource::ComplexResources(ComplexResource::Life(r2)),
-                        ),
-                    };
+                    let (ret1, ret2) = split_complex_request(resource);
 
                     //LOG
 
@@ -976,38 +2226,155 @@ impl PlanetAI for OneMillionCrabs {
                     Some(Participant::new(ActorType::Planet, state.id())),
                     Some(Participant::new(ActorType::Explorer, explorer_id)),
                     EventType::MessageExplorerToPlanet,
-                    ACK_MSG_LOG_CHNL,
+                    ACK_MSG_LOG_CHNL.default_channel(),
                     payload_ris,
                 );
 
-                log_msg!(event_ris, ACK_MSG_LOG_CHNL);
+                log_msg!(self.log; event_ris, ACK_MSG_LOG_CHNL);
 
                 res
             }
+        };
+        if self.consumed_cells(sender_id) > consumed_before {
+            self.quotas.cell_consumed(sender_id);
         }
+        self.end_handler(state);
+        response
     }
 
     fn on_explorer_arrival(
         &mut self,
-        _state: &mut PlanetState,
+        state: &mut PlanetState,
         _generator: &Generator,
         _combinator: &Combinator,
-        _explorer_id: ID,
+        explorer_id: ID,
     ) {
-        todo!()
+        self.begin_handler(state);
+        let landed = self.visitors.arrive(explorer_id);
+
+        //LOG
+        let mut payload = Payload::new();
+        payload.insert("Message".to_string(), "Incoming explorer".to_string());
+        payload.insert("explorer_id".to_string(), format!("{}", explorer_id));
+        let event = LogEvent::new(
+            Some(Participant::new(ActorType::Orchestrator, 0u32)),
+            Some(Participant::new(ActorType::Planet, state.id())),
+            MessageOrchestratorToPlanet,
+            RCV_MSG_LOG_CHNL.default_channel(),
+            payload,
+        );
+        log_msg!(self.log; event, RCV_MSG_LOG_CHNL);
+
+        if landed {
+            create_internal_log_msg!(
+                self.log;
+                state.id(),
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                format!("visitors.arrive({})", explorer_id),
+                "Result".to_string(),
+                format!("visitors: {:?}", self.visitors.present())
+            );
+        } else {
+            create_internal_log_msg!(
+                self.log;
+                state.id(),
+                WARN_LOG_CHNL,
+                "WARN".to_string(),
+                format!("explorer {} was already on the planet", explorer_id)
+            );
+        }
+        //LOG
+        self.end_handler(state);
     }
 
     fn on_explorer_departure(
         &mut self,
-        _state: &mut PlanetState,
+        state: &mut PlanetState,
         _generator: &Generator,
         _combinator: &Combinator,
-        _explorer_id: ID,
+        explorer_id: ID,
     ) {
-        todo!()
+        self.begin_handler(state);
+        let visitor = self.visitors.depart(explorer_id);
+        self.quotas.depart(explorer_id);
+        self.release_reservation(state.id(), explorer_id);
+
+        //LOG
+        let mut payload = Payload::new();
+        payload.insert("Message".to_string(), "Outgoing explorer".to_string());
+        payload.insert("explorer_id".to_string(), format!("{}", explorer_id));
+        let event = LogEvent::new(
+            Some(Participant::new(ActorType::Orchestrator, 0u32)),
+            Some(Participant::new(ActorType::Planet, state.id())),
+            MessageOrchestratorToPlanet,
+            RCV_MSG_LOG_CHNL.default_channel(),
+            payload,
+        );
+        log_msg!(self.log; event, RCV_MSG_LOG_CHNL);
+
+        match visitor {
+            Some(visitor) => {
+                create_internal_log_msg!(
+                    self.log;
+                    state.id(),
+                    DEBUG_LOG_CHNL,
+                    "Action".to_string(),
+                    format!("visitors.depart({})", explorer_id),
+                    "Stay".to_string(),
+                    format!("{:?}", visitor.stay()),
+                    "Consumed cells".to_string(),
+                    format!("{}", visitor.consumed_cells),
+                    "Generated".to_string(),
+                    format!("{:?}", visitor.generated),
+                    "Combined".to_string(),
+                    format!("{:?}", visitor.combined)
+                );
+            }
+            None => {
+                create_internal_log_msg!(
+                    self.log;
+                    state.id(),
+                    WARN_LOG_CHNL,
+                    "WARN".to_string(),
+                    format!("explorer {} was not on the planet", explorer_id)
+                );
+            }
+        }
+        //LOG
+        self.end_handler(state);
     }
 
     fn on_start(&mut self, state: &PlanetState, _generator: &Generator, _combinator: &Combinator) {
+        self.size_cells(state);
+        // the stacks must follow the planet state before the first handler runs
+        if let Err(drift) = self.check_cells(state) {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                state.id(),
+                WARN_LOG_CHNL,
+                "WARN".to_string(),
+                "cell stacks don't match the planet state, rebuilt from it".to_string(),
+                "Drift".to_string(),
+                drift.to_string()
+            );
+            //LOG
+            self.resync_cells(state);
+        }
+        if self.restored_rocket && !state.has_rocket() {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                state.id(),
+                WARN_LOG_CHNL,
+                "WARN".to_string(),
+                "the rocket of the snapshot can't be restored".to_string()
+            );
+            //LOG
+        }
+        self.restored_rocket = false;
+        self.record_cells(state);
         //println!("Planet {} AI started", state.id());
         let mut payload = Payload::new();
         payload.insert("Message".to_string(), "Planet AI start".to_string());
@@ -1015,23 +2382,102 @@ impl PlanetAI for OneMillionCrabs {
             Some(Participant::new(ActorType::Orchestrator, 0u32)),
             Some(Participant::new(ActorType::Planet, state.id())),
             MessageOrchestratorToPlanet,
-            RCV_MSG_LOG_CHNL,
+            RCV_MSG_LOG_CHNL.default_channel(),
             payload,
         );
-        log_msg!(event, RCV_MSG_LOG_CHNL);
+        log_msg!(self.log; event, RCV_MSG_LOG_CHNL);
     }
 
     fn on_stop(&mut self, state: &PlanetState, _generator: &Generator, _combinator: &Combinator) {
+        self.write_checkpoint(state);
         let mut payload = Payload::new();
         payload.insert("Message".to_string(), "Planet AI stop".to_string());
         let event = LogEvent::new(
             Some(Participant::new(ActorType::Orchestrator, 0u32)),
             Some(Participant::new(ActorType::Planet, state.id())),
             MessageOrchestratorToPlanet,
-            RCV_MSG_LOG_CHNL,
+            RCV_MSG_LOG_CHNL.default_channel(),
             payload,
         );
-        log_msg!(event, RCV_MSG_LOG_CHNL);
+        log_msg!(self.log; event, RCV_MSG_LOG_CHNL);
+    }
+}
+
+/// Reply to a request refused by the quotas: no resource, the ingredients
+/// back with the reason, or an empty answer for the informative requests.
+fn throttled_response(msg: ExplorerToPlanet, throttle: Throttle) -> Option<PlanetToExplorer> {
+    refused_response(msg, &throttle.to_string())
+}
+
+/// True for the requests that consume an energy cell, the only ones
+/// that can be refused.
+pub(crate) fn consumes_cell(msg: &ExplorerToPlanet) -> bool {
+    matches!(
+        msg,
+        ExplorerToPlanet::GenerateResourceRequest { .. }
+            | ExplorerToPlanet::CombineResourceRequest { .. }
+    )
+}
+
+/// Reply to a refused request, `reason` goes back with the ingredients.
+/// None for the informative requests, which are never refused.
+fn refused_response(msg: ExplorerToPlanet, reason: &str) -> Option<PlanetToExplorer> {
+    match msg {
+        ExplorerToPlanet::GenerateResourceRequest { .. } => {
+            Some(PlanetToExplorer::GenerateResourceResponse { resource: None })
+        }
+        ExplorerToPlanet::CombineResourceRequest { msg, .. } => {
+            let (ret1, ret2) = split_complex_request(msg);
+            Some(PlanetToExplorer::CombineResourceResponse {
+                complex_response: Err((reason.to_string(), ret1, ret2)),
+            })
+        }
+        ExplorerToPlanet::SupportedResourceRequest { .. }
+        | ExplorerToPlanet::SupportedCombinationRequest { .. }
+        | ExplorerToPlanet::AvailableEnergyCellRequest { .. } => None,
+    }
+}
+
+/// The recipe a combine request asks for.
+pub(crate) fn recipe(request: &ComplexResourceRequest) -> ComplexResourceType {
+    match request {
+        ComplexResourceRequest::Water(..) => ComplexResourceType::Water,
+        ComplexResourceRequest::Diamond(..) => ComplexResourceType::Diamond,
+        ComplexResourceRequest::Life(..) => ComplexResourceType::Life,
+        ComplexResourceRequest::Robot(..) => ComplexResourceType::Robot,
+        ComplexResourceRequest::Dolphin(..) => ComplexResourceType::Dolphin,
+        ComplexResourceRequest::AIPartner(..) => ComplexResourceType::AIPartner,
+    }
+}
+
+/// Gives back the two ingredients of a combination request,
+/// used when the request can't be served.
+fn split_complex_request(request: ComplexResourceRequest) -> (GenericResource, GenericResource) {
+    match request {
+        ComplexResourceRequest::Water(r1, r2) => (
+            GenericResource::BasicResources(BasicResource::Hydrogen(r1)),
+            GenericResource::BasicResources(BasicResource::Oxygen(r2)),
+        ),
+        ComplexResourceRequest::AIPartner(r1, r2) => (
+            GenericResource::ComplexResources(ComplexResource::Robot(r1)),
+            GenericResource::ComplexResources(ComplexResource::Diamond(r2)),
+        ),
+        ComplexResourceRequest::Life(r1, r2) => (
+            GenericResource::ComplexResources(ComplexResource::Water(r1)),
+            GenericResource::BasicResources(BasicResource::Carbon(r2)),
+        ),
+        ComplexResourceRequest::Diamond(r1, r2) => (
+            GenericResource::BasicResources(BasicResource::Carbon(r1)),
+            GenericResource::BasicResources(BasicResource::Carbon(r2)),
+        ),
+        ComplexResourceRequest::Dolphin(r1, r2) => (
+            GenericResource::ComplexResources(ComplexResource::Water(r1)),
+            GenericResource::ComplexResources(ComplexResource::Life(r2)),
+        ),
+        ComplexResourceRequest::Robot(r1, r2) => (
+            GenericResource::BasicResources(BasicResource::Silicon(r1)),
+            GenericResource::ComplexResources(ComplexResource::Life(r2)),
+        ),
     }
 }
 
@@ -1099,393 +2545,375 @@ impl ToString2 for ExplorerToPlanet {
             }
         }
     }
-}
+}
+
+/// Cells of a type D planet, the default one.
+#[deprecated(note = "the cells depend on the planet type, read them from PlanetState::cells_count")]
+pub const N_CELLS: usize = 5;
+
+/// AI of a planet that never runs: the placeholder of [`PlanetBuilder::build`]
+/// until the real AI is ready, and the AI of the crafter planets of the replayer.
+pub(crate) struct Inert;
+
+impl PlanetAI for Inert {
+    fn handle_sunray(
+        &mut self,
+        _state: &mut PlanetState,
+        _generator: &Generator,
+        _combinator: &Combinator,
+        _sunray: Sunray,
+    ) {
+    }
+
+    fn handle_asteroid(
+        &mut self,
+        _state: &mut PlanetState,
+        _generator: &Generator,
+        _combinator: &Combinator,
+    ) -> Option<Rocket> {
+        None
+    }
 
-pub const N_CELLS: usize = 5;
+    fn handle_internal_state_req(
+        &mut self,
+        state: &mut PlanetState,
+        _generator: &Generator,
+        _combinator: &Combinator,
+    ) -> DummyPlanetState {
+        state.to_dummy()
+    }
+
+    fn handle_explorer_msg(
+        &mut self,
+        _state: &mut PlanetState,
+        _generator: &Generator,
+        _combinator: &Combinator,
+        _msg: ExplorerToPlanet,
+    ) -> Option<PlanetToExplorer> {
+        None
+    }
+}
 
 /// Module used to implement an energy cell management system based on a stack.
 /// Provides O(1) lookups, charges and discharges.
-mod stacks {
-    use crate::N_CELLS;
+pub(crate) mod stacks {
+    use crate::log_config::PlanetLog;
     use crate::planet::Participant;
     use crate::planet::{DEBUG_LOG_CHNL, ERR_LOG_CHNL, TRACE_LOG_CHNL, WARN_LOG_CHNL};
+    use common_game::components::planet::PlanetState;
     use common_game::logging::{ActorType, Channel, EventType, LogEvent, Payload};
-    use std::sync::Mutex;
+    use common_game::utils::ID;
+    use std::sync::Arc;
+
+    /// Energy cell allocator owned by a single planet.
+    /// Every planet gets its own free/charged stacks, so several planets
+    /// living in the same process never share cell indexes.
+    pub struct CellAllocator {
+        planet_id: ID,
+        log: Arc<PlanetLog>,
+        /// cells of the planet, neither stack grows beyond it
+        n_cells: usize,
+        free_cell_stack: Vec<u32>,
+        charged_cell_stack: Vec<u32>,
+    }
 
-    pub(crate) static FREE_CELL_STACK: Mutex<Vec<u32>> = Mutex::new(Vec::new());
-    pub(crate) static CHARGED_CELL_STACK: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+    impl CellAllocator {
+        /// Creates the allocator of a planet with `n_cells` cells,
+        /// every one in the free stack, logging with the default configuration.
+        #[cfg(test)]
+        pub fn with_cells(planet_id: ID, n_cells: usize) -> Self {
+            Self::with_log(planet_id, n_cells, Arc::default())
+        }
 
-    /// Initializes the internal vectors used to handle the stack.
-    /// MUST be called everytime the planet is created, for example at
-    /// the start of PlanetAI.
-    pub fn initialize_free_cell_stack(planet_id: u32) {
-        //initialize the free cell stack with all the possible indexes
+        /// Creates the allocator of a planet with `n_cells` cells,
+        /// every one in the free stack, logging with the `log` of the planet.
+        pub fn with_log(planet_id: ID, n_cells: usize, log: Arc<PlanetLog>) -> Self {
+            let mut allocator = Self {
+                planet_id,
+                log,
+                n_cells,
+                free_cell_stack: Vec::with_capacity(n_cells),
+                charged_cell_stack: Vec::with_capacity(n_cells),
+            };
+            allocator.initialize_free_cell_stack();
+            allocator
+        }
 
-        //LOG
-        create_internal_log_msg!(
-            planet_id,
-            DEBUG_LOG_CHNL,
-            "Action".to_string(),
-            "initialize_free_cell_stack".to_string()
-        );
+        /// Number of cells handled by the allocator.
+        pub fn cells_count(&self) -> usize {
+            self.n_cells
+        }
 
-        create_internal_log_msg!(
-            planet_id,
-            TRACE_LOG_CHNL,
-            "Action".to_string(),
-            "FREE_CELL_STACK.lock()".to_string()
-        );
-        //LOG
-        let free_cell_stack = FREE_CELL_STACK.lock();
-        match free_cell_stack {
-            Ok(mut vec) => {
-                //empty previous values in case of reset
-                vec.clear();
-                for i in 0..N_CELLS {
-                    vec.push(i as u32);
-                }
-                //put the indexes in the correct orientation
-                vec.reverse();
-            }
-            Err(err) => {
-                //LOG
-                create_internal_log_msg!(
-                    planet_id,
-                    ERR_LOG_CHNL,
-                    "Action".to_string(),
-                    "FREE_CELL_STACK.lock()".to_string(),
-                    "ERR".to_string(),
-                    format!("{:?}", err)
-                );
-                //LOG
+        /// Initializes the internal vectors used to handle the stack.
+        /// It is already called by [`CellAllocator::with_log`], call it again
+        /// only to reset the allocator.
+        pub fn initialize_free_cell_stack(&mut self) {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                self.planet_id,
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                "initialize_free_cell_stack".to_string()
+            );
+            //LOG
+            //initialize the free cell stack with all the possible indexes,
+            //emptying the previous values in case of reset
+            self.free_cell_stack.clear();
+            for i in 0..self.n_cells {
+                self.free_cell_stack.push(i as u32);
             }
+            //put the indexes in the correct orientation
+            self.free_cell_stack.reverse();
+            //same thing as above but we just make sure that the vector is empty
+            self.charged_cell_stack.clear();
         }
 
-        //same thing as above but we just make sure that the vector is empty
-        //LOG
-        create_internal_log_msg!(
-            planet_id,
-            TRACE_LOG_CHNL,
-            "Action".to_string(),
-            "CHARGED_CELL_STACK.lock()".to_string()
-        );
-        //LOG
-        let charged_cell_stack = CHARGED_CELL_STACK.lock();
-        match charged_cell_stack {
-            Ok(mut vec) => {
-                vec.clear();
+        /// Resyncs both stacks with the cells of `state`, which are the
+        /// authoritative copy: uncharged cells go to the free stack and
+        /// charged cells to the charged one, except the ones in `taken`
+        /// that are held outside the allocator (like reserved cells).
+        /// Also sizes the allocator to the cells of `state`.
+        /// Changing the stacks of a sized allocator is logged as an error:
+        /// they had drifted from the planet state.
+        pub fn rebuild(&mut self, state: &PlanetState, taken: &[u32]) {
+            let sized = self.n_cells > 0;
+            let before = self.sorted_stacks();
+            self.n_cells = state.cells_count();
+            self.free_cell_stack.clear();
+            self.charged_cell_stack.clear();
+            for (i, cell) in state.cells_iter().enumerate() {
+                let idx = i as u32;
+                if !cell.is_charged() {
+                    self.free_cell_stack.push(idx);
+                } else if !taken.contains(&idx) {
+                    self.charged_cell_stack.push(idx);
+                }
             }
-            Err(err) => {
+            //same orientation as initialize_free_cell_stack
+            self.free_cell_stack.reverse();
+
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                self.planet_id,
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                "rebuild".to_string(),
+                "Result".to_string(),
+                format!(
+                    "free: {:?}, charged: {:?}",
+                    self.free_cell_stack, self.charged_cell_stack
+                )
+            );
+            //LOG
+            let after = self.sorted_stacks();
+            if sized && before != after {
                 //LOG
                 create_internal_log_msg!(
-                    planet_id,
+                    self.log;
+                    self.planet_id,
                     ERR_LOG_CHNL,
-                    "Action".to_string(),
-                    "CHARGED_CELL_STACK.lock()".to_string(),
-                    "ERR".to_string(),
-                    format!("{:?}", err)
+                    "Event".to_string(),
+                    "CellStacksRebuilt".to_string(),
+                    "Before".to_string(),
+                    format!("free: {:?}, charged: {:?}", before.0, before.1),
+                    "After".to_string(),
+                    format!("free: {:?}, charged: {:?}", after.0, after.1)
                 );
                 //LOG
             }
         }
-    }
 
-    /// Pulls out a free cell from the corresponding stack.
-    /// returns Some and the correspnding index to charge
-    /// or None if there are no available cells
-    pub fn get_free_cell_index(planet_id: u32) -> Option<u32> {
-        let free_cell_stack = FREE_CELL_STACK.lock();
-        //LOG
-        create_internal_log_msg!(
-            planet_id,
-            TRACE_LOG_CHNL,
-            "Action".to_string(),
-            "FREE_CELL_STACK.lock()".to_string()
-        );
-        //LOG
-        let res = match free_cell_stack {
-            Ok(mut vec) => {
-                //LOG
-                create_internal_log_msg!(
-                    planet_id,
-                    TRACE_LOG_CHNL,
-                    "Action".to_string(),
-                    "free_cell_stack.pop()".to_string()
-                );
-                //LOG
-                vec.pop()
-            }
-            Err(err) => {
-                //LOG
-                create_internal_log_msg!(
-                    planet_id,
-                    ERR_LOG_CHNL,
-                    "Action".to_string(),
-                    "get_free_cell_index".to_string(),
-                    "ERR".to_string(),
-                    format!("{:?}", err)
-                );
-                //LOG
-                None
-            }
-        };
+        /// Free and charged stacks in index order, to compare their content.
+        fn sorted_stacks(&self) -> (Vec<u32>, Vec<u32>) {
+            let (mut free, mut charged) = self.snapshot();
+            free.sort_unstable();
+            charged.sort_unstable();
+            (free, charged)
+        }
 
-        //LOG
-        create_internal_log_msg!(
-            planet_id,
-            DEBUG_LOG_CHNL,
-            "Action".to_string(),
-            "get_free_cell_index".to_string(),
-            "Result".to_string(),
-            format!("{:?}", res)
-        );
-        //LOG
+        /// Copy of the free and charged stacks, bottom first.
+        pub fn snapshot(&self) -> (Vec<u32>, Vec<u32>) {
+            (
+                self.free_cell_stack.clone(),
+                self.charged_cell_stack.clone(),
+            )
+        }
 
-        res
-    }
+        /// Pulls out a free cell from the corresponding stack.
+        /// returns Some and the correspnding index to charge
+        /// or None if there are no available cells
+        pub fn get_free_cell_index(&mut self) -> Option<u32> {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                self.planet_id,
+                TRACE_LOG_CHNL,
+                "Action".to_string(),
+                "free_cell_stack.pop()".to_string()
+            );
+            //LOG
+            let res = self.free_cell_stack.pop();
 
-    /// Pulls out a charged cell from the corresponding stack.
-    /// returns Some and the correspnding index to discharge
-    /// or None if there are no available cells
-    pub fn get_charged_cell_index(planet_id: u32) -> Option<u32> {
-        let charged_cell_stack = CHARGED_CELL_STACK.lock();
-        //LOG
-        create_internal_log_msg!(
-            planet_id,
-            TRACE_LOG_CHNL,
-            "Action".to_string(),
-            "CHARGED_CELL_STACK.lock()".to_string()
-        );
-        //LOG
-        let res;
-        match charged_cell_stack {
-            Ok(mut vec) => {
-                res = vec.pop();
-                //LOG
-                create_internal_log_msg!(
-                    planet_id,
-                    TRACE_LOG_CHNL,
-                    "Action".to_string(),
-                    "charged_cell_stack.pop()".to_string()
-                );
-                //LOG
-            }
-            Err(err) => {
-                //LOG
-                create_internal_log_msg!(
-                    planet_id,
-                    ERR_LOG_CHNL,
-                    "Action".to_string(),
-                    "get_charged_cell_index".to_string(),
-                    "ERR".to_string(),
-                    format!("{:?}", err)
-                );
-                //LOG
-                res = None;
-            }
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                self.planet_id,
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                "get_free_cell_index".to_string(),
+                "Result".to_string(),
+                format!("{:?}", res)
+            );
+            //LOG
+
+            res
         }
 
-        //LOG
-        create_internal_log_msg!(
-            planet_id,
-            DEBUG_LOG_CHNL,
-            "Action".to_string(),
-            "get_charged_cell_index".to_string(),
-            "Result".to_string(),
-            format!("{:?}", res)
-        );
-        //LOG
-        res
-    }
+        /// Pulls out a charged cell from the corresponding stack.
+        /// returns Some and the correspnding index to discharge
+        /// or None if there are no available cells
+        pub fn get_charged_cell_index(&mut self) -> Option<u32> {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                self.planet_id,
+                TRACE_LOG_CHNL,
+                "Action".to_string(),
+                "charged_cell_stack.pop()".to_string()
+            );
+            //LOG
+            let res = self.charged_cell_stack.pop();
 
-    /// Pushes a free energy cell back into the stack.
-    /// The user must verify that there is available space,
-    /// as the function will otherwise give no output without
-    /// increasing the available space.
-    pub fn push_free_cell(index: u32, planet_id: u32) {
-        //LOG
-        create_internal_log_msg!(
-            planet_id,
-            DEBUG_LOG_CHNL,
-            "Action".to_string(),
-            "push_free_cell".to_string(),
-            "index".to_string(),
-            format!("{:?}", index)
-        );
-        //LOG
-        let free_cell_stack = FREE_CELL_STACK.lock();
-        //LOG
-        create_internal_log_msg!(
-            planet_id,
-            TRACE_LOG_CHNL,
-            "Action".to_string(),
-            "FREE_CELL_STACK.lock()".to_string()
-        );
-        //LOG
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                self.planet_id,
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                "get_charged_cell_index".to_string(),
+                "Result".to_string(),
+                format!("{:?}", res)
+            );
+            //LOG
+            res
+        }
 
-        match free_cell_stack {
-            Ok(mut vec) => {
-                if vec.len() < N_CELLS {
-                    vec.push(index);
-                    //LOG
-                    create_internal_log_msg!(
-                        planet_id,
-                        TRACE_LOG_CHNL,
-                        "Action".to_string(),
-                        format!("free_cell_stack.push({})", index)
-                    );
-                    //LOG
-                } else {
-                    //LOG
-                    create_internal_log_msg!(
-                        planet_id,
-                        WARN_LOG_CHNL,
-                        "Action".to_string(),
-                        format!("free_cell_stack.push({})", index),
-                        "WARN".to_string(),
-                        format!("free_cell_stack.len()({})>=N_CELLS({})", vec.len(), N_CELLS)
-                    );
-                    //LOG
-                }
-            }
-            Err(err) => {
+        /// Pushes a free energy cell back into the stack.
+        /// The user must verify that there is available space,
+        /// as the function will otherwise give no output without
+        /// increasing the available space.
+        pub fn push_free_cell(&mut self, index: u32) {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                self.planet_id,
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                "push_free_cell".to_string(),
+                "index".to_string(),
+                format!("{:?}", index)
+            );
+            //LOG
+            if self.free_cell_stack.len() < self.n_cells {
+                self.free_cell_stack.push(index);
                 //LOG
                 create_internal_log_msg!(
-                    planet_id,
-                    ERR_LOG_CHNL,
+                    self.log;
+                    self.planet_id,
+                    TRACE_LOG_CHNL,
                     "Action".to_string(),
-                    "push_free_cell".to_string(),
-                    "ERR".to_string(),
-                    format!("{:?}", err)
+                    format!("free_cell_stack.push({})", index)
                 );
                 //LOG
-            }
-        }
-    }
-
-    /// Pushes a free energy cell back into the stack.
-    /// The user must verify that the maximum size hasn't already
-    /// been reached, as the function will otherwise give
-    /// no output without increasing the available space.
-    pub fn push_charged_cell(index: u32, planet_id: u32) {
-        //LOG
-        create_internal_log_msg!(
-            planet_id,
-            DEBUG_LOG_CHNL,
-            "Action".to_string(),
-            "push_charged_cell".to_string(),
-            "index".to_string(),
-            format!("{:?}", index)
-        );
-        //LOG
-        let charged_cell_stack = CHARGED_CELL_STACK.lock();
-        //LOG
-        create_internal_log_msg!(
-            planet_id,
-            TRACE_LOG_CHNL,
-            "Action".to_string(),
-            "CHARGED_CELL_STACK.lock()".to_string()
-        );
-        //LOG
-        match charged_cell_stack {
-            Ok(mut vec) => {
-                if vec.len() < N_CELLS {
-                    vec.push(index);
-                    //LOG
-                    create_internal_log_msg!(
-                        planet_id,
-                        TRACE_LOG_CHNL,
-                        "Action".to_string(),
-                        format!("charged_cell_stack.push({})", index)
-                    );
-                    //LOG
-                } else {
-                    //LOG
-                    create_internal_log_msg!(
-                        planet_id,
-                        WARN_LOG_CHNL,
-                        "Action".to_string(),
-                        format!("charged_cell_stack.push({})", index),
-                        "WARN".to_string(),
-                        format!(
-                            "charged_cell_stack.len()({})>=N_CELLS({})",
-                            vec.len(),
-                            N_CELLS
-                        )
-                    );
-                    //LOG
-                }
-            }
-            Err(err) => {
+            } else {
                 //LOG
                 create_internal_log_msg!(
-                    planet_id,
-                    ERR_LOG_CHNL,
+                    self.log;
+                    self.planet_id,
+                    WARN_LOG_CHNL,
                     "Action".to_string(),
-                    "push_charged_cell".to_string(),
-                    "ERR".to_string(),
-                    format!("{:?}", err)
+                    format!("free_cell_stack.push({})", index),
+                    "WARN".to_string(),
+                    format!(
+                        "free_cell_stack.len()({})>=n_cells({})",
+                        self.free_cell_stack.len(),
+                        self.n_cells
+                    )
                 );
                 //LOG
             }
         }
-    }
-
-    // TODO: this is a legacy function,
-    // TODO:  we should remove it.
 
-    /// checks wether there is an available charged cell,
-    /// without actually consuming the value.
-    /// Returns Some and the corresponding index or
-    /// None if there are no charged cells.
-    #[allow(dead_code)]
-    pub fn peek_charged_cell_index(planet_id: u32) -> Option<u32> {
-        let charged_cell_stack = CHARGED_CELL_STACK.lock();
-        //LOG
-        create_internal_log_msg!(
-            planet_id,
-            TRACE_LOG_CHNL,
-            "Action".to_string(),
-            "CHARGED_CELL_STACK.lock()".to_string()
-        );
-        //LOG
-        let res;
-        match charged_cell_stack {
-            Ok(vec) => {
-                res = vec.last().copied();
+        /// Pushes a charged energy cell back into the stack.
+        /// The user must verify that the maximum size hasn't already
+        /// been reached, as the function will otherwise give
+        /// no output without increasing the available space.
+        pub fn push_charged_cell(&mut self, index: u32) {
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                self.planet_id,
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                "push_charged_cell".to_string(),
+                "index".to_string(),
+                format!("{:?}", index)
+            );
+            //LOG
+            if self.charged_cell_stack.len() < self.n_cells {
+                self.charged_cell_stack.push(index);
                 //LOG
                 create_internal_log_msg!(
-                    planet_id,
+                    self.log;
+                    self.planet_id,
                     TRACE_LOG_CHNL,
                     "Action".to_string(),
-                    "charged_cell_stack.last().copied()".to_string()
+                    format!("charged_cell_stack.push({})", index)
                 );
                 //LOG
-            }
-            Err(err) => {
+            } else {
                 //LOG
                 create_internal_log_msg!(
-                    planet_id,
-                    ERR_LOG_CHNL,
+                    self.log;
+                    self.planet_id,
+                    WARN_LOG_CHNL,
                     "Action".to_string(),
-                    "peek_charged_cell_index".to_string(),
-                    "ERR".to_string(),
-                    format!("{:?}", err)
+                    format!("charged_cell_stack.push({})", index),
+                    "WARN".to_string(),
+                    format!(
+                        "charged_cell_stack.len()({})>=n_cells({})",
+                        self.charged_cell_stack.len(),
+                        self.n_cells
+                    )
                 );
                 //LOG
-                res = None;
             }
         }
-        //LOG
-        create_internal_log_msg!(
-            planet_id,
-            DEBUG_LOG_CHNL,
-            "Action".to_string(),
-            "peek_charged_cell_index".to_string(),
-            "Result".to_string(),
-            format!("{:?}", res)
-        );
-        //LOG
-        res
+
+        // TODO: this is a legacy function,
+        // TODO:  we should remove it.
+
+        /// checks wether there is an available charged cell,
+        /// without actually consuming the value.
+        /// Returns Some and the corresponding index or
+        /// None if there are no charged cells.
+        #[allow(dead_code)]
+        pub fn peek_charged_cell_index(&self) -> Option<u32> {
+            let res = self.charged_cell_stack.last().copied();
+            //LOG
+            create_internal_log_msg!(
+                self.log;
+                self.planet_id,
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                "peek_charged_cell_index".to_string(),
+                "Result".to_string(),
+                format!("{:?}", res)
+            );
+            //LOG
+            res
+        }
     }
 }
@@ -0,0 +1,97 @@
+//! Strategies used by the AI to decide how to answer an incoming asteroid.
+
+/// Snapshot of the planet given to a [`DefenseStrategy`].
+#[derive(Debug, Clone, Copy)]
+pub struct DefenseView {
+    pub charged_cells: usize,
+    pub total_cells: usize,
+    pub has_rocket: bool,
+}
+
+/// Policy followed by `handle_asteroid`.
+/// The AI only asks the strategy whether to defend, building and
+/// launching the rocket is still done by the AI.
+pub trait DefenseStrategy: Send {
+    /// Returns true if the planet should answer the asteroid with a rocket.
+    fn should_defend(&self, view: &DefenseView) -> bool;
+
+    /// Number of charged cells that explorers are not allowed to consume,
+    /// so that they stay available for rockets.
+    fn reserved_cells(&self) -> usize {
+        0
+    }
+
+    /// Name used in the logs.
+    fn name(&self) -> String;
+}
+
+/// Uses the held rocket or builds one whenever possible.
+/// This is the default strategy.
+pub struct AlwaysDefend;
+
+impl DefenseStrategy for AlwaysDefend {
+    fn should_defend(&self, _view: &DefenseView) -> bool {
+        true
+    }
+
+    fn name(&self) -> String {
+        "AlwaysDefend".to_string()
+    }
+}
+
+/// Always defends, and keeps the given number of charged cells away
+/// from the explorers.
+pub struct ReserveCells(pub usize);
+
+impl DefenseStrategy for ReserveCells {
+    fn should_defend(&self, _view: &DefenseView) -> bool {
+        true
+    }
+
+    fn reserved_cells(&self) -> usize {
+        self.0
+    }
+
+    fn name(&self) -> String {
+        format!("ReserveCells({})", self.0)
+    }
+}
+
+/// Defends only if at least the given number of cells are charged.
+/// A rocket that is already built is always used.
+pub struct ChargeThreshold(pub usize);
+
+impl DefenseStrategy for ChargeThreshold {
+    fn should_defend(&self, view: &DefenseView) -> bool {
+        view.has_rocket || view.charged_cells >= self.0
+    }
+
+    fn name(&self) -> String {
+        format!("ChargeThreshold({})", self.0)
+    }
+}
+
+/// Never answers an asteroid, not even with a rocket that is already built.
+pub struct NeverDefend;
+
+impl DefenseStrategy for NeverDefend {
+    fn should_defend(&self, _view: &DefenseView) -> bool {
+        false
+    }
+
+    fn name(&self) -> String {
+        "NeverDefend".to_string()
+    }
+}
+
+/// When the AI builds its rockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RocketPolicy {
+    /// A rocket is built only when an asteroid arrives.
+    #[default]
+    OnDemand,
+    /// After every sunray a rocket is built and stored if none is held,
+    /// as long as more than `explorer_cells` charged cells are available:
+    /// that many cells are always left for the explorer requests.
+    Prebuild { explorer_cells: usize },
+}
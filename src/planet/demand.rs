@@ -0,0 +1,206 @@
+//! Moving average of what the explorers ask for.
+//!
+//! Every generate and combine request that passes the quotas of its explorer
+//! counts as demand for its resource in the current tick, and at every sunray
+//! the counts of the tick are folded into an exponential moving average.
+//! When a sunray finds every cell charged, and the AI has an inventory, the
+//! planner uses the learned demand to choose what the spare cell is spent
+//! on: a rocket, or the basic resource the inventory covers the least.
+
+use crate::planet::inventory::Inventory;
+use common_game::components::resource::{BasicResourceType, ComplexResourceType};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+/// Weight of the last tick in the moving average.
+pub const DEFAULT_SMOOTHING: f64 = 0.2;
+
+/// Learned demand, in requests per tick.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Demand {
+    pub basic: HashMap<BasicResourceType, f64>,
+    pub complex: HashMap<ComplexResourceType, f64>,
+}
+
+impl Demand {
+    /// Cells the explorers consume in a tick, one per request.
+    pub fn cells_per_tick(&self) -> f64 {
+        self.basic.values().sum::<f64>() + self.complex.values().sum::<f64>()
+    }
+
+    /// Demand of a basic resource, asked directly or as the ingredient of a recipe.
+    pub fn need(&self, resource: BasicResourceType) -> f64 {
+        let direct = self.basic.get(&resource).copied().unwrap_or(0.0);
+        let as_ingredient = self
+            .complex
+            .iter()
+            .map(|(recipe, demand)| {
+                let n = ingredients(*recipe)
+                    .iter()
+                    .filter(|r| **r == resource)
+                    .count();
+                n as f64 * demand
+            })
+            .sum::<f64>();
+        direct + as_ingredient
+    }
+}
+
+/// Basic ingredients of a recipe, the complex ones are left out.
+fn ingredients(recipe: ComplexResourceType) -> &'static [BasicResourceType] {
+    match recipe {
+        ComplexResourceType::Water => &[BasicResourceType::Hydrogen, BasicResourceType::Oxygen],
+        ComplexResourceType::Diamond => &[BasicResourceType::Carbon, BasicResourceType::Carbon],
+        ComplexResourceType::Life => &[BasicResourceType::Carbon],
+        ComplexResourceType::Robot => &[BasicResourceType::Silicon],
+        ComplexResourceType::Dolphin | ComplexResourceType::AIPartner => &[],
+    }
+}
+
+impl fmt::Display for Demand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn entries<K: fmt::Debug>(map: &HashMap<K, f64>) -> Vec<String> {
+            let mut entries = map
+                .iter()
+                .filter(|(_, demand)| **demand >= 0.005)
+                .map(|(k, demand)| format!("{:?} {:.2}", k, demand))
+                .collect::<Vec<_>>();
+            entries.sort();
+            entries
+        }
+        let mut all = entries(&self.basic);
+        all.extend(entries(&self.complex));
+        if all.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", all.join(", "))
+        }
+    }
+}
+
+/// What the planet does with a cell that a sunray would otherwise waste.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Plan {
+    Rocket,
+    Preproduce(BasicResourceType),
+    /// nothing to do, the sunray is wasted
+    Idle,
+}
+
+/// Learns the demand of the explorers and plans the production.
+#[derive(Debug)]
+pub struct DemandPlanner {
+    smoothing: f64,
+    average: Demand,
+    /// requests of the current tick
+    tick_basic: HashMap<BasicResourceType, u32>,
+    tick_complex: HashMap<ComplexResourceType, u32>,
+}
+
+impl Default for DemandPlanner {
+    fn default() -> Self {
+        Self::new(DEFAULT_SMOOTHING)
+    }
+}
+
+impl DemandPlanner {
+    /// `smoothing` is the weight of the last tick, clamped between 0 and 1.
+    pub fn new(smoothing: f64) -> Self {
+        Self {
+            smoothing: smoothing.clamp(0.0, 1.0),
+            average: Demand::default(),
+            tick_basic: HashMap::new(),
+            tick_complex: HashMap::new(),
+        }
+    }
+
+    /// Replaces the learned demand, used to restore a snapshot.
+    pub fn restore(&mut self, demand: Demand) {
+        self.average = demand;
+    }
+
+    pub fn demand(&self) -> &Demand {
+        &self.average
+    }
+
+    pub fn request_basic(&mut self, resource: BasicResourceType) {
+        *self.tick_basic.entry(resource).or_default() += 1;
+    }
+
+    pub fn request_complex(&mut self, resource: ComplexResourceType) {
+        *self.tick_complex.entry(resource).or_default() += 1;
+    }
+
+    /// Folds the requests of the tick into the moving average and starts a new tick.
+    pub fn new_tick(&mut self) {
+        fold(
+            &mut self.average.basic,
+            &mut self.tick_basic,
+            self.smoothing,
+        );
+        fold(
+            &mut self.average.complex,
+            &mut self.tick_complex,
+            self.smoothing,
+        );
+    }
+
+    /// Chooses what to spend a spare cell on. A tick brings one sunray, so
+    /// with a demand below one cell per tick the explorers leave energy
+    /// unused and a rocket comes first; otherwise the cell goes to the
+    /// inventory, and to a rocket only if the inventory has no room.
+    pub fn plan(
+        &self,
+        can_build_rocket: bool,
+        supported: &[BasicResourceType],
+        inventory: &Inventory,
+    ) -> Plan {
+        let busy = self.average.cells_per_tick() >= 1.0;
+        let preproduce = self.most_needed(supported, inventory);
+        match preproduce {
+            Some(resource) if busy || !can_build_rocket => Plan::Preproduce(resource),
+            _ if can_build_rocket => Plan::Rocket,
+            _ => Plan::Idle,
+        }
+    }
+
+    /// The resource of `supported` whose demand is the least covered by the
+    /// inventory, the least stocked one if nothing was asked yet.
+    /// None if the inventory is full.
+    fn most_needed(
+        &self,
+        supported: &[BasicResourceType],
+        inventory: &Inventory,
+    ) -> Option<BasicResourceType> {
+        if inventory.is_full() {
+            return None;
+        }
+        let mut best: Option<(BasicResourceType, f64)> = None;
+        for resource in supported {
+            let score = self.average.need(*resource) / (inventory.count(*resource) + 1) as f64;
+            if score > 0.0 && best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((*resource, score));
+            }
+        }
+        best.map(|(resource, _)| resource)
+            .or_else(|| inventory.next_to_produce(supported))
+    }
+}
+
+/// `average = smoothing * last tick + (1 - smoothing) * average` for every
+/// resource ever requested, then clears the counts of the tick.
+fn fold<K: Eq + Hash + Copy>(
+    average: &mut HashMap<K, f64>,
+    tick: &mut HashMap<K, u32>,
+    smoothing: f64,
+) {
+    for resource in tick.keys() {
+        average.entry(*resource).or_insert(0.0);
+    }
+    for (resource, demand) in average.iter_mut() {
+        let n = tick.get(resource).copied().unwrap_or(0) as f64;
+        *demand = smoothing * n + (1.0 - smoothing) * *demand;
+    }
+    tick.clear();
+}
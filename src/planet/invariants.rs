@@ -0,0 +1,98 @@
+//! Consistency checks between the cell stacks of the AI and the cells of the planet.
+//!
+//! The stacks are only a shadow of `PlanetState`: every cell must be in
+//! exactly one place (free stack, charged stack or held outside the
+//! allocator, like a reservation) and its place must match its charge.
+
+use common_game::components::planet::PlanetState;
+use std::fmt;
+
+/// When the AI checks its cell stacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvariantPolicy {
+    /// check after every handler, by default only in debug builds
+    pub after_every_handler: bool,
+    /// rebuild the stacks from the planet state when they drifted
+    pub resync: bool,
+}
+
+impl Default for InvariantPolicy {
+    fn default() -> Self {
+        Self {
+            after_every_handler: cfg!(debug_assertions),
+            resync: false,
+        }
+    }
+}
+
+/// Differences between the cell stacks and the planet state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CellDrift {
+    /// cells that are in no stack and not held outside the allocator
+    pub lost: Vec<u32>,
+    /// cells found more than once
+    pub duplicated: Vec<u32>,
+    /// charged cells sitting in the free stack
+    pub charged_in_free_stack: Vec<u32>,
+    /// empty cells in the charged stack or held outside the allocator
+    pub empty_in_charged_stack: Vec<u32>,
+    /// indexes the planet doesn't have
+    pub out_of_range: Vec<u32>,
+}
+
+impl CellDrift {
+    pub fn is_empty(&self) -> bool {
+        self.lost.is_empty()
+            && self.duplicated.is_empty()
+            && self.charged_in_free_stack.is_empty()
+            && self.empty_in_charged_stack.is_empty()
+            && self.out_of_range.is_empty()
+    }
+}
+
+impl fmt::Display for CellDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "lost: {:?}, duplicated: {:?}, charged in free stack: {:?}, empty in charged stack: {:?}, out of range: {:?}",
+            self.lost,
+            self.duplicated,
+            self.charged_in_free_stack,
+            self.empty_in_charged_stack,
+            self.out_of_range
+        )
+    }
+}
+
+/// Compares the content of the stacks, and the cells held outside of them,
+/// with the cells of `state`.
+pub fn compare(free: &[u32], charged: &[u32], held: &[u32], state: &PlanetState) -> CellDrift {
+    let n_cells = state.cells_count();
+    let mut seen = vec![0usize; n_cells];
+    let mut drift = CellDrift::default();
+
+    let free_cells = free.iter().map(|idx| (*idx, false));
+    let charged_cells = charged.iter().chain(held).map(|idx| (*idx, true));
+    for (idx, should_be_charged) in free_cells.chain(charged_cells) {
+        let i = idx as usize;
+        if i >= n_cells {
+            drift.out_of_range.push(idx);
+            continue;
+        }
+        seen[i] += 1;
+        let is_charged = state.cell(i).is_charged();
+        if is_charged && !should_be_charged {
+            drift.charged_in_free_stack.push(idx);
+        } else if !is_charged && should_be_charged {
+            drift.empty_in_charged_stack.push(idx);
+        }
+    }
+    for (i, count) in seen.into_iter().enumerate() {
+        match count {
+            0 => drift.lost.push(i as u32),
+            1 => {}
+            _ => drift.duplicated.push(i as u32),
+        }
+    }
+    drift
+}
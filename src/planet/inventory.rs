@@ -0,0 +1,86 @@
+//! Basic resources produced ahead of time.
+//!
+//! When every cell is charged the next sunray would be wasted, so the AI
+//! can spend a charged cell to produce a resource into the inventory instead,
+//! see [`DemandPlanner`](crate::planet::demand::DemandPlanner).
+//! Generate requests are served from the inventory before spending a cell.
+
+use common_game::components::resource::{BasicResource, BasicResourceType};
+use std::collections::HashMap;
+
+/// Resources kept by the planet, at most `capacity` of them.
+/// A capacity of 0, the default, disables the inventory.
+#[derive(Debug, Default)]
+pub struct Inventory {
+    capacity: usize,
+    stock: HashMap<BasicResourceType, Vec<BasicResource>>,
+}
+
+impl Inventory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            stock: HashMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of resources in the inventory.
+    pub fn len(&self) -> usize {
+        self.stock.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
+    }
+
+    /// Number of resources of type `resource`.
+    pub fn count(&self, resource: BasicResourceType) -> usize {
+        self.stock.get(&resource).map_or(0, Vec::len)
+    }
+
+    /// Stores `resource`, giving it back if the inventory is full.
+    pub fn put(&mut self, resource: BasicResource) -> Result<(), BasicResource> {
+        if self.is_full() {
+            return Err(resource);
+        }
+        self.stock
+            .entry(resource.get_type())
+            .or_default()
+            .push(resource);
+        Ok(())
+    }
+
+    /// Takes a resource of type `resource`, if there is one.
+    pub fn take(&mut self, resource: BasicResourceType) -> Option<BasicResource> {
+        self.stock.get_mut(&resource)?.pop()
+    }
+
+    /// Number of resources of each type, the types never stored are missing.
+    pub fn counts(&self) -> HashMap<BasicResourceType, u64> {
+        self.stock
+            .iter()
+            .filter(|(_, resources)| !resources.is_empty())
+            .map(|(resource, resources)| (*resource, resources.len() as u64))
+            .collect()
+    }
+
+    /// The type of `supported` with the fewest resources in stock,
+    /// ties go to the first in `supported`. None if the inventory is full.
+    pub fn next_to_produce(&self, supported: &[BasicResourceType]) -> Option<BasicResourceType> {
+        if self.is_full() {
+            return None;
+        }
+        supported
+            .iter()
+            .min_by_key(|resource| self.count(**resource))
+            .copied()
+    }
+}
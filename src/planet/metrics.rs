@@ -0,0 +1,168 @@
+//! Counters of the planet activity.
+//!
+//! Every [`OneMillionCrabs`](crate::planet::OneMillionCrabs) AI updates its
+//! own [`PlanetMetrics`], which is also registered by planet id so that it
+//! can be read from outside the planet thread with [`snapshot`].
+//! A planet id belongs to one AI at a time: an AI created while another one
+//! with the same id is alive keeps its metrics to itself.
+
+use common_game::components::resource::{BasicResourceType, ComplexResourceType};
+use common_game::utils::ID;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlanetMetrics {
+    /// charged cells after the last handler
+    pub charged_cells: usize,
+    pub total_cells: usize,
+    pub sunrays_received: u64,
+    /// sunrays that found no free cell to charge
+    pub sunrays_wasted: u64,
+    pub asteroids_survived: u64,
+    pub asteroids_failed: u64,
+    pub rockets_built: u64,
+    pub generated: HashMap<BasicResourceType, u64>,
+    pub combined: HashMap<ComplexResourceType, u64>,
+    /// resources in the inventory after the last handler
+    pub inventory: HashMap<BasicResourceType, u64>,
+    /// generate requests served from the inventory, without spending a cell
+    pub served_from_inventory: u64,
+    /// requests received from each explorer, served or not
+    pub explorer_requests: HashMap<ID, u64>,
+    /// requests refused by the explorer quotas or the fairness policy
+    pub throttled_requests: HashMap<ID, u64>,
+}
+
+impl PlanetMetrics {
+    pub fn total_generated(&self) -> u64 {
+        self.generated.values().sum()
+    }
+
+    pub fn total_combined(&self) -> u64 {
+        self.combined.values().sum()
+    }
+}
+
+/// Entries of a map sorted by their `Debug` name, the resource types have no ordering.
+fn sorted_by_name<K: fmt::Debug>(map: &HashMap<K, u64>) -> Vec<(String, u64)> {
+    let mut entries = map
+        .iter()
+        .map(|(k, v)| (format!("{:?}", k), *v))
+        .collect::<Vec<_>>();
+    entries.sort();
+    entries
+}
+
+fn join(entries: Vec<(String, u64)>) -> String {
+    if entries.is_empty() {
+        return "none".to_string();
+    }
+    entries
+        .into_iter()
+        .map(|(k, v)| format!("{} {}", k, v))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl fmt::Display for PlanetMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let by_explorer = |map: &HashMap<ID, u64>| {
+            let mut explorers = map.iter().collect::<Vec<_>>();
+            explorers.sort();
+            explorers
+                .into_iter()
+                .map(|(id, n)| (format!("#{}", id), *n))
+                .collect()
+        };
+        writeln!(
+            f,
+            "cells: {}/{} charged",
+            self.charged_cells, self.total_cells
+        )?;
+        writeln!(
+            f,
+            "sunrays: {} received, {} wasted",
+            self.sunrays_received, self.sunrays_wasted
+        )?;
+        writeln!(
+            f,
+            "asteroids: {} survived, {} failed",
+            self.asteroids_survived, self.asteroids_failed
+        )?;
+        writeln!(f, "rockets built: {}", self.rockets_built)?;
+        writeln!(f, "generated: {}", join(sorted_by_name(&self.generated)))?;
+        writeln!(f, "combined: {}", join(sorted_by_name(&self.combined)))?;
+        writeln!(
+            f,
+            "inventory: {}, {} served",
+            join(sorted_by_name(&self.inventory)),
+            self.served_from_inventory
+        )?;
+        writeln!(
+            f,
+            "explorer requests: {}",
+            join(by_explorer(&self.explorer_requests))
+        )?;
+        write!(
+            f,
+            "throttled requests: {}",
+            join(by_explorer(&self.throttled_requests))
+        )
+    }
+}
+
+/// Metrics shared between the AI and the readers on other threads.
+pub type MetricsHandle = Arc<Mutex<PlanetMetrics>>;
+
+/// Metrics of the planets, keyed by planet id.
+static REGISTRY: LazyLock<Mutex<HashMap<ID, MetricsHandle>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Registers `handle` as the metrics of the planet `planet_id`.
+/// Fails if the id already has metrics, which are left untouched.
+pub fn register(planet_id: ID, handle: &MetricsHandle) -> Result<(), String> {
+    let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    if registry.contains_key(&planet_id) {
+        return Err(format!("planet {} already has metrics", planet_id));
+    }
+    registry.insert(planet_id, handle.clone());
+    Ok(())
+}
+
+/// Removes the metrics of the planet `planet_id` if they are `handle`,
+/// the metrics registered by another planet with the same id are kept.
+pub fn unregister(planet_id: ID, handle: &MetricsHandle) {
+    let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    if registry
+        .get(&planet_id)
+        .is_some_and(|current| Arc::ptr_eq(current, handle))
+    {
+        registry.remove(&planet_id);
+    }
+}
+
+/// Copy of the current metrics of the planet,
+/// None if it was never created or its AI was dropped.
+pub fn snapshot(planet_id: ID) -> Option<PlanetMetrics> {
+    let registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    registry.get(&planet_id).map(|handle| {
+        handle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    })
+}
+
+/// Ids of every planet with metrics, sorted.
+pub fn planets() -> Vec<ID> {
+    let mut ids = REGISTRY
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    ids.sort();
+    ids
+}
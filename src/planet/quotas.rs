@@ -0,0 +1,168 @@
+//! Limits on what a single explorer can ask to the planet.
+//!
+//! A tick is the time between two sunrays: the per-tick counters and the
+//! fairness rounds start over with every sunray.
+
+use common_game::utils::ID;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Limits applied to every explorer, None means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExplorerQuota {
+    /// generate and combine requests an explorer can send in a tick,
+    /// the informative requests are not counted
+    pub requests_per_tick: Option<u32>,
+    /// energy cells an explorer can consume while it stays on the planet
+    pub cells_per_visit: Option<u32>,
+}
+
+/// How the charged cells are shared between the landed explorers.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum FairnessPolicy {
+    /// Whoever asks first gets the cell.
+    #[default]
+    FirstCome,
+    /// In a round every landed explorer can consume one cell, an explorer
+    /// that already had its cell waits for the others or for the next tick.
+    RoundRobin,
+    /// Like [`FairnessPolicy::RoundRobin`], but an explorer can consume as
+    /// many cells per round as its weight, `default_weight` if it has none.
+    Weighted {
+        weights: HashMap<ID, u32>,
+        default_weight: u32,
+    },
+}
+
+impl FairnessPolicy {
+    /// Weighted policy, the explorers not in `weights` have weight 1.
+    pub fn weighted(weights: impl IntoIterator<Item = (ID, u32)>) -> Self {
+        FairnessPolicy::Weighted {
+            weights: weights.into_iter().collect(),
+            default_weight: 1,
+        }
+    }
+
+    /// Cells the explorer can consume in a round, None if unlimited.
+    fn share(&self, explorer_id: ID) -> Option<u32> {
+        match self {
+            FairnessPolicy::FirstCome => None,
+            FairnessPolicy::RoundRobin => Some(1),
+            FairnessPolicy::Weighted {
+                weights,
+                default_weight,
+            } => Some(*weights.get(&explorer_id).unwrap_or(default_weight)),
+        }
+    }
+}
+
+/// Why a request was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttle {
+    RequestsPerTick(u32),
+    CellsPerVisit(u32),
+    /// the explorer already had its share of the round
+    Fairness,
+    /// the planet script refused the request
+    Script,
+}
+
+impl fmt::Display for Throttle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Throttle::RequestsPerTick(n) => write!(f, "quota exceeded: {} requests per tick", n),
+            Throttle::CellsPerVisit(n) => write!(f, "quota exceeded: {} cells per visit", n),
+            Throttle::Fairness => write!(f, "other explorers are waiting for a cell"),
+            Throttle::Script => write!(f, "refused by the planet script"),
+        }
+    }
+}
+
+/// Counters of the quotas and of the fairness rounds.
+#[derive(Debug, Default)]
+pub struct QuotaBook {
+    quota: ExplorerQuota,
+    fairness: FairnessPolicy,
+    /// requests received in the current tick
+    requests: HashMap<ID, u32>,
+    /// cells consumed in the current round
+    round: HashMap<ID, u32>,
+}
+
+impl QuotaBook {
+    pub fn new(quota: ExplorerQuota, fairness: FairnessPolicy) -> Self {
+        Self {
+            quota,
+            fairness,
+            ..Self::default()
+        }
+    }
+
+    pub fn quota(&self) -> ExplorerQuota {
+        self.quota
+    }
+
+    pub fn fairness(&self) -> &FairnessPolicy {
+        &self.fairness
+    }
+
+    /// Starts a new tick, resetting the request counters and the round.
+    pub fn new_tick(&mut self) {
+        self.requests.clear();
+        self.round.clear();
+    }
+
+    /// Counts a request of the explorer, refused if it is over the per-tick quota.
+    pub fn request(&mut self, explorer_id: ID) -> Result<(), Throttle> {
+        let n = self.requests.entry(explorer_id).or_default();
+        *n += 1;
+        match self.quota.requests_per_tick {
+            Some(limit) if *n > limit => Err(Throttle::RequestsPerTick(limit)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that the explorer, which consumed `consumed_cells` during its
+    /// visit, can consume one more cell. `landed` are the explorers sharing
+    /// the cells: when all of them had their share a new round starts.
+    pub fn check_cell(
+        &mut self,
+        explorer_id: ID,
+        consumed_cells: u32,
+        landed: &[ID],
+    ) -> Result<(), Throttle> {
+        if let Some(limit) = self.quota.cells_per_visit
+            && consumed_cells >= limit
+        {
+            return Err(Throttle::CellsPerVisit(limit));
+        }
+        let Some(share) = self.fairness.share(explorer_id) else {
+            return Ok(());
+        };
+        if landed.iter().all(|id| self.had_share(*id)) {
+            self.round.clear();
+        }
+        if self.round.get(&explorer_id).copied().unwrap_or(0) >= share {
+            return Err(Throttle::Fairness);
+        }
+        Ok(())
+    }
+
+    /// Records a cell consumed by the explorer in the current round.
+    pub fn cell_consumed(&mut self, explorer_id: ID) {
+        *self.round.entry(explorer_id).or_default() += 1;
+    }
+
+    /// Forgets the counters of an explorer leaving the planet.
+    pub fn depart(&mut self, explorer_id: ID) {
+        self.requests.remove(&explorer_id);
+        self.round.remove(&explorer_id);
+    }
+
+    fn had_share(&self, explorer_id: ID) -> bool {
+        match self.fairness.share(explorer_id) {
+            Some(share) => self.round.get(&explorer_id).copied().unwrap_or(0) >= share,
+            None => true,
+        }
+    }
+}
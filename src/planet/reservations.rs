@@ -0,0 +1,103 @@
+//! Charged cells reserved by explorers for a short window.
+//!
+//! A reserved cell is pulled out of the charged stack, so no other request
+//! can consume it, and is pushed back when the reservation expires
+//! or its explorer leaves the planet.
+
+use common_game::utils::ID;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    pub cell: u32,
+    pub expires_at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct ReservationBook {
+    reservations: HashMap<ID, Reservation>,
+}
+
+impl ReservationBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserves `cell` for the explorer until `window` has elapsed.
+    /// Returns the cell previously reserved by the explorer, if any,
+    /// which the caller must give back to the allocator.
+    pub fn reserve(&mut self, explorer_id: ID, cell: u32, window: Duration) -> Option<u32> {
+        self.reservations
+            .insert(
+                explorer_id,
+                Reservation {
+                    cell,
+                    expires_at: Instant::now() + window,
+                },
+            )
+            .map(|old| old.cell)
+    }
+
+    /// Extends the reservation of the explorer, returns false if it has none.
+    pub fn refresh(&mut self, explorer_id: ID, window: Duration) -> bool {
+        match self.reservations.get_mut(&explorer_id) {
+            Some(reservation) => {
+                reservation.expires_at = Instant::now() + window;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the reservation of the explorer and returns its cell.
+    /// Used both to consume and to release a reservation.
+    pub fn take(&mut self, explorer_id: ID) -> Option<u32> {
+        self.reservations
+            .remove(&explorer_id)
+            .map(|reservation| reservation.cell)
+    }
+
+    /// Removes every reservation expired at `now`,
+    /// returning the explorers and the cells to give back.
+    pub fn expire(&mut self, now: Instant) -> Vec<(ID, u32)> {
+        let expired: Vec<(ID, u32)> = self
+            .reservations
+            .iter()
+            .filter(|(_, reservation)| reservation.expires_at <= now)
+            .map(|(id, reservation)| (*id, reservation.cell))
+            .collect();
+        for (id, _) in &expired {
+            self.reservations.remove(id);
+        }
+        expired
+    }
+
+    /// Removes every reservation, returning the explorers and the cells to give back.
+    pub fn release_all(&mut self) -> Vec<(ID, u32)> {
+        self.reservations
+            .drain()
+            .map(|(id, reservation)| (id, reservation.cell))
+            .collect()
+    }
+
+    pub fn get(&self, explorer_id: ID) -> Option<&Reservation> {
+        self.reservations.get(&explorer_id)
+    }
+
+    /// Cells currently reserved.
+    pub fn cells(&self) -> Vec<u32> {
+        self.reservations
+            .values()
+            .map(|reservation| reservation.cell)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.reservations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reservations.is_empty()
+    }
+}
@@ -0,0 +1,445 @@
+//! Planet reactions written in a Rhai script.
+//!
+//! A [`ScriptedAI`] wraps the default [`OneMillionCrabs`] AI and asks the
+//! script before handling a sunray, an asteroid or an explorer request.
+//! Every hook is optional and receives a copy of the planet state, so the
+//! script can only decide, never change the planet:
+//!
+//! ```text
+//! fn on_sunray(planet) { if planet.charged == planet.cells { "rocket" } else { "charge" } }
+//! fn on_asteroid(planet) { "defend" }
+//! fn on_explorer_request(planet, request) {
+//!     if request.kind == "generate" && planet.available_cells < 2 { "refuse" } else { "serve" }
+//! }
+//! ```
+//!
+//! The planet view has `id`, `cells`, `charged`, `available_cells`,
+//! `has_rocket`, `can_have_rocket`, `inventory`, `demand` (cells per tick)
+//! and `explorers`; the request has `explorer_id`, `kind` and, for generate
+//! and combine requests, `resource`. Only the generate and combine requests
+//! can be refused, the informative ones are always answered. A hook
+//! returning nothing keeps the default behaviour.
+//!
+//! The script runs sandboxed: no modules, no `eval`, and limits on the
+//! operations, the call depth and the size of strings, arrays and maps.
+//! A script error, or an unknown decision, is logged and the default
+//! behaviour is used. What the script prints goes to the log of the planet.
+
+use super::{DEBUG_LOG_CHNL, ERR_LOG_CHNL, INTRNL_ACTN_LOG_CHNL, OneMillionCrabs, Throttle};
+use super::{ToString2, consumes_cell, recipe, throttled_response};
+use crate::create_internal_log_msg;
+use crate::log_config::PlanetLog;
+use common_game::components::planet::{DummyPlanetState, PlanetAI, PlanetState};
+use common_game::components::resource::{Combinator, Generator};
+use common_game::components::rocket::Rocket;
+use common_game::components::sunray::Sunray;
+use common_game::logging::{ActorType, Channel, EventType, LogEvent, Participant, Payload};
+use common_game::protocols::planet_explorer::{ExplorerToPlanet, PlanetToExplorer};
+use common_game::utils::ID;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{AST, Array, Dynamic, Engine, FuncArgs, Map, Scope};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Operations a single hook can run before it is stopped.
+pub const MAX_OPERATIONS: u64 = 100_000;
+
+/// A compiled script and the sandboxed engine running it.
+pub struct PlanetScript {
+    engine: Engine,
+    ast: AST,
+}
+
+impl PlanetScript {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        Self::compile(&source).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn compile(source: &str) -> Result<Self, String> {
+        let mut engine = Engine::new();
+        engine
+            .set_module_resolver(DummyModuleResolver::new())
+            .set_max_modules(0)
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(4096)
+            .set_max_array_size(1024)
+            .set_max_map_size(1024)
+            .disable_symbol("eval");
+        // print and debug go to the log crate, not to the terminal of the game,
+        // until the script is given to a planet
+        engine.on_print(|s| log::info!("script: {}", s));
+        engine.on_debug(|s, _, pos| log::debug!("script {}: {}", pos, s));
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        Ok(Self { engine, ast })
+    }
+
+    /// Sends what the script prints to the log of planet `planet_id`.
+    fn log_output(&mut self, planet_id: ID, log: Arc<PlanetLog>) {
+        let debug_log = log.clone();
+        self.engine.on_print(move |s| {
+            //LOG
+            create_internal_log_msg!(
+                log;
+                planet_id,
+                INTRNL_ACTN_LOG_CHNL,
+                "Action".to_string(),
+                "script print".to_string(),
+                "Message".to_string(),
+                s.to_string()
+            );
+            //LOG
+        });
+        self.engine.on_debug(move |s, _, pos| {
+            //LOG
+            create_internal_log_msg!(
+                debug_log;
+                planet_id,
+                DEBUG_LOG_CHNL,
+                "Action".to_string(),
+                "script debug".to_string(),
+                "Position".to_string(),
+                pos.to_string(),
+                "Message".to_string(),
+                s.to_string()
+            );
+            //LOG
+        });
+    }
+
+    /// True if the script defines `hook` with `n_params` parameters.
+    pub fn has_hook(&self, hook: &str, n_params: usize) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|f| f.name == hook && f.params.len() == n_params)
+    }
+
+    /// Calls `hook` and returns its decision, None if the hook is missing
+    /// or returned nothing.
+    fn call(
+        &self,
+        hook: &str,
+        n_params: usize,
+        args: impl FuncArgs,
+    ) -> Result<Option<String>, String> {
+        if !self.has_hook(hook, n_params) {
+            return Ok(None);
+        }
+        let result = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, hook, args)
+            .map_err(|e| e.to_string())?;
+        if result.is_unit() {
+            return Ok(None);
+        }
+        result
+            .into_string()
+            .map(Some)
+            .map_err(|t| format!("{} returned a {}, not a string", hook, t))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SunrayDecision {
+    Charge,
+    /// charge, then build a rocket if the planet has none
+    Rocket,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AsteroidDecision {
+    Defend,
+    /// the rocket is not used and the planet is destroyed
+    Surrender,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RequestDecision {
+    Serve,
+    Refuse,
+}
+
+/// The default AI, with the decisions of a [`PlanetScript`].
+/// The script is asked inside the handler of the default AI, after the
+/// expired reservations are given back and before the cells are checked.
+pub struct ScriptedAI {
+    inner: OneMillionCrabs,
+    script: PlanetScript,
+}
+
+impl ScriptedAI {
+    pub fn new(inner: OneMillionCrabs, mut script: PlanetScript) -> Self {
+        script.log_output(inner.planet_id, inner.log.clone());
+        Self { inner, script }
+    }
+
+    /// Read-only copy of the planet state given to the hooks.
+    fn view(&self, state: &PlanetState) -> Map {
+        let mut view = Map::new();
+        view.insert("id".into(), Dynamic::from(state.id() as i64));
+        view.insert("cells".into(), Dynamic::from(state.cells_count() as i64));
+        view.insert(
+            "charged".into(),
+            Dynamic::from(super::charged_cells(state) as i64),
+        );
+        view.insert(
+            "available_cells".into(),
+            Dynamic::from(self.inner.explorer_available_cells(state) as i64),
+        );
+        view.insert("has_rocket".into(), Dynamic::from(state.has_rocket()));
+        view.insert(
+            "can_have_rocket".into(),
+            Dynamic::from(state.can_have_rocket()),
+        );
+        view.insert(
+            "inventory".into(),
+            Dynamic::from(self.inner.inventory.len() as i64),
+        );
+        view.insert(
+            "demand".into(),
+            Dynamic::from(self.inner.demand.demand().cells_per_tick()),
+        );
+        let explorers = self
+            .inner
+            .visitors
+            .present()
+            .into_iter()
+            .map(|id| Dynamic::from(id as i64))
+            .collect::<Array>();
+        view.insert("explorers".into(), Dynamic::from(explorers));
+        view
+    }
+
+    /// Runs `hook` and parses its decision, `default` on any error.
+    fn decide<T: Copy + std::fmt::Debug>(
+        &self,
+        planet_id: ID,
+        hook: &str,
+        result: Result<Option<String>, String>,
+        parse: fn(&str) -> Option<T>,
+        default: T,
+    ) -> T {
+        let decision = match result {
+            Ok(None) => return default,
+            Ok(Some(s)) => parse(&s).ok_or_else(|| format!("unknown decision {:?}", s)),
+            Err(e) => Err(e),
+        };
+        match decision {
+            Ok(decision) => {
+                //LOG
+                create_internal_log_msg!(
+                    self.inner.log;
+                    planet_id,
+                    DEBUG_LOG_CHNL,
+                    "Action".to_string(),
+                    format!("script {}", hook),
+                    "Decision".to_string(),
+                    format!("{:?}", decision)
+                );
+                //LOG
+                decision
+            }
+            Err(e) => {
+                //LOG
+                create_internal_log_msg!(
+                    self.inner.log;
+                    planet_id,
+                    ERR_LOG_CHNL,
+                    "Action".to_string(),
+                    format!("script {}", hook),
+                    "ERR".to_string(),
+                    e,
+                    "Fallback".to_string(),
+                    format!("{:?}", default)
+                );
+                //LOG
+                default
+            }
+        }
+    }
+}
+
+/// The fields of an explorer request given to `on_explorer_request`.
+fn request_view(msg: &ExplorerToPlanet) -> Map {
+    let (kind, resource) = match msg {
+        ExplorerToPlanet::GenerateResourceRequest { resource, .. } => {
+            ("generate", Some(format!("{:?}", resource)))
+        }
+        ExplorerToPlanet::CombineResourceRequest { msg, .. } => {
+            ("combine", Some(format!("{:?}", recipe(msg))))
+        }
+        ExplorerToPlanet::SupportedResourceRequest { .. } => ("supported_resources", None),
+        ExplorerToPlanet::SupportedCombinationRequest { .. } => ("supported_combinations", None),
+        ExplorerToPlanet::AvailableEnergyCellRequest { .. } => ("available_cells", None),
+    };
+    let mut view = Map::new();
+    view.insert(
+        "explorer_id".into(),
+        Dynamic::from(msg.explorer_id() as i64),
+    );
+    view.insert("kind".into(), Dynamic::from(kind.to_string()));
+    view.insert(
+        "resource".into(),
+        resource.map_or(Dynamic::UNIT, Dynamic::from),
+    );
+    view
+}
+
+impl PlanetAI for ScriptedAI {
+    fn handle_sunray(
+        &mut self,
+        state: &mut PlanetState,
+        generator: &Generator,
+        combinator: &Combinator,
+        sunray: Sunray,
+    ) {
+        self.inner.begin_handler(state);
+        let result = self.script.call("on_sunray", 1, (self.view(state),));
+        let decision = self.decide(
+            state.id(),
+            "on_sunray",
+            result,
+            |s| match s {
+                "charge" => Some(SunrayDecision::Charge),
+                "rocket" => Some(SunrayDecision::Rocket),
+                _ => None,
+            },
+            SunrayDecision::Charge,
+        );
+        self.inner
+            .handle_sunray(state, generator, combinator, sunray);
+        if decision == SunrayDecision::Rocket && state.can_have_rocket() && !state.has_rocket() {
+            self.inner.build_spare_rocket(state);
+        }
+        self.inner.end_handler(state);
+    }
+
+    fn handle_asteroid(
+        &mut self,
+        state: &mut PlanetState,
+        generator: &Generator,
+        combinator: &Combinator,
+    ) -> Option<Rocket> {
+        self.inner.begin_handler(state);
+        let result = self.script.call("on_asteroid", 1, (self.view(state),));
+        let decision = self.decide(
+            state.id(),
+            "on_asteroid",
+            result,
+            |s| match s {
+                "defend" => Some(AsteroidDecision::Defend),
+                "surrender" => Some(AsteroidDecision::Surrender),
+                _ => None,
+            },
+            AsteroidDecision::Defend,
+        );
+        let rocket = match decision {
+            AsteroidDecision::Defend => self.inner.handle_asteroid(state, generator, combinator),
+            AsteroidDecision::Surrender => {
+                self.inner.record(|m| m.asteroids_failed += 1);
+                //LOG
+                create_internal_log_msg!(
+                    self.inner.log;
+                    state.id(),
+                    INTRNL_ACTN_LOG_CHNL,
+                    "Message".to_string(),
+                    "The script surrendered to the asteroid".to_string()
+                );
+                //LOG
+                None
+            }
+        };
+        self.inner.end_handler(state);
+        rocket
+    }
+
+    fn handle_internal_state_req(
+        &mut self,
+        state: &mut PlanetState,
+        generator: &Generator,
+        combinator: &Combinator,
+    ) -> DummyPlanetState {
+        self.inner
+            .handle_internal_state_req(state, generator, combinator)
+    }
+
+    fn handle_explorer_msg(
+        &mut self,
+        state: &mut PlanetState,
+        generator: &Generator,
+        combinator: &Combinator,
+        msg: ExplorerToPlanet,
+    ) -> Option<PlanetToExplorer> {
+        self.inner.begin_handler(state);
+        let result = self.script.call(
+            "on_explorer_request",
+            2,
+            (self.view(state), request_view(&msg)),
+        );
+        let decision = self.decide(
+            state.id(),
+            "on_explorer_request",
+            result,
+            |s| match s {
+                "serve" => Some(RequestDecision::Serve),
+                "refuse" => Some(RequestDecision::Refuse),
+                _ => None,
+            },
+            RequestDecision::Serve,
+        );
+        let response = match decision {
+            RequestDecision::Refuse if consumes_cell(&msg) => {
+                let explorer_id = msg.explorer_id();
+                self.inner.record_request(explorer_id);
+                self.inner.throttled(
+                    state.id(),
+                    explorer_id,
+                    msg.to_string_2(),
+                    &Throttle::Script,
+                );
+                throttled_response(msg, Throttle::Script)
+            }
+            // the informative requests are always answered
+            _ => self
+                .inner
+                .handle_explorer_msg(state, generator, combinator, msg),
+        };
+        self.inner.end_handler(state);
+        response
+    }
+
+    fn on_explorer_arrival(
+        &mut self,
+        state: &mut PlanetState,
+        generator: &Generator,
+        combinator: &Combinator,
+        explorer_id: ID,
+    ) {
+        self.inner
+            .on_explorer_arrival(state, generator, combinator, explorer_id);
+    }
+
+    fn on_explorer_departure(
+        &mut self,
+        state: &mut PlanetState,
+        generator: &Generator,
+        combinator: &Combinator,
+        explorer_id: ID,
+    ) {
+        self.inner
+            .on_explorer_departure(state, generator, combinator, explorer_id);
+    }
+
+    fn on_start(&mut self, state: &PlanetState, generator: &Generator, combinator: &Combinator) {
+        self.inner.on_start(state, generator, combinator);
+    }
+
+    fn on_stop(&mut self, state: &PlanetState, generator: &Generator, combinator: &Combinator) {
+        self.inner.on_stop(state, generator, combinator);
+    }
+}
@@ -0,0 +1,371 @@
+//! Checkpoints of the AI-side state of a crab-rave planet.
+//!
+//! A [`PlanetSnapshot`] holds everything the [`OneMillionCrabs`](crate::planet::OneMillionCrabs)
+//! AI knows (rocket status, visitors, metrics, learned demand and options)
+//! together with the configuration of the planet, so that
+//! [`PlanetBuilder::from_snapshot`](crate::planet::PlanetBuilder::from_snapshot)
+//! can create the same planet again.
+//!
+//! The charge of the cells and the rocket belong to the `PlanetState`, which
+//! only the orchestrator can fill with sunrays: a restored planet starts with
+//! empty cells, so the cell stacks and the reservations are not saved and the
+//! stacks are rebuilt from the new planet state.
+//! The resources of the inventory and the state of the explorer quotas are
+//! not saved either.
+//!
+//! The visitors are restored in the AI only: the orchestrator must send
+//! `IncomingExplorerRequest` again for each of them, otherwise the planet
+//! ignores their messages.
+
+use crate::planet::defense::RocketPolicy;
+use crate::planet::demand::Demand;
+use crate::planet::invariants::InvariantPolicy;
+use crate::planet::metrics::PlanetMetrics;
+use crate::planet::visitors::Visitor;
+use common_game::components::planet::PlanetType;
+use common_game::components::resource::{BasicResourceType, ComplexResourceType};
+use common_game::utils::ID;
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hash;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Version written in every snapshot, bumped when the format changes.
+pub const SNAPSHOT_VERSION: u64 = 1;
+
+#[derive(Debug, Clone)]
+pub struct PlanetSnapshot {
+    pub planet_id: ID,
+    pub taken_at_unix: u64,
+    pub planet_type: PlanetType,
+    pub gen_rules: Vec<BasicResourceType>,
+    pub comb_rules: Vec<ComplexResourceType>,
+    pub has_rocket: bool,
+    pub rocket_policy: RocketPolicy,
+    pub reservation_window: Option<Duration>,
+    pub invariant_policy: InvariantPolicy,
+    /// explorers landed on the planet and how long they had been there
+    pub visitors: Vec<(ID, Visitor)>,
+    pub metrics: PlanetMetrics,
+    /// demand learned by the production planner, in requests per tick
+    pub demand: Demand,
+}
+
+impl PlanetSnapshot {
+    /// Writes the snapshot to `path`, replacing the previous one only once
+    /// the new one is complete.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.to_json().to_string())
+            .map_err(|e| format!("can't write {}: {}", tmp.display(), e))?;
+        fs::rename(&tmp, path).map_err(|e| format!("can't write {}: {}", path.display(), e))
+    }
+
+    /// Reads a snapshot written by [`PlanetSnapshot::save`].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let value: Value =
+            serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::from_json(&value).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn to_json(&self) -> Value {
+        let visitors = self
+            .visitors
+            .iter()
+            .map(|(id, visitor)| {
+                json!({
+                    "explorer_id": id,
+                    "stay_ms": visitor.stay().as_millis() as u64,
+                    "consumed_cells": visitor.consumed_cells,
+                    "generated": counts_to_json(&visitor.generated),
+                    "combined": counts_to_json(&visitor.combined),
+                })
+            })
+            .collect::<Vec<_>>();
+        let rocket_policy = match self.rocket_policy {
+            RocketPolicy::OnDemand => json!({ "kind": "OnDemand" }),
+            RocketPolicy::Prebuild { explorer_cells } => {
+                json!({ "kind": "Prebuild", "explorer_cells": explorer_cells })
+            }
+        };
+        let m = &self.metrics;
+        json!({
+            "version": SNAPSHOT_VERSION,
+            "planet_id": self.planet_id,
+            "taken_at_unix": self.taken_at_unix,
+            "planet_type": format!("{:?}", self.planet_type),
+            "gen_rules": self.gen_rules.iter().map(|r| format!("{:?}", r)).collect::<Vec<_>>(),
+            "comb_rules": self.comb_rules.iter().map(|r| format!("{:?}", r)).collect::<Vec<_>>(),
+            "has_rocket": self.has_rocket,
+            "rocket_policy": rocket_policy,
+            "reservation_window_ms": self.reservation_window.map(|w| w.as_millis() as u64),
+            "invariant_policy": {
+                "after_every_handler": self.invariant_policy.after_every_handler,
+                "resync": self.invariant_policy.resync,
+            },
+            "visitors": visitors,
+            "metrics": {
+                "sunrays_received": m.sunrays_received,
+                "sunrays_wasted": m.sunrays_wasted,
+                "asteroids_survived": m.asteroids_survived,
+                "asteroids_failed": m.asteroids_failed,
+                "rockets_built": m.rockets_built,
+                "generated": counts_to_json(&m.generated),
+                "combined": counts_to_json(&m.combined),
+                "served_from_inventory": m.served_from_inventory,
+                "explorer_requests": per_explorer_to_json(&m.explorer_requests),
+                "throttled_requests": per_explorer_to_json(&m.throttled_requests),
+            },
+            "demand": {
+                "basic": averages_to_json(&self.demand.basic),
+                "complex": averages_to_json(&self.demand.complex),
+            },
+        })
+    }
+
+    /// Parses a snapshot written by [`PlanetSnapshot::to_json`].
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        let version = u64_field(value, "version")?;
+        if version != SNAPSHOT_VERSION {
+            return Err(format!("unsupported snapshot version {}", version));
+        }
+
+        let rocket_policy = field(value, "rocket_policy")?;
+        let rocket_policy = match str_field(rocket_policy, "kind")? {
+            "OnDemand" => RocketPolicy::OnDemand,
+            "Prebuild" => RocketPolicy::Prebuild {
+                explorer_cells: u64_field(rocket_policy, "explorer_cells")? as usize,
+            },
+            kind => return Err(format!("unknown rocket policy {}", kind)),
+        };
+        let invariant_policy = field(value, "invariant_policy")?;
+        let invariant_policy = InvariantPolicy {
+            after_every_handler: bool_field(invariant_policy, "after_every_handler")?,
+            resync: bool_field(invariant_policy, "resync")?,
+        };
+
+        let now = Instant::now();
+        let mut visitors = Vec::new();
+        for v in array_field(value, "visitors")? {
+            let stay = Duration::from_millis(u64_field(v, "stay_ms")?);
+            visitors.push((
+                id_field(v, "explorer_id")?,
+                Visitor {
+                    arrived_at: now.checked_sub(stay).unwrap_or(now),
+                    consumed_cells: u64_field(v, "consumed_cells")? as u32,
+                    generated: counts_from_json(field(v, "generated")?, parse_basic)?
+                        .into_iter()
+                        .map(|(k, n)| (k, n as u32))
+                        .collect(),
+                    combined: counts_from_json(field(v, "combined")?, parse_complex)?
+                        .into_iter()
+                        .map(|(k, n)| (k, n as u32))
+                        .collect(),
+                },
+            ));
+        }
+
+        let m = field(value, "metrics")?;
+        let metrics = PlanetMetrics {
+            sunrays_received: u64_field(m, "sunrays_received")?,
+            sunrays_wasted: u64_field(m, "sunrays_wasted")?,
+            asteroids_survived: u64_field(m, "asteroids_survived")?,
+            asteroids_failed: u64_field(m, "asteroids_failed")?,
+            rockets_built: u64_field(m, "rockets_built")?,
+            generated: counts_from_json(field(m, "generated")?, parse_basic)?,
+            combined: counts_from_json(field(m, "combined")?, parse_complex)?,
+            served_from_inventory: u64_field(m, "served_from_inventory")?,
+            explorer_requests: per_explorer_from_json(m, "explorer_requests")?,
+            throttled_requests: per_explorer_from_json(m, "throttled_requests")?,
+            ..PlanetMetrics::default()
+        };
+
+        let d = field(value, "demand")?;
+        let demand = Demand {
+            basic: averages_from_json(field(d, "basic")?, parse_basic)?,
+            complex: averages_from_json(field(d, "complex")?, parse_complex)?,
+        };
+
+        let window = field(value, "reservation_window_ms")?;
+        Ok(Self {
+            planet_id: id_field(value, "planet_id")?,
+            taken_at_unix: u64_field(value, "taken_at_unix")?,
+            planet_type: parse_planet_type(str_field(value, "planet_type")?)?,
+            gen_rules: strings(value, "gen_rules", parse_basic)?,
+            comb_rules: strings(value, "comb_rules", parse_complex)?,
+            has_rocket: bool_field(value, "has_rocket")?,
+            rocket_policy,
+            reservation_window: match window {
+                Value::Null => None,
+                w => Some(Duration::from_millis(
+                    w.as_u64()
+                        .ok_or("field reservation_window_ms is not a number")?,
+                )),
+            },
+            invariant_policy,
+            visitors,
+            metrics,
+            demand,
+        })
+    }
+}
+
+/// Seconds since the unix epoch, 0 if the clock is before it.
+pub(crate) fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn counts_to_json<K: std::fmt::Debug, N: Copy + Into<u64>>(counts: &HashMap<K, N>) -> Value {
+    let map = counts
+        .iter()
+        .map(|(k, n)| (format!("{:?}", k), json!((*n).into())))
+        .collect::<Map<_, _>>();
+    Value::Object(map)
+}
+
+fn averages_to_json<K: std::fmt::Debug>(averages: &HashMap<K, f64>) -> Value {
+    let map = averages
+        .iter()
+        .map(|(k, n)| (format!("{:?}", k), json!(n)))
+        .collect::<Map<_, _>>();
+    Value::Object(map)
+}
+
+fn averages_from_json<K: Eq + Hash>(
+    value: &Value,
+    parse: fn(&str) -> Result<K, String>,
+) -> Result<HashMap<K, f64>, String> {
+    let object = value.as_object().ok_or("averages are not an object")?;
+    let mut averages = HashMap::new();
+    for (k, n) in object {
+        let n = n
+            .as_f64()
+            .ok_or_else(|| format!("average of {} is not a number", k))?;
+        averages.insert(parse(k)?, n);
+    }
+    Ok(averages)
+}
+
+fn per_explorer_to_json(counts: &HashMap<ID, u64>) -> Value {
+    let mut pairs = counts.iter().collect::<Vec<_>>();
+    pairs.sort();
+    Value::Array(pairs.into_iter().map(|(id, n)| json!([id, n])).collect())
+}
+
+fn per_explorer_from_json(value: &Value, name: &str) -> Result<HashMap<ID, u64>, String> {
+    let mut counts = HashMap::new();
+    for pair in array_field(value, name)? {
+        let (id, n) = pair
+            .as_array()
+            .and_then(|pair| Some((pair.first()?.as_u64()?, pair.get(1)?.as_u64()?)))
+            .ok_or_else(|| format!("invalid {} entry", name))?;
+        let id = ID::try_from(id).map_err(|e| e.to_string())?;
+        counts.insert(id, n);
+    }
+    Ok(counts)
+}
+
+fn counts_from_json<K: Eq + Hash>(
+    value: &Value,
+    parse: fn(&str) -> Result<K, String>,
+) -> Result<HashMap<K, u64>, String> {
+    let object = value.as_object().ok_or("counts are not an object")?;
+    let mut counts = HashMap::new();
+    for (k, n) in object {
+        let n = n
+            .as_u64()
+            .ok_or_else(|| format!("count of {} is not a number", k))?;
+        counts.insert(parse(k)?, n);
+    }
+    Ok(counts)
+}
+
+fn field<'a>(value: &'a Value, name: &str) -> Result<&'a Value, String> {
+    value
+        .get(name)
+        .ok_or_else(|| format!("missing field {}", name))
+}
+
+fn u64_field(value: &Value, name: &str) -> Result<u64, String> {
+    field(value, name)?
+        .as_u64()
+        .ok_or_else(|| format!("field {} is not a number", name))
+}
+
+fn id_field(value: &Value, name: &str) -> Result<ID, String> {
+    ID::try_from(u64_field(value, name)?).map_err(|e| format!("field {}: {}", name, e))
+}
+
+fn bool_field(value: &Value, name: &str) -> Result<bool, String> {
+    field(value, name)?
+        .as_bool()
+        .ok_or_else(|| format!("field {} is not a boolean", name))
+}
+
+fn str_field<'a>(value: &'a Value, name: &str) -> Result<&'a str, String> {
+    field(value, name)?
+        .as_str()
+        .ok_or_else(|| format!("field {} is not a string", name))
+}
+
+fn array_field<'a>(value: &'a Value, name: &str) -> Result<&'a Vec<Value>, String> {
+    field(value, name)?
+        .as_array()
+        .ok_or_else(|| format!("field {} is not an array", name))
+}
+
+fn strings<T>(
+    value: &Value,
+    name: &str,
+    parse: fn(&str) -> Result<T, String>,
+) -> Result<Vec<T>, String> {
+    array_field(value, name)?
+        .iter()
+        .map(|s| {
+            s.as_str()
+                .ok_or_else(|| format!("invalid entry in {}", name))
+                .and_then(parse)
+        })
+        .collect()
+}
+
+fn parse_planet_type(s: &str) -> Result<PlanetType, String> {
+    match s {
+        "A" => Ok(PlanetType::A),
+        "B" => Ok(PlanetType::B),
+        "C" => Ok(PlanetType::C),
+        "D" => Ok(PlanetType::D),
+        _ => Err(format!("unknown planet type {}", s)),
+    }
+}
+
+fn parse_basic(s: &str) -> Result<BasicResourceType, String> {
+    match s {
+        "Carbon" => Ok(BasicResourceType::Carbon),
+        "Hydrogen" => Ok(BasicResourceType::Hydrogen),
+        "Oxygen" => Ok(BasicResourceType::Oxygen),
+        "Silicon" => Ok(BasicResourceType::Silicon),
+        _ => Err(format!("unknown basic resource {}", s)),
+    }
+}
+
+fn parse_complex(s: &str) -> Result<ComplexResourceType, String> {
+    match s {
+        "Diamond" => Ok(ComplexResourceType::Diamond),
+        "Water" => Ok(ComplexResourceType::Water),
+        "Life" => Ok(ComplexResourceType::Life),
+        "Robot" => Ok(ComplexResourceType::Robot),
+        "Dolphin" => Ok(ComplexResourceType::Dolphin),
+        "AIPartner" => Ok(ComplexResourceType::AIPartner),
+        _ => Err(format!("unknown complex resource {}", s)),
+    }
+}
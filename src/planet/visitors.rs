@@ -0,0 +1,115 @@
+//! Registry of the explorers currently landed on the planet.
+
+use common_game::components::resource::{BasicResourceType, ComplexResourceType};
+use common_game::utils::ID;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// What the planet knows about a landed explorer.
+#[derive(Debug, Clone)]
+pub struct Visitor {
+    pub arrived_at: Instant,
+    /// number of energy cells discharged to serve this explorer
+    pub consumed_cells: u32,
+    pub generated: HashMap<BasicResourceType, u32>,
+    pub combined: HashMap<ComplexResourceType, u32>,
+}
+
+impl Visitor {
+    fn new() -> Self {
+        Self {
+            arrived_at: Instant::now(),
+            consumed_cells: 0,
+            generated: HashMap::new(),
+            combined: HashMap::new(),
+        }
+    }
+
+    /// Time spent on the planet so far.
+    pub fn stay(&self) -> Duration {
+        self.arrived_at.elapsed()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VisitorRegistry {
+    visitors: HashMap<ID, Visitor>,
+}
+
+impl VisitorRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new explorer on the planet.
+    /// Returns false if the explorer was already landed, in that case
+    /// its previous record is kept.
+    pub fn arrive(&mut self, explorer_id: ID) -> bool {
+        if self.visitors.contains_key(&explorer_id) {
+            return false;
+        }
+        self.visitors.insert(explorer_id, Visitor::new());
+        true
+    }
+
+    /// Removes an explorer from the planet, returning its record
+    /// or None if the explorer wasn't landed.
+    pub fn depart(&mut self, explorer_id: ID) -> Option<Visitor> {
+        self.visitors.remove(&explorer_id)
+    }
+
+    /// Puts back the record of an explorer, replacing the current one.
+    /// Used to restore a snapshot.
+    pub fn restore(&mut self, explorer_id: ID, visitor: Visitor) {
+        self.visitors.insert(explorer_id, visitor);
+    }
+
+    pub fn is_present(&self, explorer_id: ID) -> bool {
+        self.visitors.contains_key(&explorer_id)
+    }
+
+    pub fn get(&self, explorer_id: ID) -> Option<&Visitor> {
+        self.visitors.get(&explorer_id)
+    }
+
+    /// Ids of the explorers currently landed, in ascending order.
+    pub fn present(&self) -> Vec<ID> {
+        let mut ids: Vec<ID> = self.visitors.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub fn len(&self) -> usize {
+        self.visitors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.visitors.is_empty()
+    }
+
+    /// Records a basic resource generated for the explorer.
+    /// Does nothing if the explorer isn't landed.
+    pub fn record_generated(&mut self, explorer_id: ID, resource: BasicResourceType) {
+        if let Some(visitor) = self.visitors.get_mut(&explorer_id) {
+            visitor.consumed_cells += 1;
+            *visitor.generated.entry(resource).or_insert(0) += 1;
+        }
+    }
+
+    /// Records a basic resource served to the explorer from the inventory,
+    /// which doesn't consume a cell. Does nothing if the explorer isn't landed.
+    pub fn record_served(&mut self, explorer_id: ID, resource: BasicResourceType) {
+        if let Some(visitor) = self.visitors.get_mut(&explorer_id) {
+            *visitor.generated.entry(resource).or_insert(0) += 1;
+        }
+    }
+
+    /// Records a complex resource combined for the explorer.
+    /// Does nothing if the explorer isn't landed.
+    pub fn record_combined(&mut self, explorer_id: ID, resource: ComplexResourceType) {
+        if let Some(visitor) = self.visitors.get_mut(&explorer_id) {
+            visitor.consumed_cells += 1;
+            *visitor.combined.entry(resource).or_insert(0) += 1;
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Prometheus exposition of the planet metrics, enabled by the `prometheus` feature.
+//!
+//! [`serve`] starts a small HTTP server on a localhost port answering
+//! `GET /metrics` with the [`render`]ed metrics of every crab-rave planet
+//! living in the process; a planet leaves the output when its AI is dropped.
+
+use crate::planet::metrics::{self, PlanetMetrics};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long a client can take to send its request or read the response.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Metrics of every planet in the Prometheus text format.
+pub fn render() -> String {
+    let planets = metrics::planets()
+        .into_iter()
+        .filter_map(|id| metrics::snapshot(id).map(|m| (id, m)))
+        .collect::<Vec<_>>();
+    let single = |value: u64| vec![(String::new(), value)];
+
+    let mut out = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: Sampler| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (id, m) in &planets {
+            for (labels, value) in samples(m) {
+                let _ = writeln!(out, "{}{{planet=\"{}\"{}}} {}", name, id, labels, value);
+            }
+        }
+    };
+
+    family(
+        "crab_rave_charged_cells",
+        "gauge",
+        "Charged energy cells.",
+        &|m| single(m.charged_cells as u64),
+    );
+    family(
+        "crab_rave_cells",
+        "gauge",
+        "Energy cells of the planet.",
+        &|m| single(m.total_cells as u64),
+    );
+    family(
+        "crab_rave_sunrays_received_total",
+        "counter",
+        "Sunrays received.",
+        &|m| single(m.sunrays_received),
+    );
+    family(
+        "crab_rave_sunrays_wasted_total",
+        "counter",
+        "Sunrays that found no free cell.",
+        &|m| single(m.sunrays_wasted),
+    );
+    family(
+        "crab_rave_asteroids_total",
+        "counter",
+        "Asteroids received, by outcome.",
+        &|m| {
+            vec![
+                (",outcome=\"survived\"".to_string(), m.asteroids_survived),
+                (",outcome=\"failed\"".to_string(), m.asteroids_failed),
+            ]
+        },
+    );
+    family(
+        "crab_rave_rockets_built_total",
+        "counter",
+        "Rockets built.",
+        &|m| single(m.rockets_built),
+    );
+    family(
+        "crab_rave_resources_generated_total",
+        "counter",
+        "Basic resources generated, by type.",
+        &|m| {
+            labelled(
+                "resource",
+                m.generated.iter().map(|(k, v)| (format!("{:?}", k), *v)),
+            )
+        },
+    );
+    family(
+        "crab_rave_resources_combined_total",
+        "counter",
+        "Complex resources combined, by type.",
+        &|m| {
+            labelled(
+                "resource",
+                m.combined.iter().map(|(k, v)| (format!("{:?}", k), *v)),
+            )
+        },
+    );
+    family(
+        "crab_rave_inventory",
+        "gauge",
+        "Basic resources in the inventory, by type.",
+        &|m| {
+            labelled(
+                "resource",
+                m.inventory.iter().map(|(k, v)| (format!("{:?}", k), *v)),
+            )
+        },
+    );
+    family(
+        "crab_rave_inventory_served_total",
+        "counter",
+        "Generate requests served from the inventory.",
+        &|m| single(m.served_from_inventory),
+    );
+    family(
+        "crab_rave_explorer_requests_total",
+        "counter",
+        "Requests received, by explorer.",
+        &|m| {
+            labelled(
+                "explorer",
+                m.explorer_requests.iter().map(|(k, v)| (k.to_string(), *v)),
+            )
+        },
+    );
+    family(
+        "crab_rave_explorer_throttled_total",
+        "counter",
+        "Requests refused by the explorer quotas, by explorer.",
+        &|m| {
+            labelled(
+                "explorer",
+                m.throttled_requests
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), *v)),
+            )
+        },
+    );
+    out
+}
+
+/// Label suffix and value of every sample of a metric family.
+type Sampler<'a> = &'a dyn Fn(&PlanetMetrics) -> Vec<(String, u64)>;
+
+/// Samples labelled with `label`, sorted so the output is stable.
+fn labelled(label: &str, values: impl Iterator<Item = (String, u64)>) -> Vec<(String, u64)> {
+    let mut samples = values
+        .map(|(value, n)| (format!(",{}=\"{}\"", label, value), n))
+        .collect::<Vec<_>>();
+    samples.sort();
+    samples
+}
+
+/// Handle of the server started by [`serve`].
+/// The server runs until [`MetricsServer::shutdown`] or the end of the process.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    /// Address the server listens on, useful when started on port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops the server and waits for its thread.
+    pub fn shutdown(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // wakes up the blocking accept
+        let _ = TcpStream::connect(self.addr);
+        let _ = self.handle.join();
+    }
+}
+
+/// Starts serving `/metrics` on `127.0.0.1:port`, port 0 picks a free one.
+pub fn serve(port: u16) -> Result<MetricsServer, String> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .map_err(|e| format!("can't listen on port {}: {}", port, e))?;
+    let addr = listener.local_addr().map_err(|e| e.to_string())?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_flag = stop.clone();
+    let handle = thread::spawn(move || {
+        for stream in listener.incoming() {
+            if stop_flag.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Ok(stream) = stream {
+                // a client that goes away is not our problem
+                let _ = answer(stream);
+            }
+        }
+    });
+    Ok(MetricsServer { addr, stop, handle })
+}
+
+fn answer(mut stream: TcpStream) -> std::io::Result<()> {
+    // the requests are answered one at a time, a client that sends nothing
+    // must not keep the next scrapes waiting
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
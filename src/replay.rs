@@ -0,0 +1,790 @@
+//! Recording and replay of the messages exchanged by a planet.
+//!
+//! [`record`] wraps the channels passed to `create_planet`: every message
+//! reaching the planet, and every response it sends, is written with its
+//! timestamp as one JSON line. [`Replayer`] feeds the inbound messages of a
+//! recording to a fresh planet, one at a time, and diffs its responses with
+//! the recorded ones, stream by stream.
+//!
+//! The resources of a combine request can't be written to a file: only the
+//! recipe is recorded and the replayer crafts the ingredients again.
+
+use crate::planet::{Inert, create_planet, recipe};
+use common_game::components::asteroid::Asteroid;
+use common_game::components::energy_cell::EnergyCell;
+use common_game::components::planet::{Planet, PlanetType};
+use common_game::components::resource::{
+    BasicResourceType, Carbon, Combinator, ComplexResourceRequest, ComplexResourceType, Diamond,
+    Generator, Hydrogen, Life, Oxygen, Robot, Silicon, Water,
+};
+use common_game::components::sunray::Sunray;
+use common_game::protocols::orchestrator_planet::{OrchestratorToPlanet, PlanetToOrchestrator};
+use common_game::protocols::planet_explorer::{ExplorerToPlanet, PlanetToExplorer};
+use common_game::utils::ID;
+use crossbeam_channel::{Receiver, Sender, unbounded};
+use serde_json::{Value, json};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long the replayer waits for each response if not told otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Who a message comes from or goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Stream {
+    Orchestrator,
+    Explorer(ID),
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stream::Orchestrator => write!(f, "orchestrator"),
+            Stream::Explorer(id) => write!(f, "explorer {}", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ToPlanet,
+    FromPlanet,
+}
+
+/// One recorded message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// time since the recording started
+    pub at: Duration,
+    pub timestamp_unix_ms: u64,
+    pub stream: Stream,
+    pub direction: Direction,
+    pub message: Value,
+}
+
+impl Entry {
+    fn to_json(&self) -> Value {
+        let (stream, explorer_id) = match self.stream {
+            Stream::Orchestrator => ("orchestrator", None),
+            Stream::Explorer(id) => ("explorer", Some(id)),
+        };
+        let direction = match self.direction {
+            Direction::ToPlanet => "in",
+            Direction::FromPlanet => "out",
+        };
+        json!({
+            "at_ms": self.at.as_millis() as u64,
+            "timestamp_unix_ms": self.timestamp_unix_ms,
+            "stream": stream,
+            "explorer_id": explorer_id,
+            "direction": direction,
+            "message": self.message,
+        })
+    }
+
+    fn from_json(value: &Value) -> Result<Self, String> {
+        let u64_field = |name: &str| {
+            value
+                .get(name)
+                .and_then(Value::as_u64)
+                .ok_or_else(|| format!("field {} is not a number", name))
+        };
+        let stream = match value.get("stream").and_then(Value::as_str) {
+            Some("orchestrator") => Stream::Orchestrator,
+            Some("explorer") => Stream::Explorer(
+                ID::try_from(u64_field("explorer_id")?).map_err(|e| e.to_string())?,
+            ),
+            _ => return Err("invalid field stream".to_string()),
+        };
+        let direction = match value.get("direction").and_then(Value::as_str) {
+            Some("in") => Direction::ToPlanet,
+            Some("out") => Direction::FromPlanet,
+            _ => return Err("invalid field direction".to_string()),
+        };
+        Ok(Self {
+            at: Duration::from_millis(u64_field("at_ms")?),
+            timestamp_unix_ms: u64_field("timestamp_unix_ms")?,
+            stream,
+            direction,
+            message: value
+                .get("message")
+                .cloned()
+                .ok_or("missing field message")?,
+        })
+    }
+}
+
+/// Reads back a file written by a recorder.
+pub fn read_recording(path: impl AsRef<Path>) -> Result<Vec<Entry>, String> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| format!("can't open {}: {}", path.display(), e))?;
+    let mut entries = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value =
+            serde_json::from_str(&line).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        entries.push(Entry::from_json(&value).map_err(|e| format!("line {}: {}", n + 1, e))?);
+    }
+    Ok(entries)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////
+// Recorder
+///////////////////////////////////////////////////////////////////////////////////////////
+
+struct RecordingFile {
+    writer: LineWriter<File>,
+    started: Instant,
+}
+
+#[derive(Clone)]
+struct Recorder(Arc<Mutex<RecordingFile>>);
+
+impl Recorder {
+    fn write(&self, stream: Stream, direction: Direction, message: Value) {
+        let mut file = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = Entry {
+            at: file.started.elapsed(),
+            timestamp_unix_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            stream,
+            direction,
+            message,
+        };
+        // a failed write must not stop the game, the recording is only a debugging aid
+        let _ = writeln!(file.writer, "{}", entry.to_json());
+    }
+}
+
+/// Planet side of the channels wrapped by [`record`].
+pub struct RecordedChannels {
+    pub rx_orchestrator: Receiver<OrchestratorToPlanet>,
+    pub tx_orchestrator: Sender<PlanetToOrchestrator>,
+    pub rx_explorer: Receiver<ExplorerToPlanet>,
+}
+
+/// Wraps the channels of a planet, writing every message that goes through
+/// them to the file at `path`, which is truncated.
+/// The messages are forwarded by background threads that end with the channels.
+pub fn record(
+    path: impl AsRef<Path>,
+    rx_orchestrator: Receiver<OrchestratorToPlanet>,
+    tx_orchestrator: Sender<PlanetToOrchestrator>,
+    rx_explorer: Receiver<ExplorerToPlanet>,
+) -> Result<RecordedChannels, String> {
+    let path = path.as_ref();
+    let file = File::create(path).map_err(|e| format!("can't create {}: {}", path.display(), e))?;
+    let recorder = Recorder(Arc::new(Mutex::new(RecordingFile {
+        writer: LineWriter::new(file),
+        started: Instant::now(),
+    })));
+
+    let (to_planet, planet_rx_orchestrator) = unbounded();
+    let (planet_tx_orchestrator, from_planet) = unbounded();
+    let (to_planet_explorer, planet_rx_explorer) = unbounded();
+
+    let rec = recorder.clone();
+    thread::spawn(move || {
+        for msg in rx_orchestrator {
+            rec.write(
+                Stream::Orchestrator,
+                Direction::ToPlanet,
+                orchestrator_msg_to_json(&msg),
+            );
+            // the responses to an explorer go through the recorder as well
+            let msg = match msg {
+                OrchestratorToPlanet::IncomingExplorerRequest {
+                    explorer_id,
+                    new_sender,
+                } => OrchestratorToPlanet::IncomingExplorerRequest {
+                    explorer_id,
+                    new_sender: forward_explorer(&rec, explorer_id, new_sender),
+                },
+                msg => msg,
+            };
+            if to_planet.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+
+    let rec = recorder.clone();
+    thread::spawn(move || {
+        for msg in from_planet {
+            rec.write(
+                Stream::Orchestrator,
+                Direction::FromPlanet,
+                orchestrator_reply_to_json(&msg),
+            );
+            if tx_orchestrator.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+
+    let rec = recorder;
+    thread::spawn(move || {
+        for msg in rx_explorer {
+            rec.write(
+                Stream::Explorer(msg.explorer_id()),
+                Direction::ToPlanet,
+                explorer_msg_to_json(&msg),
+            );
+            if to_planet_explorer.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(RecordedChannels {
+        rx_orchestrator: planet_rx_orchestrator,
+        tx_orchestrator: planet_tx_orchestrator,
+        rx_explorer: planet_rx_explorer,
+    })
+}
+
+/// Sender given to the planet in place of the one of the explorer.
+fn forward_explorer(
+    recorder: &Recorder,
+    explorer_id: ID,
+    to_explorer: Sender<PlanetToExplorer>,
+) -> Sender<PlanetToExplorer> {
+    let (tx, rx) = unbounded();
+    let rec = recorder.clone();
+    thread::spawn(move || {
+        for msg in rx {
+            rec.write(
+                Stream::Explorer(explorer_id),
+                Direction::FromPlanet,
+                explorer_reply_to_json(&msg),
+            );
+            if to_explorer.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+    tx
+}
+
+/// Same as [`create_planet`], with the messages of the planet recorded to `path`.
+pub fn create_recorded_planet(
+    rx_orchestrator: Receiver<OrchestratorToPlanet>,
+    tx_orchestrator: Sender<PlanetToOrchestrator>,
+    rx_explorer: Receiver<ExplorerToPlanet>,
+    planet_id: u32,
+    path: impl AsRef<Path>,
+) -> Result<Planet, String> {
+    let channels = record(path, rx_orchestrator, tx_orchestrator, rx_explorer)?;
+    create_planet(
+        channels.rx_orchestrator,
+        channels.tx_orchestrator,
+        channels.rx_explorer,
+        planet_id,
+    )
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////
+// Replayer
+///////////////////////////////////////////////////////////////////////////////////////////
+
+/// A response that differs between the recording and the replay.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub stream: Stream,
+    /// position of the response in its stream
+    pub index: usize,
+    /// None if the replayed planet sent more responses than the recorded one
+    pub expected: Option<Value>,
+    /// None if the replayed planet sent fewer responses than the recorded one
+    pub actual: Option<Value>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplayReport {
+    /// inbound messages sent to the replayed planet
+    pub replayed: usize,
+    /// responses sent by the replayed planet
+    pub responses: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    /// True if the replayed planet answered exactly like the recorded one.
+    pub fn is_clean(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} messages replayed, {} responses, {} mismatches",
+            self.replayed,
+            self.responses,
+            self.mismatches.len()
+        )?;
+        let none = || "nothing".to_string();
+        for m in &self.mismatches {
+            write!(
+                f,
+                "\n{} #{}: expected {}, got {}",
+                m.stream,
+                m.index,
+                m.expected.as_ref().map_or_else(none, Value::to_string),
+                m.actual.as_ref().map_or_else(none, Value::to_string)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Feeds a recording to a fresh planet.
+pub struct Replayer {
+    entries: Vec<Entry>,
+    timeout: Duration,
+    paced: bool,
+}
+
+impl Replayer {
+    pub fn new(entries: Vec<Entry>) -> Self {
+        Self {
+            entries,
+            timeout: DEFAULT_TIMEOUT,
+            paced: false,
+        }
+    }
+
+    /// Replayer of the recording written to `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        Ok(Self::new(read_recording(path)?))
+    }
+
+    /// Changes how long the replayer waits for each response.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sends every message at its recorded time instead of as soon as
+    /// the previous one is answered, needed when the timing matters
+    /// (like reservation windows).
+    pub fn paced(mut self, paced: bool) -> Self {
+        self.paced = paced;
+        self
+    }
+
+    /// Replays the recording on the default planet returned by [`create_planet`].
+    pub fn run(&self, planet_id: ID) -> Result<ReplayReport, String> {
+        self.run_with(|rx_orch, tx_orch, rx_explorer| {
+            create_planet(rx_orch, tx_orch, rx_explorer, planet_id)
+        })
+    }
+
+    /// Replays the recording on the planet created by `create`, which receives
+    /// the planet side of the channels.
+    pub fn run_with<F>(&self, create: F) -> Result<ReplayReport, String>
+    where
+        F: FnOnce(
+            Receiver<OrchestratorToPlanet>,
+            Sender<PlanetToOrchestrator>,
+            Receiver<ExplorerToPlanet>,
+        ) -> Result<Planet, String>,
+    {
+        let (to_planet, rx_orch) = unbounded();
+        let (tx_orch, from_planet) = unbounded();
+        let (to_planet_explorer, rx_explorer) = unbounded();
+        let mut planet = create(rx_orch, tx_orch, rx_explorer)?;
+        let handle = thread::spawn(move || planet.run());
+
+        let crafter = Crafter::new()?;
+        let mut explorers: HashMap<ID, Receiver<PlanetToExplorer>> = HashMap::new();
+        let mut actual: HashMap<Stream, Vec<Value>> = HashMap::new();
+        let mut report = ReplayReport::default();
+        let started = Instant::now();
+
+        let inbound = self
+            .entries
+            .iter()
+            .filter(|e| e.direction == Direction::ToPlanet);
+        for entry in inbound {
+            if self.paced
+                && let Some(wait) = entry.at.checked_sub(started.elapsed())
+            {
+                thread::sleep(wait);
+            }
+            report.replayed += 1;
+            let reply = match entry.stream {
+                Stream::Orchestrator => {
+                    let msg = orchestrator_msg_from_json(&entry.message, &mut explorers)?;
+                    // a killed planet doesn't answer anymore
+                    if to_planet.send(msg).is_err() {
+                        continue;
+                    }
+                    from_planet
+                        .recv_timeout(self.timeout)
+                        .ok()
+                        .map(|reply| orchestrator_reply_to_json(&reply))
+                }
+                Stream::Explorer(explorer_id) => {
+                    let msg = explorer_msg_from_json(&entry.message, &crafter)?;
+                    if to_planet_explorer.send(msg).is_err() {
+                        continue;
+                    }
+                    // the planet only answers explorers that are landed
+                    explorers.get(&explorer_id).and_then(|rx| {
+                        rx.recv_timeout(self.timeout)
+                            .ok()
+                            .map(|reply| explorer_reply_to_json(&reply))
+                    })
+                }
+            };
+            if let Some(reply) = reply {
+                report.responses += 1;
+                actual.entry(entry.stream).or_default().push(reply);
+            }
+        }
+
+        // ends the planet if it wasn't killed by the recording
+        drop(to_planet);
+        let _ = handle.join();
+
+        let mut expected: HashMap<Stream, Vec<Value>> = HashMap::new();
+        for entry in &self.entries {
+            if entry.direction == Direction::FromPlanet {
+                expected
+                    .entry(entry.stream)
+                    .or_default()
+                    .push(entry.message.clone());
+            }
+        }
+        let streams = expected.keys().chain(actual.keys()).copied();
+        for stream in streams.collect::<BTreeSet<_>>() {
+            let expected = expected.remove(&stream).unwrap_or_default();
+            let actual = actual.remove(&stream).unwrap_or_default();
+            for index in 0..expected.len().max(actual.len()) {
+                let (expected, actual) = (expected.get(index), actual.get(index));
+                if expected != actual {
+                    report.mismatches.push(Mismatch {
+                        stream,
+                        index,
+                        expected: expected.cloned(),
+                        actual: actual.cloned(),
+                    });
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////
+// Messages
+///////////////////////////////////////////////////////////////////////////////////////////
+
+fn orchestrator_msg_to_json(msg: &OrchestratorToPlanet) -> Value {
+    match msg {
+        OrchestratorToPlanet::Sunray(_) => json!({ "kind": "Sunray" }),
+        OrchestratorToPlanet::Asteroid(_) => json!({ "kind": "Asteroid" }),
+        OrchestratorToPlanet::StartPlanetAI => json!({ "kind": "StartPlanetAI" }),
+        OrchestratorToPlanet::StopPlanetAI => json!({ "kind": "StopPlanetAI" }),
+        OrchestratorToPlanet::KillPlanet => json!({ "kind": "KillPlanet" }),
+        OrchestratorToPlanet::InternalStateRequest => json!({ "kind": "InternalStateRequest" }),
+        OrchestratorToPlanet::IncomingExplorerRequest { explorer_id, .. } => {
+            json!({ "kind": "IncomingExplorerRequest", "explorer_id": explorer_id })
+        }
+        OrchestratorToPlanet::OutgoingExplorerRequest { explorer_id } => {
+            json!({ "kind": "OutgoingExplorerRequest", "explorer_id": explorer_id })
+        }
+    }
+}
+
+/// Rebuilds an orchestrator message, the explorers landing on the planet
+/// get a new channel whose receiver is put in `explorers`.
+fn orchestrator_msg_from_json(
+    value: &Value,
+    explorers: &mut HashMap<ID, Receiver<PlanetToExplorer>>,
+) -> Result<OrchestratorToPlanet, String> {
+    let explorer_id = || {
+        value
+            .get("explorer_id")
+            .and_then(Value::as_u64)
+            .and_then(|id| ID::try_from(id).ok())
+            .ok_or("invalid explorer_id")
+    };
+    Ok(match kind(value)? {
+        "Sunray" => OrchestratorToPlanet::Sunray(Sunray::default()),
+        "Asteroid" => OrchestratorToPlanet::Asteroid(Asteroid::default()),
+        "StartPlanetAI" => OrchestratorToPlanet::StartPlanetAI,
+        "StopPlanetAI" => OrchestratorToPlanet::StopPlanetAI,
+        "KillPlanet" => OrchestratorToPlanet::KillPlanet,
+        "InternalStateRequest" => OrchestratorToPlanet::InternalStateRequest,
+        "IncomingExplorerRequest" => {
+            let explorer_id = explorer_id()?;
+            let (tx, rx) = unbounded();
+            explorers.insert(explorer_id, rx);
+            OrchestratorToPlanet::IncomingExplorerRequest {
+                explorer_id,
+                new_sender: tx,
+            }
+        }
+        "OutgoingExplorerRequest" => {
+            let explorer_id = explorer_id()?;
+            explorers.remove(&explorer_id);
+            OrchestratorToPlanet::OutgoingExplorerRequest { explorer_id }
+        }
+        kind => return Err(format!("unknown orchestrator message {}", kind)),
+    })
+}
+
+fn orchestrator_reply_to_json(msg: &PlanetToOrchestrator) -> Value {
+    Value::String(format!("{:?}", msg))
+}
+
+fn explorer_msg_to_json(msg: &ExplorerToPlanet) -> Value {
+    let explorer_id = msg.explorer_id();
+    match msg {
+        ExplorerToPlanet::SupportedResourceRequest { .. } => {
+            json!({ "kind": "SupportedResourceRequest", "explorer_id": explorer_id })
+        }
+        ExplorerToPlanet::SupportedCombinationRequest { .. } => {
+            json!({ "kind": "SupportedCombinationRequest", "explorer_id": explorer_id })
+        }
+        ExplorerToPlanet::GenerateResourceRequest { resource, .. } => json!({
+            "kind": "GenerateResourceRequest",
+            "explorer_id": explorer_id,
+            "resource": format!("{:?}", resource),
+        }),
+        ExplorerToPlanet::CombineResourceRequest { msg, .. } => json!({
+            "kind": "CombineResourceRequest",
+            "explorer_id": explorer_id,
+            "recipe": format!("{:?}", recipe(msg)),
+        }),
+        ExplorerToPlanet::AvailableEnergyCellRequest { .. } => {
+            json!({ "kind": "AvailableEnergyCellRequest", "explorer_id": explorer_id })
+        }
+    }
+}
+
+fn explorer_msg_from_json(value: &Value, crafter: &Crafter) -> Result<ExplorerToPlanet, String> {
+    let explorer_id = value
+        .get("explorer_id")
+        .and_then(Value::as_u64)
+        .and_then(|id| ID::try_from(id).ok())
+        .ok_or("invalid explorer_id")?;
+    let string = |name: &str| {
+        value
+            .get(name)
+            .and_then(Value::as_str)
+            .ok_or_else(|| format!("invalid {}", name))
+    };
+    Ok(match kind(value)? {
+        "SupportedResourceRequest" => ExplorerToPlanet::SupportedResourceRequest { explorer_id },
+        "SupportedCombinationRequest" => {
+            ExplorerToPlanet::SupportedCombinationRequest { explorer_id }
+        }
+        "GenerateResourceRequest" => ExplorerToPlanet::GenerateResourceRequest {
+            explorer_id,
+            resource: parse_basic(string("resource")?)?,
+        },
+        "CombineResourceRequest" => ExplorerToPlanet::CombineResourceRequest {
+            explorer_id,
+            msg: crafter.ingredients(parse_complex(string("recipe")?)?)?,
+        },
+        "AvailableEnergyCellRequest" => {
+            ExplorerToPlanet::AvailableEnergyCellRequest { explorer_id }
+        }
+        kind => return Err(format!("unknown explorer message {}", kind)),
+    })
+}
+
+/// The supported resources and combinations are sorted, the order of a `HashSet` changes every run.
+fn explorer_reply_to_json(msg: &PlanetToExplorer) -> Value {
+    let sorted = |mut names: Vec<String>| {
+        names.sort();
+        format!("{:?}", names)
+    };
+    let text = match msg {
+        PlanetToExplorer::SupportedResourceResponse { resource_list } => format!(
+            "SupportedResourceResponse {}",
+            sorted(resource_list.iter().map(|r| format!("{:?}", r)).collect())
+        ),
+        PlanetToExplorer::SupportedCombinationResponse { combination_list } => format!(
+            "SupportedCombinationResponse {}",
+            sorted(
+                combination_list
+                    .iter()
+                    .map(|r| format!("{:?}", r))
+                    .collect()
+            )
+        ),
+        msg => format!("{:?}", msg),
+    };
+    Value::String(text)
+}
+
+fn kind(value: &Value) -> Result<&str, String> {
+    value
+        .get("kind")
+        .and_then(Value::as_str)
+        .ok_or_else(|| "missing field kind".to_string())
+}
+
+fn parse_basic(s: &str) -> Result<BasicResourceType, String> {
+    match s {
+        "Carbon" => Ok(BasicResourceType::Carbon),
+        "Hydrogen" => Ok(BasicResourceType::Hydrogen),
+        "Oxygen" => Ok(BasicResourceType::Oxygen),
+        "Silicon" => Ok(BasicResourceType::Silicon),
+        _ => Err(format!("unknown basic resource {}", s)),
+    }
+}
+
+fn parse_complex(s: &str) -> Result<ComplexResourceType, String> {
+    match s {
+        "Diamond" => Ok(ComplexResourceType::Diamond),
+        "Water" => Ok(ComplexResourceType::Water),
+        "Life" => Ok(ComplexResourceType::Life),
+        "Robot" => Ok(ComplexResourceType::Robot),
+        "Dolphin" => Ok(ComplexResourceType::Dolphin),
+        "AIPartner" => Ok(ComplexResourceType::AIPartner),
+        _ => Err(format!("unknown complex resource {}", s)),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////
+// Crafter
+///////////////////////////////////////////////////////////////////////////////////////////
+
+/// Crafts the ingredients of the recorded combine requests with the generator
+/// and the combinator of two planets that never run.
+struct Crafter {
+    generator: Planet,
+    combinator: Planet,
+}
+
+fn inert_planet(
+    planet_type: PlanetType,
+    gen_rules: Vec<BasicResourceType>,
+    comb_rules: Vec<ComplexResourceType>,
+) -> Result<Planet, String> {
+    let (_, rx_orch) = unbounded();
+    let (tx_orch, _) = unbounded();
+    let (_, rx_explorer) = unbounded();
+    Planet::new(
+        0,
+        planet_type,
+        Box::new(Inert),
+        gen_rules,
+        comb_rules,
+        (rx_orch, tx_orch),
+        rx_explorer,
+    )
+}
+
+fn charged_cell() -> EnergyCell {
+    let mut cell = EnergyCell::new();
+    cell.charge(Sunray::default());
+    cell
+}
+
+impl Crafter {
+    fn new() -> Result<Self, String> {
+        use BasicResourceType::*;
+        use ComplexResourceType::*;
+        Ok(Self {
+            generator: inert_planet(
+                PlanetType::D,
+                vec![Carbon, Hydrogen, Oxygen, Silicon],
+                vec![],
+            )?,
+            combinator: inert_planet(
+                PlanetType::C,
+                vec![Carbon],
+                vec![Water, Diamond, Life, Robot, Dolphin, AIPartner],
+            )?,
+        })
+    }
+
+    fn ingredients(&self, recipe: ComplexResourceType) -> Result<ComplexResourceRequest, String> {
+        Ok(match recipe {
+            ComplexResourceType::Water => {
+                ComplexResourceRequest::Water(self.hydrogen()?, self.oxygen()?)
+            }
+            ComplexResourceType::Diamond => {
+                ComplexResourceRequest::Diamond(self.carbon()?, self.carbon()?)
+            }
+            ComplexResourceType::Life => {
+                ComplexResourceRequest::Life(self.water()?, self.carbon()?)
+            }
+            ComplexResourceType::Robot => {
+                ComplexResourceRequest::Robot(self.silicon()?, self.life()?)
+            }
+            ComplexResourceType::Dolphin => {
+                ComplexResourceRequest::Dolphin(self.water()?, self.life()?)
+            }
+            ComplexResourceType::AIPartner => {
+                ComplexResourceRequest::AIPartner(self.robot()?, self.diamond()?)
+            }
+        })
+    }
+
+    fn generator(&self) -> &Generator {
+        self.generator.generator()
+    }
+
+    fn combinator(&self) -> &Combinator {
+        self.combinator.combinator()
+    }
+
+    fn carbon(&self) -> Result<Carbon, String> {
+        self.generator().make_carbon(&mut charged_cell())
+    }
+
+    fn hydrogen(&self) -> Result<Hydrogen, String> {
+        self.generator().make_hydrogen(&mut charged_cell())
+    }
+
+    fn oxygen(&self) -> Result<Oxygen, String> {
+        self.generator().make_oxygen(&mut charged_cell())
+    }
+
+    fn silicon(&self) -> Result<Silicon, String> {
+        self.generator().make_silicon(&mut charged_cell())
+    }
+
+    fn water(&self) -> Result<Water, String> {
+        self.combinator()
+            .make_water(self.hydrogen()?, self.oxygen()?, &mut charged_cell())
+            .map_err(|(e, _, _)| e)
+    }
+
+    fn diamond(&self) -> Result<Diamond, String> {
+        self.combinator()
+            .make_diamond(self.carbon()?, self.carbon()?, &mut charged_cell())
+            .map_err(|(e, _, _)| e)
+    }
+
+    fn life(&self) -> Result<Life, String> {
+        self.combinator()
+            .make_life(self.water()?, self.carbon()?, &mut charged_cell())
+            .map_err(|(e, _, _)| e)
+    }
+
+    fn robot(&self) -> Result<Robot, String> {
+        self.combinator()
+            .make_robot(self.silicon()?, self.life()?, &mut charged_cell())
+            .map_err(|(e, _, _)| e)
+    }
+}